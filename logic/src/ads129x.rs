@@ -0,0 +1,662 @@
+//! Driver for TI ADS1292/ADS1299 biopotential front ends.
+//!
+//! The register encoding and data frame decoding are plain functions so they
+//! can be checked without hardware; the driver itself only needs an async
+//! `SpiDevice` (SPI mode 1), the DRDY pin and a delay.
+
+use embedded_hal_async::{
+    delay::DelayNs,
+    digital::Wait,
+    spi::{Operation, SpiDevice},
+};
+
+mod command {
+    pub const RESET: u8 = 0x06;
+    pub const START: u8 = 0x08;
+    pub const RDATAC: u8 = 0x10;
+    pub const SDATAC: u8 = 0x11;
+    pub const RREG: u8 = 0x20;
+    pub const WREG: u8 = 0x40;
+}
+
+mod register {
+    pub const ID: u8 = 0x00;
+    pub const CONFIG1: u8 = 0x01;
+    pub const CONFIG2: u8 = 0x02;
+    pub const LOFF: u8 = 0x04;
+    pub const CH1SET: u8 = 0x05;
+
+    // ADS1299 only
+    pub const CONFIG3: u8 = 0x03;
+    pub const LOFF_SENSP: u8 = 0x0F;
+    pub const LOFF_SENSN: u8 = 0x10;
+    pub const MISC1: u8 = 0x15;
+    pub const CONFIG4: u8 = 0x17;
+
+    // ADS1292 only
+    pub const LOFF_SENS: u8 = 0x07;
+    pub const RESP2: u8 = 0x0A;
+}
+
+/// Size of the status word preceding the channel data
+const STATUS_LEN: usize = 3;
+const SAMPLE_LEN: usize = 3;
+/// Largest frame: ADS1299 with 8 channels
+pub const MAX_FRAME_LEN: usize = STATUS_LEN + 8 * SAMPLE_LEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Variant {
+    /// Two channel ADS1292/ADS1292R
+    Ads1292,
+    /// Four channel ADS1299-4
+    Ads1299_4,
+    /// Six channel ADS1299-6
+    Ads1299_6,
+    /// Eight channel ADS1299
+    Ads1299,
+}
+
+impl Variant {
+    /// Identifies the chip from its ID register
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x53 | 0x73 => Some(Self::Ads1292),
+            0x3C => Some(Self::Ads1299_4),
+            0x3D => Some(Self::Ads1299_6),
+            0x3E => Some(Self::Ads1299),
+            _ => None,
+        }
+    }
+
+    /// Inputs of the device, all of them are shifted out with every frame
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Ads1292 => 2,
+            Self::Ads1299_4 => 4,
+            Self::Ads1299_6 => 6,
+            Self::Ads1299 => 8,
+        }
+    }
+
+    /// The ADS1299 variants share their register map and frame layout
+    fn is_ads1299(&self) -> bool {
+        *self != Self::Ads1292
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DataRate {
+    Sps250,
+    Sps500,
+    Sps1000,
+    Sps2000,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Gain {
+    X1,
+    X2,
+    X4,
+    X8,
+    X12,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Config {
+    pub data_rate: DataRate,
+    pub gain: Gain,
+    /// Enables DC lead-off detection on every used channel
+    pub lead_off_detection: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_rate: DataRate::Sps500,
+            gain: Gain::X12,
+            lead_off_detection: true,
+        }
+    }
+}
+
+impl Config {
+    fn data_rate_bits(&self, variant: Variant) -> u8 {
+        match (variant.is_ads1299(), self.data_rate) {
+            (false, DataRate::Sps250) => 0b001,
+            (false, DataRate::Sps500) => 0b010,
+            (false, DataRate::Sps1000) => 0b011,
+            (false, DataRate::Sps2000) => 0b100,
+            (true, DataRate::Sps250) => 0b110,
+            (true, DataRate::Sps500) => 0b101,
+            (true, DataRate::Sps1000) => 0b100,
+            (true, DataRate::Sps2000) => 0b011,
+        }
+    }
+
+    fn gain_bits(&self, variant: Variant) -> u8 {
+        match (variant.is_ads1299(), self.gain) {
+            (false, Gain::X1) => 0b001,
+            (false, Gain::X2) => 0b010,
+            (false, Gain::X4) => 0b100,
+            (false, Gain::X8) => 0b101,
+            (false, Gain::X12) => 0b110,
+            (true, Gain::X1) => 0b000,
+            (true, Gain::X2) => 0b001,
+            (true, Gain::X4) => 0b010,
+            (true, Gain::X8) => 0b100,
+            (true, Gain::X12) => 0b101,
+        }
+    }
+
+    /// Register values for the first `channels` inputs, other inputs are powered down.
+    ///
+    /// Returns the number of entries used in `registers`.
+    pub fn encode(
+        &self,
+        variant: Variant,
+        channels: usize,
+        registers: &mut [(u8, u8); 16],
+    ) -> usize {
+        let mut len = 0;
+        let mut push = |address: u8, value: u8| {
+            registers[len] = (address, value);
+            len += 1;
+        };
+
+        // Normal electrode input, gain in bits 6:4, power down in bit 7
+        let channel_set = self.gain_bits(variant) << 4;
+        let used_mask = ((1u16 << channels) - 1) as u8;
+
+        match variant {
+            Variant::Ads1292 => {
+                // Continuous conversion
+                push(register::CONFIG1, self.data_rate_bits(variant));
+                // Lead-off comparators and internal reference buffer
+                let comparators = if self.lead_off_detection { 0x40 } else { 0x00 };
+                push(register::CONFIG2, 0x80 | comparators | 0x20);
+                // 95% comparator threshold, 6 nA DC excitation
+                push(register::LOFF, 0x10);
+                for channel in 0..variant.channels() {
+                    let value = if channel < channels {
+                        channel_set
+                    } else {
+                        0x81
+                    };
+                    push(register::CH1SET + channel as u8, value);
+                }
+                let sense = if self.lead_off_detection {
+                    // LOFF1N/P and LOFF2N/P of the used channels
+                    let mut bits = 0;
+                    for channel in 0..channels {
+                        bits |= 0b11 << (2 * channel);
+                    }
+                    bits
+                } else {
+                    0
+                };
+                push(register::LOFF_SENS, sense);
+                // Reserved bit that must be written as 1
+                push(register::RESP2, 0x02);
+            }
+            Variant::Ads1299_4 | Variant::Ads1299_6 | Variant::Ads1299 => {
+                // Reserved bits 7 and 4 must be written as 1
+                push(register::CONFIG1, 0x90 | self.data_rate_bits(variant));
+                push(register::CONFIG2, 0xC0);
+                // Internal reference buffer on
+                push(register::CONFIG3, 0xE0);
+                // 95% comparator threshold, 6 nA DC excitation
+                push(register::LOFF, 0x00);
+                for channel in 0..variant.channels() {
+                    let value = if channel < channels {
+                        channel_set
+                    } else {
+                        0x81
+                    };
+                    push(register::CH1SET + channel as u8, value);
+                }
+                let sense = if self.lead_off_detection {
+                    used_mask
+                } else {
+                    0
+                };
+                push(register::LOFF_SENSP, sense);
+                push(register::LOFF_SENSN, sense);
+                push(register::MISC1, 0x00);
+                let comparators = if self.lead_off_detection { 0x02 } else { 0x00 };
+                push(register::CONFIG4, comparators);
+            }
+        }
+
+        len
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum DecodeError {
+    /// Frame shorter than the status word plus the requested channels
+    TooShort,
+    /// Status word does not start with the `1100` sync pattern
+    BadSync,
+}
+
+/// One conversion of all channels
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Frame<const CHANNELS: usize> {
+    /// Signed 24 bit conversion results
+    pub samples: [i32; CHANNELS],
+    /// Bit `n` is set when either electrode of channel `n` is off
+    pub lead_off: u8,
+}
+
+pub fn sign_extend_24(bytes: [u8; 3]) -> i32 {
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8
+}
+
+/// Decodes a status word followed by `CHANNELS` 24 bit samples.
+pub fn decode_frame<const CHANNELS: usize>(
+    variant: Variant,
+    data: &[u8],
+) -> Result<Frame<CHANNELS>, DecodeError> {
+    if data.len() < STATUS_LEN + CHANNELS * SAMPLE_LEN {
+        return Err(DecodeError::TooShort);
+    }
+
+    if data[0] & 0xF0 != 0xC0 {
+        return Err(DecodeError::BadSync);
+    }
+
+    let status = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+    let lead_off = match variant {
+        Variant::Ads1292 => {
+            // Bits 18:15: IN2N, IN2P, IN1N, IN1P
+            let bits = (status >> 15) & 0x0F;
+            let mut lead_off = 0;
+            if bits & 0b0011 != 0 {
+                lead_off |= 0b01;
+            }
+            if bits & 0b1100 != 0 {
+                lead_off |= 0b10;
+            }
+            lead_off
+        }
+        // LOFF_STATP in bits 19:12, LOFF_STATN in bits 11:4, only the bits of
+        // the device's inputs
+        Variant::Ads1299_4 | Variant::Ads1299_6 | Variant::Ads1299 => {
+            let inputs = (1u32 << variant.channels()) - 1;
+            (((status >> 12) | (status >> 4)) & inputs) as u8
+        }
+    };
+
+    let mut samples = [0; CHANNELS];
+    for (channel, sample) in samples.iter_mut().enumerate() {
+        let start = STATUS_LEN + channel * SAMPLE_LEN;
+        *sample = sign_extend_24([data[start], data[start + 1], data[start + 2]]);
+    }
+
+    Ok(Frame { samples, lead_off })
+}
+
+#[derive(defmt::Format)]
+pub enum Error<S, P> {
+    Spi(S),
+    Drdy(P),
+    /// ID register did not match a supported device
+    UnknownDevice(u8),
+    /// Device has fewer inputs than requested
+    TooManyChannels,
+    /// Frame read before `init`
+    NotInitialized,
+    Frame(DecodeError),
+}
+
+/// ADS129x reading `CHANNELS` inputs in continuous conversion mode.
+pub struct Ads129x<SPI, DRDY, DELAY, const CHANNELS: usize> {
+    spi: SPI,
+    drdy: DRDY,
+    delay: DELAY,
+    variant: Option<Variant>,
+}
+
+impl<SPI, DRDY, DELAY, const CHANNELS: usize> Ads129x<SPI, DRDY, DELAY, CHANNELS>
+where
+    SPI: SpiDevice,
+    DRDY: Wait,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: SPI, drdy: DRDY, delay: DELAY) -> Self {
+        Self {
+            spi,
+            drdy,
+            delay,
+            variant: None,
+        }
+    }
+
+    /// Resets and configures the device, then starts continuous conversion.
+    pub async fn init(
+        &mut self,
+        config: Config,
+    ) -> Result<Variant, Error<SPI::Error, DRDY::Error>> {
+        self.command(command::RESET).await?;
+        self.delay.delay_ms(1).await;
+        // Registers can only be accessed outside of continuous read mode
+        self.command(command::SDATAC).await?;
+
+        let id = self.read_register(register::ID).await?;
+        let variant = Variant::from_id(id).ok_or(Error::UnknownDevice(id))?;
+        if CHANNELS > variant.channels() {
+            return Err(Error::TooManyChannels);
+        }
+
+        let mut registers = [(0, 0); 16];
+        let len = config.encode(variant, CHANNELS, &mut registers);
+        for &(address, value) in &registers[..len] {
+            self.write_register(address, value).await?;
+        }
+
+        self.command(command::RDATAC).await?;
+        self.command(command::START).await?;
+        self.variant = Some(variant);
+
+        Ok(variant)
+    }
+
+    /// Waits for DRDY and reads the next conversion.
+    pub async fn read_frame(&mut self) -> Result<Frame<CHANNELS>, Error<SPI::Error, DRDY::Error>> {
+        let variant = self.variant.ok_or(Error::NotInitialized)?;
+        self.drdy
+            .wait_for_falling_edge()
+            .await
+            .map_err(Error::Drdy)?;
+
+        // All of the device's channels are shifted out, even unused ones
+        let len = STATUS_LEN + variant.channels() * SAMPLE_LEN;
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        self.spi
+            .read(&mut buffer[..len])
+            .await
+            .map_err(Error::Spi)?;
+
+        decode_frame(variant, &buffer[..len]).map_err(Error::Frame)
+    }
+
+    async fn command(&mut self, command: u8) -> Result<(), Error<SPI::Error, DRDY::Error>> {
+        // Commands need 4 tCLK (~2 us) before the next one may be sent
+        self.spi
+            .transaction(&mut [Operation::Write(&[command]), Operation::DelayNs(2_000)])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    async fn read_register(&mut self, address: u8) -> Result<u8, Error<SPI::Error, DRDY::Error>> {
+        let mut value = [0u8];
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[command::RREG | address, 0x00]),
+                Operation::Read(&mut value),
+            ])
+            .await
+            .map_err(Error::Spi)?;
+        Ok(value[0])
+    }
+
+    async fn write_register(
+        &mut self,
+        address: u8,
+        value: u8,
+    ) -> Result<(), Error<SPI::Error, DRDY::Error>> {
+        self.spi
+            .write(&[command::WREG | address, 0x00, value])
+            .await
+            .map_err(Error::Spi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    };
+
+    use embedded_hal_mock::eh1::{
+        delay::NoopDelay,
+        digital::{Edge, Mock as PinMock, Transaction as PinTransaction},
+        spi::{Mock as SpiMock, Transaction as SpiTransaction},
+    };
+
+    use super::*;
+
+    /// Runs a future of the driver to completion, the mocks never make it wait
+    fn block_on<F: Future>(future: F) -> F::Output {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(
+            |_| RawWaker::new(core::ptr::null(), &VTABLE),
+            |_| {},
+            |_| {},
+            |_| {},
+        );
+        let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+        let mut context = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    fn command(command: u8) -> Vec<SpiTransaction<u8>> {
+        vec![
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write_vec(vec![command]),
+            SpiTransaction::delay(2_000),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    fn write_register(address: u8, value: u8) -> Vec<SpiTransaction<u8>> {
+        vec![
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write_vec(vec![command::WREG | address, 0x00, value]),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    /// Reset and identification up to the register writes
+    fn identify(id: u8) -> Vec<SpiTransaction<u8>> {
+        let mut transactions = command(command::RESET);
+        transactions.extend(command(command::SDATAC));
+        transactions.extend([
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write_vec(vec![command::RREG | register::ID, 0x00]),
+            SpiTransaction::read_vec(vec![id]),
+            SpiTransaction::transaction_end(),
+        ]);
+        transactions
+    }
+
+    fn start() -> Vec<SpiTransaction<u8>> {
+        let mut transactions = command(command::RDATAC);
+        transactions.extend(command(command::START));
+        transactions
+    }
+
+    fn read_frame(frame: Vec<u8>) -> Vec<SpiTransaction<u8>> {
+        vec![
+            SpiTransaction::transaction_start(),
+            SpiTransaction::read_vec(frame),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    #[test]
+    fn ads1299_4_is_configured_and_read() {
+        let mut expected = identify(0x3C);
+        for (address, value) in [
+            (register::CONFIG1, 0x95),
+            (register::CONFIG2, 0xC0),
+            (register::CONFIG3, 0xE0),
+            (register::LOFF, 0x00),
+            // Two used inputs at gain 12, the other two powered down
+            (register::CH1SET, 0x50),
+            (register::CH1SET + 1, 0x50),
+            (register::CH1SET + 2, 0x81),
+            (register::CH1SET + 3, 0x81),
+            (register::LOFF_SENSP, 0x03),
+            (register::LOFF_SENSN, 0x03),
+            (register::MISC1, 0x00),
+            (register::CONFIG4, 0x02),
+        ] {
+            expected.extend(write_register(address, value));
+        }
+        expected.extend(start());
+        // Status with the positive electrode of channel 2 off, then all four
+        // inputs although only two are used
+        expected.extend(read_frame(vec![
+            0xC0, 0x20, 0x00, 0x7F, 0xFF, 0xFF, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF,
+            0xFF,
+        ]));
+
+        let mut spi = SpiMock::new(&expected);
+        let mut drdy = PinMock::new(&[PinTransaction::wait_for_edge(Edge::Falling)]);
+        let mut ads = Ads129x::<_, _, _, 2>::new(spi.clone(), drdy.clone(), NoopDelay::new());
+
+        assert!(matches!(
+            block_on(ads.init(Config::default())),
+            Ok(Variant::Ads1299_4)
+        ));
+        let frame = block_on(ads.read_frame());
+        assert!(matches!(
+            frame,
+            Ok(Frame {
+                samples: [8_388_607, -8_388_608],
+                lead_off: 0b10,
+            })
+        ));
+
+        spi.done();
+        drdy.done();
+    }
+
+    #[test]
+    fn ads1292_is_configured_and_read() {
+        let mut expected = identify(0x73);
+        for (address, value) in [
+            (register::CONFIG1, 0x02),
+            (register::CONFIG2, 0xE0),
+            (register::LOFF, 0x10),
+            (register::CH1SET, 0x60),
+            (register::CH1SET + 1, 0x60),
+            (register::LOFF_SENS, 0x0F),
+            (register::RESP2, 0x02),
+        ] {
+            expected.extend(write_register(address, value));
+        }
+        expected.extend(start());
+        // IN1P off
+        expected.extend(read_frame(vec![
+            0xC0, 0x80, 0x00, 0x00, 0x01, 0x00, 0xFF, 0xFF, 0x00,
+        ]));
+
+        let mut spi = SpiMock::new(&expected);
+        let mut drdy = PinMock::new(&[PinTransaction::wait_for_edge(Edge::Falling)]);
+        let mut ads = Ads129x::<_, _, _, 2>::new(spi.clone(), drdy.clone(), NoopDelay::new());
+
+        assert!(matches!(
+            block_on(ads.init(Config::default())),
+            Ok(Variant::Ads1292)
+        ));
+        assert!(matches!(
+            block_on(ads.read_frame()),
+            Ok(Frame {
+                samples: [256, -256],
+                lead_off: 0b01,
+            })
+        ));
+
+        spi.done();
+        drdy.done();
+    }
+
+    #[test]
+    fn frames_carry_every_input_of_the_device() {
+        for (id, variant) in [
+            (0x3C, Variant::Ads1299_4),
+            (0x3D, Variant::Ads1299_6),
+            (0x3E, Variant::Ads1299),
+        ] {
+            let config = Config::default();
+            let mut registers = [(0, 0); 16];
+            let len = config.encode(variant, 4, &mut registers);
+
+            let mut expected = identify(id);
+            for &(address, value) in &registers[..len] {
+                expected.extend(write_register(address, value));
+            }
+            expected.extend(start());
+            let mut frame = vec![0xC0, 0x00, 0x00];
+            for channel in 0..variant.channels() {
+                frame.extend([0x00, 0x00, channel as u8]);
+            }
+            expected.extend(read_frame(frame));
+
+            let mut spi = SpiMock::new(&expected);
+            let mut drdy = PinMock::new(&[PinTransaction::wait_for_edge(Edge::Falling)]);
+            let mut ads = Ads129x::<_, _, _, 4>::new(spi.clone(), drdy.clone(), NoopDelay::new());
+
+            assert!(matches!(block_on(ads.init(config)), Ok(found) if found == variant));
+            assert!(matches!(
+                block_on(ads.read_frame()),
+                Ok(Frame {
+                    samples: [0, 1, 2, 3],
+                    lead_off: 0,
+                })
+            ));
+
+            spi.done();
+            drdy.done();
+        }
+    }
+
+    #[test]
+    fn unknown_or_too_small_devices_are_refused() {
+        let mut spi = SpiMock::new(&identify(0x00));
+        let mut drdy = PinMock::new(&[]);
+        let mut ads = Ads129x::<_, _, _, 2>::new(spi.clone(), drdy.clone(), NoopDelay::new());
+        assert!(matches!(
+            block_on(ads.init(Config::default())),
+            Err(Error::UnknownDevice(0x00))
+        ));
+        spi.done();
+        drdy.done();
+
+        // Six inputs asked of an ADS1299-4
+        let mut spi = SpiMock::new(&identify(0x3C));
+        let mut drdy = PinMock::new(&[]);
+        let mut ads = Ads129x::<_, _, _, 6>::new(spi.clone(), drdy.clone(), NoopDelay::new());
+        assert!(matches!(
+            block_on(ads.init(Config::default())),
+            Err(Error::TooManyChannels)
+        ));
+        spi.done();
+        drdy.done();
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let short = [0xC0, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(
+            decode_frame::<2>(Variant::Ads1292, &short),
+            Err(DecodeError::TooShort)
+        );
+        let unsynced = [0x00; 9];
+        assert_eq!(
+            decode_frame::<2>(Variant::Ads1292, &unsynced),
+            Err(DecodeError::BadSync)
+        );
+    }
+}
@@ -0,0 +1,454 @@
+//! Over-force and stall detection on the hand's sensor readings.
+
+use defmt::Format;
+
+use crate::limits::{JointRange, JOINTS};
+
+pub const PRESSURE_SENSORS: usize = 3;
+/// One per joint, in the units of `SetPosition`
+pub const POSITION_SENSORS: usize = JOINTS;
+
+/// Smallest position change, in raw sensor counts, that counts as movement
+const STALL_MOVEMENT: u16 = 8;
+/// Readings without movement, while the hand should move, before it is stalled
+const STALL_READINGS: u32 = 6;
+/// Distance outside of its commanded range at which a joint still counts as there
+const ON_TARGET: u16 = 8;
+
+/// One `ResponseSensors` packet
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct SensorReadings {
+    pub pressure: [u16; PRESSURE_SENSORS],
+    pub position: [u16; POSITION_SENSORS],
+}
+
+impl SensorReadings {
+    /// Parses the payload, pressures first, each value little endian
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() != (PRESSURE_SENSORS + POSITION_SENSORS) * 2 {
+            return None;
+        }
+        let value = |index: usize| u16::from_le_bytes([payload[2 * index], payload[2 * index + 1]]);
+        Some(Self {
+            pressure: core::array::from_fn(value),
+            position: core::array::from_fn(|index| value(PRESSURE_SENSORS + index)),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum GripFault {
+    /// A pressure sensor went beyond its limit
+    OverForce { sensor: usize, pressure: u16 },
+    /// The fingers stopped short of their commanded positions, or a close
+    /// stopped on an object or at its end of travel
+    Stall,
+}
+
+/// Movement the hand was told to make
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Goal {
+    /// Closing, which stalls where the fingers stop. The grip is held from
+    /// then on and its pressure still watched, it may keep rising on a soft
+    /// object.
+    Close,
+    /// Opening, which ends at the end of travel
+    Open,
+    /// Moving each joint into its commanded range
+    Position([JointRange; POSITION_SENSORS]),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Progress {
+    Moving,
+    /// A stalled close holds the grip, only over-force is still checked
+    Holding,
+    /// The goal was reached, the hand no longer needs watching
+    Complete,
+}
+
+pub struct GripMonitor {
+    // Set per pressure sensor from exceeding its limit until it drops 10 %
+    // below, so that one press is reported once
+    over_force: [bool; PRESSURE_SENSORS],
+    // Positions the next movement is measured from
+    reference: Option<[u16; POSITION_SENSORS]>,
+    still_readings: u32,
+    holding: bool,
+}
+
+impl GripMonitor {
+    pub const fn new() -> Self {
+        Self {
+            over_force: [false; PRESSURE_SENSORS],
+            reference: None,
+            still_readings: 0,
+            holding: false,
+        }
+    }
+
+    /// Checks one reading taken while the hand works towards `goal`. A fault
+    /// is returned once when it starts.
+    pub fn update(
+        &mut self,
+        readings: &SensorReadings,
+        limits: &[u16; PRESSURE_SENSORS],
+        goal: &Goal,
+    ) -> Result<Progress, GripFault> {
+        let mut fault = None;
+        for (sensor, (&pressure, &limit)) in readings.pressure.iter().zip(limits).enumerate() {
+            if self.over_force[sensor] {
+                self.over_force[sensor] = pressure >= limit - limit / 10;
+            } else if pressure > limit {
+                self.over_force[sensor] = true;
+                fault.get_or_insert(GripFault::OverForce { sensor, pressure });
+            }
+        }
+        if let Some(fault) = fault {
+            self.reset_movement();
+            return Err(fault);
+        }
+        if self.holding {
+            return Ok(Progress::Holding);
+        }
+
+        if let Goal::Position(targets) = goal {
+            let reached =
+                targets
+                    .iter()
+                    .zip(readings.position.iter())
+                    .all(|(target, &position)| {
+                        (target.min.saturating_sub(ON_TARGET)
+                            ..=target.max.saturating_add(ON_TARGET))
+                            .contains(&position)
+                    });
+            if reached {
+                self.reset_movement();
+                return Ok(Progress::Complete);
+            }
+        }
+
+        let Some(reference) = self.reference else {
+            self.reference = Some(readings.position);
+            return Ok(Progress::Moving);
+        };
+        let movement = reference
+            .iter()
+            .zip(readings.position.iter())
+            .map(|(&from, &to)| from.abs_diff(to))
+            .max()
+            .unwrap_or(0);
+        if movement >= STALL_MOVEMENT {
+            self.reference = Some(readings.position);
+            self.still_readings = 0;
+            return Ok(Progress::Moving);
+        }
+
+        self.still_readings += 1;
+        if self.still_readings < STALL_READINGS {
+            return Ok(Progress::Moving);
+        }
+        self.reset_movement();
+        match goal {
+            Goal::Open => Ok(Progress::Complete),
+            Goal::Close => {
+                self.holding = true;
+                Err(GripFault::Stall)
+            }
+            Goal::Position(_) => Err(GripFault::Stall),
+        }
+    }
+
+    fn reset_movement(&mut self) {
+        self.reference = None;
+        self.still_readings = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: [u16; PRESSURE_SENSORS] = [1000; PRESSURE_SENSORS];
+
+    fn at(position: u16) -> SensorReadings {
+        SensorReadings {
+            pressure: [0; PRESSURE_SENSORS],
+            position: [position; POSITION_SENSORS],
+        }
+    }
+
+    fn target(min: u16, max: u16) -> Goal {
+        Goal::Position([JointRange { min, max }; POSITION_SENSORS])
+    }
+
+    #[test]
+    fn positioning_completes_on_target() {
+        let mut monitor = GripMonitor::new();
+        let goal = target(500, 600);
+        for position in [100, 200, 300, 400] {
+            assert_eq!(
+                monitor.update(&at(position), &LIMITS, &goal),
+                Ok(Progress::Moving)
+            );
+        }
+        assert_eq!(
+            monitor.update(&at(495), &LIMITS, &goal),
+            Ok(Progress::Complete)
+        );
+    }
+
+    #[test]
+    fn positioning_stalls_short_of_the_target() {
+        let mut monitor = GripMonitor::new();
+        let goal = target(500, 600);
+        assert_eq!(
+            monitor.update(&at(300), &LIMITS, &goal),
+            Ok(Progress::Moving)
+        );
+        for _ in 1..STALL_READINGS {
+            assert_eq!(
+                monitor.update(&at(302), &LIMITS, &goal),
+                Ok(Progress::Moving)
+            );
+        }
+        assert_eq!(
+            monitor.update(&at(302), &LIMITS, &goal),
+            Err(GripFault::Stall)
+        );
+    }
+
+    #[test]
+    fn opening_ends_where_the_fingers_stop() {
+        let mut monitor = GripMonitor::new();
+        for position in [700, 500, 300, 100] {
+            assert_eq!(
+                monitor.update(&at(position), &LIMITS, &Goal::Open),
+                Ok(Progress::Moving)
+            );
+        }
+        for _ in 1..STALL_READINGS {
+            assert_eq!(
+                monitor.update(&at(100), &LIMITS, &Goal::Open),
+                Ok(Progress::Moving)
+            );
+        }
+        assert_eq!(
+            monitor.update(&at(100), &LIMITS, &Goal::Open),
+            Ok(Progress::Complete)
+        );
+    }
+
+    #[test]
+    fn stalled_close_keeps_watching_the_pressure() {
+        let mut monitor = GripMonitor::new();
+        for position in [100, 300, 500, 700] {
+            assert_eq!(
+                monitor.update(&at(position), &LIMITS, &Goal::Close),
+                Ok(Progress::Moving)
+            );
+        }
+        for _ in 1..STALL_READINGS {
+            assert_eq!(
+                monitor.update(&at(700), &LIMITS, &Goal::Close),
+                Ok(Progress::Moving)
+            );
+        }
+        assert_eq!(
+            monitor.update(&at(700), &LIMITS, &Goal::Close),
+            Err(GripFault::Stall)
+        );
+        // Reported once, the grip is held from then on
+        assert_eq!(
+            monitor.update(&at(700), &LIMITS, &Goal::Close),
+            Ok(Progress::Holding)
+        );
+        let pressed = SensorReadings {
+            pressure: [0, 1001, 0],
+            position: [700; POSITION_SENSORS],
+        };
+        assert_eq!(
+            monitor.update(&pressed, &LIMITS, &Goal::Close),
+            Err(GripFault::OverForce {
+                sensor: 1,
+                pressure: 1001
+            })
+        );
+    }
+
+    /// Pressures then positions, as the hand reported them
+    type Recording = [[u16; PRESSURE_SENSORS + POSITION_SENSORS]];
+
+    /// Closing on a cup until one finger presses too hard, then easing off
+    /// below the hysteresis and pressing again
+    const CLOSING_ON_A_CUP: &Recording = &[
+        [0, 0, 0, 100, 100, 100, 100, 100, 100],
+        [0, 0, 0, 180, 175, 182, 170, 178, 181],
+        [0, 0, 0, 260, 255, 262, 250, 258, 261],
+        [40, 20, 10, 340, 335, 342, 330, 338, 341],
+        [250, 180, 90, 400, 395, 401, 390, 398, 400],
+        [520, 610, 300, 430, 425, 430, 420, 428, 431],
+        [780, 940, 480, 441, 437, 440, 433, 439, 442],
+        [870, 1012, 560, 443, 439, 441, 435, 440, 444],
+        [905, 1040, 600, 443, 440, 441, 435, 441, 444],
+        [890, 930, 590, 444, 440, 442, 436, 441, 444],
+        [860, 880, 570, 444, 440, 442, 436, 441, 445],
+        [870, 1005, 575, 444, 441, 442, 436, 441, 445],
+    ];
+
+    /// Closing on a sponge: the fingers stop while the pressure keeps rising
+    /// as the motor pushes on
+    const CLOSING_ON_A_SPONGE: &Recording = &[
+        [0, 0, 0, 100, 100, 100, 100, 100, 100],
+        [0, 0, 0, 220, 215, 221, 219, 218, 222],
+        [60, 40, 30, 330, 326, 331, 329, 327, 332],
+        [180, 150, 120, 381, 377, 380, 379, 378, 383],
+        [260, 230, 190, 384, 380, 383, 382, 381, 385],
+        [330, 300, 250, 385, 381, 384, 383, 382, 386],
+        [400, 370, 300, 386, 382, 385, 384, 383, 387],
+        [470, 450, 360, 386, 383, 385, 384, 384, 387],
+        [540, 520, 410, 387, 383, 386, 385, 384, 388],
+        [610, 600, 470, 387, 384, 386, 385, 385, 388],
+        [690, 680, 520, 387, 384, 386, 385, 385, 388],
+        [770, 760, 580, 388, 384, 387, 386, 385, 389],
+        [850, 840, 630, 388, 385, 387, 386, 386, 389],
+        [930, 920, 690, 388, 385, 387, 386, 386, 389],
+        [1010, 995, 740, 388, 385, 387, 386, 386, 389],
+    ];
+
+    /// Opening fully, the positions jitter by a few counts at the end stop
+    const OPENING_TO_THE_END_STOP: &Recording = &[
+        [12, 2, 11, 599, 598, 600, 602, 597, 597],
+        [12, 3, 1, 523, 521, 517, 519, 521, 517],
+        [9, 4, 8, 441, 438, 437, 437, 440, 440],
+        [7, 5, 11, 357, 358, 357, 361, 360, 357],
+        [7, 4, 9, 283, 281, 277, 278, 282, 282],
+        [1, 1, 8, 201, 197, 201, 201, 200, 197],
+        [6, 2, 12, 118, 117, 121, 123, 118, 119],
+        [5, 2, 7, 118, 115, 122, 120, 119, 118],
+        [6, 0, 10, 119, 120, 123, 121, 115, 120],
+        [1, 12, 8, 119, 119, 119, 122, 115, 120],
+        [9, 12, 5, 120, 114, 122, 120, 119, 117],
+        [5, 11, 5, 118, 119, 122, 123, 121, 118],
+        [9, 7, 9, 118, 118, 121, 122, 117, 117],
+    ];
+
+    /// Positioning towards 800..900, held up by an obstacle at about 478
+    const POSITIONING_INTO_AN_OBSTACLE: &Recording = &[
+        [3, 200, 7, 203, 200, 197, 203, 197, 199],
+        [9, 211, 4, 270, 272, 272, 267, 267, 272],
+        [4, 200, 2, 342, 339, 342, 341, 342, 343],
+        [6, 234, 5, 410, 409, 412, 410, 412, 409],
+        [9, 236, 5, 477, 480, 479, 478, 481, 477],
+        [2, 244, 8, 477, 477, 477, 481, 480, 475],
+        [9, 241, 10, 479, 478, 479, 478, 484, 477],
+        [11, 203, 7, 474, 478, 479, 478, 482, 476],
+        [12, 260, 10, 475, 483, 479, 481, 482, 476],
+        [12, 235, 6, 479, 480, 478, 480, 481, 475],
+        [6, 225, 6, 475, 477, 477, 476, 479, 479],
+    ];
+
+    /// Positioning towards 800..900, slowing down before the target
+    const POSITIONING_ONTO_THE_TARGET: &Recording = &[
+        [2, 11, 8, 197, 200, 202, 200, 197, 198],
+        [0, 12, 8, 297, 298, 300, 298, 297, 299],
+        [4, 10, 1, 401, 397, 397, 397, 401, 398],
+        [11, 4, 8, 501, 497, 499, 501, 497, 497],
+        [5, 2, 5, 603, 598, 601, 600, 598, 602],
+        [12, 3, 8, 699, 699, 701, 699, 700, 697],
+        [8, 12, 8, 758, 761, 761, 761, 761, 760],
+        [5, 10, 3, 788, 789, 788, 790, 790, 791],
+        [9, 12, 12, 799, 802, 798, 799, 802, 800],
+    ];
+
+    /// Feeds a recording through the payload parser and the monitor, returns
+    /// every reading's index that did not leave the hand moving or holding
+    fn replay(recording: &Recording, goal: Goal) -> Vec<(usize, Result<Progress, GripFault>)> {
+        let mut monitor = GripMonitor::new();
+        recording
+            .iter()
+            .map(|values| {
+                let payload: Vec<u8> = values
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect();
+                SensorReadings::parse(&payload).unwrap()
+            })
+            .map(|readings| monitor.update(&readings, &LIMITS, &goal))
+            .enumerate()
+            .filter(|(_, outcome)| !matches!(outcome, Ok(Progress::Moving | Progress::Holding)))
+            .collect()
+    }
+
+    #[test]
+    fn over_force_is_reported_once_per_press() {
+        assert_eq!(
+            replay(CLOSING_ON_A_CUP, Goal::Close),
+            [
+                (
+                    7,
+                    Err(GripFault::OverForce {
+                        sensor: 1,
+                        pressure: 1012
+                    })
+                ),
+                (
+                    11,
+                    Err(GripFault::OverForce {
+                        sensor: 1,
+                        pressure: 1005
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn pressure_rising_after_a_close_stalls_is_caught() {
+        assert_eq!(
+            replay(CLOSING_ON_A_SPONGE, Goal::Close),
+            [
+                (9, Err(GripFault::Stall)),
+                (
+                    14,
+                    Err(GripFault::OverForce {
+                        sensor: 0,
+                        pressure: 1010
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn end_stop_jitter_completes_the_opening() {
+        assert_eq!(
+            replay(OPENING_TO_THE_END_STOP, Goal::Open),
+            [(12, Ok(Progress::Complete))]
+        );
+    }
+
+    #[test]
+    fn obstacle_stalls_the_positioning() {
+        assert_eq!(
+            replay(POSITIONING_INTO_AN_OBSTACLE, target(800, 900)),
+            [(10, Err(GripFault::Stall))]
+        );
+    }
+
+    #[test]
+    fn slow_arrival_completes_the_positioning() {
+        assert_eq!(
+            replay(POSITIONING_ONTO_THE_TARGET, target(800, 900)),
+            [(8, Ok(Progress::Complete))]
+        );
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        assert!(SensorReadings::parse(&[0; 17]).is_none());
+        assert!(SensorReadings::parse(&[0; 20]).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+//! `ResponseDeviceInfo` payload, exchanged in both directions.
+
+use defmt::Format;
+
+/// Version of the packet protocol, devices with different major versions do
+/// not understand each other
+pub const PROTOCOL_MAJOR: u8 = 1;
+pub const PROTOCOL_MINOR: u8 = 0;
+
+pub const DEVICE_INFO_LEN: usize = 18;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct DeviceInfo {
+    pub protocol_major: u8,
+    pub protocol_minor: u8,
+    /// Major, minor and patch version
+    pub firmware: [u8; 3],
+    pub hardware_revision: u8,
+    pub serial: [u8; 8],
+    /// Bit `n` set when the command with code `n` is supported
+    pub commands: u32,
+}
+
+impl DeviceInfo {
+    /// Protocol version, firmware version, hardware revision, serial and
+    /// command bitmap, multi-byte values little endian
+    pub fn to_bytes(&self) -> [u8; DEVICE_INFO_LEN] {
+        let mut bytes = [0; DEVICE_INFO_LEN];
+        bytes[0] = self.protocol_major;
+        bytes[1] = self.protocol_minor;
+        bytes[2..5].copy_from_slice(&self.firmware);
+        bytes[5] = self.hardware_revision;
+        bytes[6..14].copy_from_slice(&self.serial);
+        bytes[14..].copy_from_slice(&self.commands.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != DEVICE_INFO_LEN {
+            return None;
+        }
+        Some(Self {
+            protocol_major: bytes[0],
+            protocol_minor: bytes[1],
+            firmware: [bytes[2], bytes[3], bytes[4]],
+            hardware_revision: bytes[5],
+            serial: [
+                bytes[6], bytes[7], bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13],
+            ],
+            commands: u32::from_le_bytes([bytes[14], bytes[15], bytes[16], bytes[17]]),
+        })
+    }
+
+    /// Minor versions only add commands, which `supports` tells about
+    pub fn compatible(&self) -> bool {
+        self.protocol_major == PROTOCOL_MAJOR
+    }
+
+    pub fn supports(&self, code: u8) -> bool {
+        code < 32 && self.commands & (1 << code) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: DeviceInfo = DeviceInfo {
+        protocol_major: PROTOCOL_MAJOR,
+        protocol_minor: PROTOCOL_MINOR,
+        firmware: [1, 2, 3],
+        hardware_revision: 4,
+        serial: [0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80],
+        commands: 0x8000_0005,
+    };
+
+    #[test]
+    fn round_trip() {
+        let bytes = INFO.to_bytes();
+        assert_eq!(&bytes[..6], &[PROTOCOL_MAJOR, PROTOCOL_MINOR, 1, 2, 3, 4]);
+        assert_eq!(&bytes[14..], &[0x05, 0, 0, 0x80]);
+        assert_eq!(DeviceInfo::from_bytes(&bytes), Some(INFO));
+    }
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        let bytes = INFO.to_bytes();
+        assert_eq!(DeviceInfo::from_bytes(&bytes[..DEVICE_INFO_LEN - 1]), None);
+        assert_eq!(DeviceInfo::from_bytes(&[0; DEVICE_INFO_LEN + 1]), None);
+    }
+
+    #[test]
+    fn only_the_major_version_decides_compatibility() {
+        let newer_minor = DeviceInfo {
+            protocol_minor: PROTOCOL_MINOR + 1,
+            ..INFO
+        };
+        assert!(newer_minor.compatible());
+        let other_major = DeviceInfo {
+            protocol_major: PROTOCOL_MAJOR + 1,
+            ..INFO
+        };
+        assert!(!other_major.compatible());
+    }
+
+    #[test]
+    fn supported_commands() {
+        assert!(INFO.supports(0));
+        assert!(!INFO.supports(1));
+        assert!(INFO.supports(2));
+        assert!(INFO.supports(31));
+        assert!(!INFO.supports(32));
+        assert!(!INFO.supports(u8::MAX));
+    }
+}
@@ -0,0 +1,427 @@
+use super::adaptive_notch::AdaptiveNotch;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NotchFrequency {
+    Freq50Hz = 50,
+    Freq60Hz = 60,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SampleFrequency {
+    Freq500Hz = 500,
+    Freq1000Hz = 1000,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NotchMode {
+    /// Fixed anti-hum filter tuned to the nominal mains frequency
+    Fixed,
+    /// LMS canceller tracking the mains frequency and its harmonics
+    Adaptive,
+}
+
+#[derive(Clone, Copy)]
+enum FilterType {
+    Lowpass,
+    Highpass,
+}
+
+// Constants for filter coefficients
+const LPF_NUMERATOR_COEF: [[f32; 3]; 2] = [[0.3913, 0.7827, 0.3913], [0.1311, 0.2622, 0.1311]];
+
+const LPF_DENOMINATOR_COEF: [[f32; 3]; 2] = [[1.0000, 0.3695, 0.1958], [1.0000, -0.7478, 0.2722]];
+
+const HPF_NUMERATOR_COEF: [[f32; 3]; 2] = [[0.8371, -1.6742, 0.8371], [0.9150, -1.8299, 0.9150]];
+
+const HPF_DENOMINATOR_COEF: [[f32; 3]; 2] = [[1.0000, -1.6475, 0.7009], [1.0000, -1.8227, 0.8372]];
+
+// Anti-hum filter coefficients for 50Hz
+const AHF_NUMERATOR_COEF_50HZ: [[f32; 6]; 2] = [
+    [0.9522, -1.5407, 0.9522, 0.8158, -0.8045, 0.0855],
+    [0.5869, -1.1146, 0.5869, 1.0499, -2.0000, 1.0499],
+];
+
+const AHF_DENOMINATOR_COEF_50HZ: [[f32; 6]; 2] = [
+    [1.0000, -1.5395, 0.9056, 1.0000, -1.1187, 0.3129],
+    [1.0000, -1.8844, 0.9893, 1.0000, -1.8991, 0.9892],
+];
+
+const AHF_OUTPUT_GAIN_COEF_50HZ: [f32; 2] = [1.3422, 1.4399];
+
+// Anti-hum filter coefficients for 60Hz
+const AHF_NUMERATOR_COEF_60HZ: [[f32; 6]; 2] = [
+    [0.9528, -1.3891, 0.9528, 0.8272, -0.7225, 0.0264],
+    [0.5824, -1.0810, 0.5824, 1.0736, -2.0000, 1.0736],
+];
+
+const AHF_DENOMINATOR_COEF_60HZ: [[f32; 6]; 2] = [
+    [1.0000, -1.3880, 0.9066, 1.0000, -0.9739, 0.2371],
+    [1.0000, -1.8407, 0.9894, 1.0000, -1.8584, 0.9891],
+];
+
+const AHF_OUTPUT_GAIN_COEF_60HZ: [f32; 2] = [1.3430, 1.4206];
+
+struct Filter2nd {
+    states: [f32; 2],
+    num: [f32; 3],
+    den: [f32; 3],
+}
+
+impl Filter2nd {
+    fn new() -> Self {
+        Self {
+            states: [0.0; 2],
+            num: [0.0; 3],
+            den: [0.0; 3],
+        }
+    }
+
+    fn init(&mut self, ftype: FilterType, sample_freq: SampleFrequency) {
+        self.states = [0.0; 2];
+
+        let idx = match sample_freq {
+            SampleFrequency::Freq500Hz => 0,
+            SampleFrequency::Freq1000Hz => 1,
+        };
+
+        match ftype {
+            FilterType::Lowpass => {
+                self.num.copy_from_slice(&LPF_NUMERATOR_COEF[idx]);
+                self.den.copy_from_slice(&LPF_DENOMINATOR_COEF[idx]);
+            }
+            FilterType::Highpass => {
+                self.num.copy_from_slice(&HPF_NUMERATOR_COEF[idx]);
+                self.den.copy_from_slice(&HPF_DENOMINATOR_COEF[idx]);
+            }
+        }
+    }
+
+    fn update(&mut self, input: f32) -> f32 {
+        let tmp =
+            (input - self.den[1] * self.states[0] - self.den[2] * self.states[1]) / self.den[0];
+        let output =
+            self.num[0] * tmp + self.num[1] * self.states[0] + self.num[2] * self.states[1];
+
+        self.states[1] = self.states[0];
+        self.states[0] = tmp;
+
+        output
+    }
+
+    /// Sets the states to the steady state for a constant `input` and
+    /// returns the matching output.
+    fn prime(&mut self, input: f32) -> f32 {
+        let tmp = input / (self.den[0] + self.den[1] + self.den[2]);
+        self.states = [tmp; 2];
+
+        (self.num[0] + self.num[1] + self.num[2]) * tmp
+    }
+}
+
+struct Filter4th {
+    states: [f32; 4],
+    num: [f32; 6],
+    den: [f32; 6],
+    gain: f32,
+}
+
+impl Filter4th {
+    fn new() -> Self {
+        Self {
+            states: [0.0; 4],
+            num: [0.0; 6],
+            den: [0.0; 6],
+            gain: 0.0,
+        }
+    }
+
+    fn init(&mut self, sample_freq: SampleFrequency, hum_freq: NotchFrequency) {
+        self.states = [0.0; 4];
+
+        let idx = match sample_freq {
+            SampleFrequency::Freq500Hz => 0,
+            SampleFrequency::Freq1000Hz => 1,
+        };
+
+        match hum_freq {
+            NotchFrequency::Freq50Hz => {
+                self.num.copy_from_slice(&AHF_NUMERATOR_COEF_50HZ[idx]);
+                self.den.copy_from_slice(&AHF_DENOMINATOR_COEF_50HZ[idx]);
+                self.gain = AHF_OUTPUT_GAIN_COEF_50HZ[idx];
+            }
+            NotchFrequency::Freq60Hz => {
+                self.num.copy_from_slice(&AHF_NUMERATOR_COEF_60HZ[idx]);
+                self.den.copy_from_slice(&AHF_DENOMINATOR_COEF_60HZ[idx]);
+                self.gain = AHF_OUTPUT_GAIN_COEF_60HZ[idx];
+            }
+        }
+    }
+
+    fn update(&mut self, input: f32) -> f32 {
+        let mut stage_out = self.num[0] * input + self.states[0];
+        self.states[0] = (self.num[1] * input + self.states[1]) - self.den[1] * stage_out;
+        self.states[1] = self.num[2] * input - self.den[2] * stage_out;
+        let stage_in = stage_out;
+        stage_out = self.num[3] * stage_out + self.states[2];
+        self.states[2] = (self.num[4] * stage_in + self.states[3]) - self.den[4] * stage_out;
+        self.states[3] = self.num[5] * stage_in - self.den[5] * stage_out;
+
+        self.gain * stage_out
+    }
+
+    /// Sets the states to the steady state for a constant `input` and
+    /// returns the matching output.
+    fn prime(&mut self, input: f32) -> f32 {
+        let stage1_out =
+            input * (self.num[0] + self.num[1] + self.num[2]) / (1.0 + self.den[1] + self.den[2]);
+        self.states[0] = stage1_out - self.num[0] * input;
+        self.states[1] = self.num[2] * input - self.den[2] * stage1_out;
+
+        let stage2_out = stage1_out * (self.num[3] + self.num[4] + self.num[5])
+            / (1.0 + self.den[4] + self.den[5]);
+        self.states[2] = stage2_out - self.num[3] * stage1_out;
+        self.states[3] = self.num[5] * stage1_out - self.den[5] * stage2_out;
+
+        self.gain * stage2_out
+    }
+}
+
+/// Runtime configuration of the filter chain
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FilterSettings {
+    pub notch_freq: NotchFrequency,
+    pub notch_mode: NotchMode,
+    pub notch_enabled: bool,
+    pub lowpass_enabled: bool,
+    pub highpass_enabled: bool,
+}
+
+impl FilterSettings {
+    const NOTCH_BIT: u8 = 1 << 0;
+    const LOWPASS_BIT: u8 = 1 << 1;
+    const HIGHPASS_BIT: u8 = 1 << 2;
+    const NOTCH_60HZ_BIT: u8 = 1 << 3;
+    const ADAPTIVE_NOTCH_BIT: u8 = 1 << 4;
+
+    /// Bit 0: notch, bit 1: lowpass, bit 2: highpass, bit 3: 60 Hz notch,
+    /// bit 4: adaptive notch
+    pub fn to_bits(&self) -> u8 {
+        let mut bits = 0;
+        if self.notch_enabled {
+            bits |= Self::NOTCH_BIT;
+        }
+        if self.lowpass_enabled {
+            bits |= Self::LOWPASS_BIT;
+        }
+        if self.highpass_enabled {
+            bits |= Self::HIGHPASS_BIT;
+        }
+        if self.notch_freq == NotchFrequency::Freq60Hz {
+            bits |= Self::NOTCH_60HZ_BIT;
+        }
+        if self.notch_mode == NotchMode::Adaptive {
+            bits |= Self::ADAPTIVE_NOTCH_BIT;
+        }
+        bits
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self {
+            notch_freq: if bits & Self::NOTCH_60HZ_BIT != 0 {
+                NotchFrequency::Freq60Hz
+            } else {
+                NotchFrequency::Freq50Hz
+            },
+            notch_mode: if bits & Self::ADAPTIVE_NOTCH_BIT != 0 {
+                NotchMode::Adaptive
+            } else {
+                NotchMode::Fixed
+            },
+            notch_enabled: bits & Self::NOTCH_BIT != 0,
+            lowpass_enabled: bits & Self::LOWPASS_BIT != 0,
+            highpass_enabled: bits & Self::HIGHPASS_BIT != 0,
+        }
+    }
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        Self {
+            notch_freq: NotchFrequency::Freq50Hz,
+            notch_mode: NotchMode::Fixed,
+            notch_enabled: true,
+            lowpass_enabled: true,
+            highpass_enabled: true,
+        }
+    }
+}
+
+pub struct EMGFilters {
+    lpf: Filter2nd,
+    hpf: Filter2nd,
+    ahf: Filter4th,
+    anf: AdaptiveNotch,
+    sample_freq: SampleFrequency,
+    settings: FilterSettings,
+    last_input: f32,
+}
+
+impl EMGFilters {
+    pub fn new() -> Self {
+        Self {
+            lpf: Filter2nd::new(),
+            hpf: Filter2nd::new(),
+            ahf: Filter4th::new(),
+            anf: AdaptiveNotch::new(),
+            sample_freq: SampleFrequency::Freq500Hz,
+            settings: FilterSettings::default(),
+            last_input: 0.0,
+        }
+    }
+
+    pub fn init(&mut self, sample_freq: SampleFrequency, settings: FilterSettings) {
+        self.sample_freq = sample_freq;
+        self.settings = settings;
+
+        self.lpf.init(FilterType::Lowpass, sample_freq);
+        self.hpf.init(FilterType::Highpass, sample_freq);
+        self.ahf.init(sample_freq, settings.notch_freq);
+        self.anf.init(sample_freq, settings.notch_freq);
+
+        self.reset();
+    }
+
+    /// Applies new settings while running, resetting the chain if anything changed.
+    pub fn configure(&mut self, settings: FilterSettings) {
+        if settings == self.settings {
+            return;
+        }
+
+        if settings.notch_freq != self.settings.notch_freq {
+            self.ahf.init(self.sample_freq, settings.notch_freq);
+            self.anf.init(self.sample_freq, settings.notch_freq);
+        }
+
+        self.settings = settings;
+        self.reset();
+    }
+
+    /// Settles every stage on the last input so that a reconfiguration
+    /// does not produce a step transient.
+    pub fn reset(&mut self) {
+        let mut value = self.last_input;
+
+        // The adaptive canceller passes DC unchanged
+        let notch_out = self.ahf.prime(value);
+        self.anf.reset(value);
+        if self.settings.notch_enabled && self.settings.notch_mode == NotchMode::Fixed {
+            value = notch_out;
+        }
+
+        let lowpass_out = self.lpf.prime(value);
+        if self.settings.lowpass_enabled {
+            value = lowpass_out;
+        }
+
+        self.hpf.prime(value);
+    }
+
+    pub fn update(&mut self, input_value: i32) -> i32 {
+        let mut output = input_value as f32;
+        self.last_input = output;
+
+        if self.settings.notch_enabled {
+            output = match self.settings.notch_mode {
+                NotchMode::Fixed => self.ahf.update(output),
+                NotchMode::Adaptive => self.anf.update(output),
+            };
+        }
+
+        if self.settings.lowpass_enabled {
+            output = self.lpf.update(output);
+        }
+
+        if self.settings.highpass_enabled {
+            output = self.hpf.update(output);
+        }
+
+        output as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: i32 = 2000;
+
+    fn chain(settings: FilterSettings) -> EMGFilters {
+        let mut filters = EMGFilters::new();
+        filters.init(SampleFrequency::Freq500Hz, settings);
+        filters
+    }
+
+    /// Output once every transient of a chain started from zero has died away
+    fn steady_output(settings: FilterSettings) -> i32 {
+        let mut filters = chain(settings);
+        (0..5000).map(|_| filters.update(INPUT)).last().unwrap()
+    }
+
+    /// Largest distance of the next `samples` outputs from the steady output
+    fn deviation(filters: &mut EMGFilters, settings: FilterSettings, samples: usize) -> i32 {
+        let steady = steady_output(settings);
+        (0..samples)
+            .map(|_| (filters.update(INPUT) - steady).abs())
+            .max()
+            .unwrap()
+    }
+
+    const PASS_DC: FilterSettings = FilterSettings {
+        notch_freq: NotchFrequency::Freq50Hz,
+        notch_mode: NotchMode::Fixed,
+        notch_enabled: true,
+        lowpass_enabled: true,
+        highpass_enabled: false,
+    };
+
+    #[test]
+    fn primed_chain_has_no_step_transient() {
+        for settings in [PASS_DC, FilterSettings::default()] {
+            let mut filters = chain(settings);
+            filters.update(INPUT);
+            filters.reset();
+            let worst = deviation(&mut filters, settings, 500);
+            assert!(worst <= 1, "{} with {:#x}", worst, settings.to_bits());
+        }
+    }
+
+    #[test]
+    fn reconfiguring_a_constant_input_has_no_step_transient() {
+        let mut filters = chain(PASS_DC);
+        for _ in 0..5000 {
+            filters.update(INPUT);
+        }
+
+        for settings in [
+            FilterSettings {
+                notch_freq: NotchFrequency::Freq60Hz,
+                ..PASS_DC
+            },
+            FilterSettings {
+                notch_mode: NotchMode::Adaptive,
+                ..PASS_DC
+            },
+            FilterSettings {
+                lowpass_enabled: false,
+                notch_enabled: false,
+                ..PASS_DC
+            },
+            PASS_DC,
+            FilterSettings::default(),
+        ] {
+            filters.configure(settings);
+            let worst = deviation(&mut filters, settings, 500);
+            assert!(worst <= 1, "{} after {:#x}", worst, settings.to_bits());
+        }
+    }
+}
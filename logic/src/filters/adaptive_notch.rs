@@ -0,0 +1,213 @@
+use core::f32::consts::PI;
+
+use super::EMG::{NotchFrequency, SampleFrequency};
+
+/// Number of mains components cancelled, fundamental included
+const HARMONICS: usize = 3;
+/// Step size of the harmonic amplitude/phase weights
+const WEIGHT_STEP: f32 = 0.01;
+/// Step size of the fundamental frequency tracker, in Hz per unit phase error
+const FREQ_STEP: f32 = 0.002;
+/// How far the tracked fundamental may drift from the nominal mains frequency
+const MAX_DEVIATION_HZ: f32 = 2.0;
+/// Harmonic amplitude (squared) below which frequency tracking is frozen
+const MIN_LOCK_POWER: f32 = 1.0;
+/// Smoothing factor of the DC level removed before adaptation
+const DC_ALPHA: f32 = 0.01;
+
+/// Power-line interference canceller.
+///
+/// Synthesizes sine/cosine references for the mains fundamental and its
+/// first harmonics, fits them to the input with LMS and subtracts the fit.
+/// The fundamental frequency itself is adapted from the phase error so that
+/// the canceller stays locked when the mains frequency drifts.
+pub struct AdaptiveNotch {
+    sample_freq: f32,
+    nominal_freq: f32,
+    freq: f32,
+    // Unit phasor of the fundamental and its per-sample rotation
+    phase: (f32, f32),
+    rotation: (f32, f32),
+    weights: [(f32, f32); HARMONICS],
+    dc: f32,
+}
+
+impl AdaptiveNotch {
+    pub fn new() -> Self {
+        let mut notch = Self {
+            sample_freq: 0.0,
+            nominal_freq: 0.0,
+            freq: 0.0,
+            phase: (1.0, 0.0),
+            rotation: (1.0, 0.0),
+            weights: [(0.0, 0.0); HARMONICS],
+            dc: 0.0,
+        };
+        notch.init(SampleFrequency::Freq500Hz, NotchFrequency::Freq50Hz);
+        notch
+    }
+
+    pub fn init(&mut self, sample_freq: SampleFrequency, hum_freq: NotchFrequency) {
+        self.sample_freq = sample_freq as u32 as f32;
+        self.nominal_freq = hum_freq as u32 as f32;
+        self.reset(0.0);
+    }
+
+    /// Forgets the learned interference and restarts tracking at the nominal
+    /// frequency, settled on a constant `input`.
+    pub fn reset(&mut self, input: f32) {
+        self.dc = input;
+        self.phase = (1.0, 0.0);
+        self.weights = [(0.0, 0.0); HARMONICS];
+        self.set_freq(self.nominal_freq);
+    }
+
+    pub fn update(&mut self, input: f32) -> f32 {
+        let mut references = [(0.0, 0.0); HARMONICS];
+        let mut harmonic = self.phase;
+        for reference in references.iter_mut() {
+            *reference = harmonic;
+            harmonic = complex_mul(harmonic, self.phase);
+        }
+
+        let mut estimate = 0.0;
+        for (&(c, s), &(a, b)) in references.iter().zip(self.weights.iter()) {
+            estimate += a * c + b * s;
+        }
+        // The DC level passes through untouched but must not disturb the fit
+        self.dc += DC_ALPHA * (input - self.dc);
+        let error = input - self.dc - estimate;
+
+        // Derivative of the estimate with respect to the fundamental phase
+        let mut slope = 0.0;
+        let mut power = 0.0;
+        for (h, (&(c, s), &(a, b))) in references.iter().zip(self.weights.iter()).enumerate() {
+            let order = (h + 1) as f32;
+            slope += order * (b * c - a * s);
+            power += order * order * (a * a + b * b);
+        }
+
+        let step = WEIGHT_STEP / HARMONICS as f32;
+        for (&(c, s), weight) in references.iter().zip(self.weights.iter_mut()) {
+            weight.0 += step * error * c;
+            weight.1 += step * error * s;
+        }
+
+        if power > MIN_LOCK_POWER {
+            let freq = self.freq + FREQ_STEP * error * slope / power;
+            let freq = freq.clamp(
+                self.nominal_freq - MAX_DEVIATION_HZ,
+                self.nominal_freq + MAX_DEVIATION_HZ,
+            );
+            self.set_freq(freq);
+        }
+
+        self.phase = complex_mul(self.phase, self.rotation);
+        // Keep the phasor on the unit circle despite rounding
+        let magnitude = self.phase.0 * self.phase.0 + self.phase.1 * self.phase.1;
+        let correction = (3.0 - magnitude) / 2.0;
+        self.phase = (self.phase.0 * correction, self.phase.1 * correction);
+
+        input - estimate
+    }
+
+    fn set_freq(&mut self, freq: f32) {
+        self.freq = freq;
+        let (sin, cos) = libm::sincosf(2.0 * PI * freq / self.sample_freq);
+        self.rotation = (cos, sin);
+    }
+}
+
+fn complex_mul(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 500.0;
+    /// Hum amplitude of the fundamental and its harmonics, in ADC counts
+    const HUM: [f32; HARMONICS] = [300.0, 100.0, 50.0];
+
+    /// Uniform noise in `-amplitude..amplitude` standing in for EMG,
+    /// deterministic so that failures reproduce
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self, amplitude: f32) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            ((self.0 >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+        }
+    }
+
+    /// Feeds `seconds` of noise on a DC level plus hum whose fundamental
+    /// moves linearly from `from` to `to` Hz. Returns the power of the hum
+    /// left in the output over the last half, relative to the hum's, in dB.
+    fn residual_hum_db(notch: &mut AdaptiveNotch, from: f32, to: f32, seconds: f32) -> f32 {
+        let samples = (seconds * SAMPLE_RATE) as usize;
+        let mut noise = Noise(1);
+        let mut phase = 0.0f32;
+        let mut residual = 0.0;
+        let mut hum_power = 0.0;
+        for n in 0..samples {
+            let freq = from + (to - from) * n as f32 / samples as f32;
+            phase = (phase + 2.0 * PI * freq / SAMPLE_RATE) % (2.0 * PI);
+            let hum: f32 = HUM
+                .iter()
+                .enumerate()
+                .map(|(h, amplitude)| amplitude * libm::sinf((h + 1) as f32 * phase))
+                .sum();
+            let clean = 2048.0 + noise.next(50.0);
+
+            let output = notch.update(clean + hum);
+            if n >= samples / 2 {
+                residual += (output - clean) * (output - clean);
+                hum_power += hum * hum;
+            }
+        }
+        10.0 * libm::log10f(residual / hum_power)
+    }
+
+    #[test]
+    fn cancels_steady_hum() {
+        let mut notch = AdaptiveNotch::new();
+        notch.reset(2048.0);
+        let residual = residual_hum_db(&mut notch, 50.0, 50.0, 10.0);
+        assert!(residual < -25.0, "hum reduced by {} dB only", -residual);
+    }
+
+    #[test]
+    fn locks_onto_off_nominal_hum() {
+        let mut notch = AdaptiveNotch::new();
+        notch.reset(2048.0);
+        let residual = residual_hum_db(&mut notch, 51.2, 51.2, 10.0);
+        assert!(residual < -25.0, "hum reduced by {} dB only", -residual);
+        assert!(
+            (notch.freq - 51.2).abs() < 0.05,
+            "tracking {} Hz",
+            notch.freq
+        );
+    }
+
+    #[test]
+    fn follows_drifting_hum() {
+        let mut notch = AdaptiveNotch::new();
+        notch.reset(2048.0);
+        let residual = residual_hum_db(&mut notch, 49.5, 51.0, 30.0);
+        assert!(residual < -15.0, "hum reduced by {} dB only", -residual);
+        assert!(
+            (notch.freq - 51.0).abs() < 0.1,
+            "tracking {} Hz",
+            notch.freq
+        );
+    }
+
+    #[test]
+    fn tracking_is_limited_to_the_allowed_deviation() {
+        let mut notch = AdaptiveNotch::new();
+        notch.reset(2048.0);
+        residual_hum_db(&mut notch, 55.0, 55.0, 10.0);
+        assert!((notch.freq - 50.0).abs() <= MAX_DEVIATION_HZ);
+    }
+}
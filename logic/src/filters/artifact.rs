@@ -0,0 +1,354 @@
+use super::{envelope::Ema, EMG::SampleFrequency};
+
+/// Time constant of the fast baseline, passing motion artifacts but not EMG
+const FAST_BASELINE_MS: u16 = 15;
+/// Time constant of the slow baseline the fast one is compared against
+const SLOW_BASELINE_MS: u16 = 1000;
+/// Fraction of the full scale treated as clipped
+const CLIP_MARGIN: f32 = 0.01;
+/// Samples kept out of the envelope after the last artifact
+const BLANKING_MS: u32 = 100;
+
+/// How readily samples are rejected as artifacts
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactSensitivity {
+    /// Only clipped samples are rejected
+    Off = 0,
+    Low = 1,
+    Medium = 2,
+    High = 3,
+}
+
+impl TryFrom<u8> for ArtifactSensitivity {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Off),
+            1 => Ok(Self::Low),
+            2 => Ok(Self::Medium),
+            3 => Ok(Self::High),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for ArtifactSensitivity {
+    fn default() -> Self {
+        Self::Medium
+    }
+}
+
+/// Outlier limits, as fractions of half the converter range
+struct Limits {
+    /// Filtered sample amplitude
+    amplitude: f32,
+    /// Raw sample-to-sample step
+    slope: f32,
+    /// Distance between the fast and slow baseline
+    baseline: f32,
+}
+
+impl ArtifactSensitivity {
+    fn limits(&self) -> Option<Limits> {
+        match self {
+            Self::Off => None,
+            Self::Low => Some(Limits {
+                amplitude: 0.6,
+                slope: 0.6,
+                baseline: 0.25,
+            }),
+            Self::Medium => Some(Limits {
+                amplitude: 0.45,
+                slope: 0.4,
+                baseline: 0.15,
+            }),
+            Self::High => Some(Limits {
+                amplitude: 0.3,
+                slope: 0.3,
+                baseline: 0.08,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum ArtifactKind {
+    /// Raw sample at a converter rail
+    Clipped,
+    /// Filtered sample far larger than any plausible EMG
+    Amplitude,
+    /// Raw sample jumped from the previous one
+    Slope,
+    /// Low-frequency baseline moved away from its long-term level
+    BaselineShift,
+}
+
+/// Number of samples flagged by each check, and the resulting blanking
+#[derive(Clone, Copy, Default)]
+pub struct ArtifactStats {
+    pub clipped: u32,
+    pub amplitude: u32,
+    pub slope: u32,
+    pub baseline: u32,
+    /// Separate artifacts, a new one starts after the blanking period ran out
+    pub episodes: u32,
+    pub blanked_samples: u32,
+}
+
+impl ArtifactStats {
+    fn count(&mut self, kind: ArtifactKind) {
+        let counter = match kind {
+            ArtifactKind::Clipped => &mut self.clipped,
+            ArtifactKind::Amplitude => &mut self.amplitude,
+            ArtifactKind::Slope => &mut self.slope,
+            ArtifactKind::BaselineShift => &mut self.baseline,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Flags motion artifacts and clipping, and blanks the samples around them.
+pub struct ArtifactDetector {
+    min: i32,
+    max: i32,
+    half_range: f32,
+    limits: Option<Limits>,
+    sample_freq: SampleFrequency,
+    blanking_len: u32,
+
+    fast_baseline: Ema,
+    slow_baseline: Ema,
+    last_raw: Option<i32>,
+    blank_remaining: u32,
+    stats: ArtifactStats,
+}
+
+impl ArtifactDetector {
+    /// `min` and `max` are the raw sample codes of the converter rails.
+    pub fn new(
+        min: i32,
+        max: i32,
+        sample_freq: SampleFrequency,
+        sensitivity: ArtifactSensitivity,
+    ) -> Self {
+        Self {
+            min,
+            max,
+            half_range: (max - min) as f32 / 2.0,
+            limits: sensitivity.limits(),
+            sample_freq,
+            blanking_len: sample_freq as u32 * BLANKING_MS / 1000,
+            fast_baseline: Ema::new(FAST_BASELINE_MS, sample_freq, 0.0),
+            slow_baseline: Ema::new(SLOW_BASELINE_MS, sample_freq, 0.0),
+            last_raw: None,
+            blank_remaining: 0,
+            stats: ArtifactStats::default(),
+        }
+    }
+
+    pub fn set_sensitivity(&mut self, sensitivity: ArtifactSensitivity) {
+        self.limits = sensitivity.limits();
+    }
+
+    pub fn stats(&self) -> &ArtifactStats {
+        &self.stats
+    }
+
+    /// Checks one sample. Returns the artifact that started a new blanking
+    /// period, if any, and whether this sample has to be blanked.
+    pub fn update(&mut self, raw: i32, filtered: i32) -> (Option<ArtifactKind>, bool) {
+        let kind = self.classify(raw, filtered);
+        self.last_raw = Some(raw);
+
+        let mut started = None;
+        if let Some(kind) = kind {
+            self.stats.count(kind);
+            if self.blank_remaining == 0 {
+                self.stats.episodes = self.stats.episodes.wrapping_add(1);
+                started = Some(kind);
+            }
+            self.blank_remaining = self.blanking_len;
+        }
+
+        if self.blank_remaining == 0 {
+            return (None, false);
+        }
+
+        self.blank_remaining -= 1;
+        self.stats.blanked_samples = self.stats.blanked_samples.wrapping_add(1);
+        (started, true)
+    }
+
+    fn classify(&mut self, raw: i32, filtered: i32) -> Option<ArtifactKind> {
+        let margin = (self.half_range * 2.0 * CLIP_MARGIN) as i32;
+        let clipped = raw <= self.min + margin || raw >= self.max - margin;
+
+        let Some(last_raw) = self.last_raw else {
+            // Start both baselines settled on the first sample
+            self.fast_baseline = Ema::new(FAST_BASELINE_MS, self.sample_freq, raw as f32);
+            self.slow_baseline = Ema::new(SLOW_BASELINE_MS, self.sample_freq, raw as f32);
+            return clipped.then_some(ArtifactKind::Clipped);
+        };
+
+        // A lasting offset is absorbed by the slow baseline within about a
+        // second, after which blanking ends
+        let fast = self.fast_baseline.update(raw as f32);
+        let slow = self.slow_baseline.update(raw as f32);
+
+        if clipped {
+            return Some(ArtifactKind::Clipped);
+        }
+
+        let limits = self.limits.as_ref()?;
+        if libm::fabsf(filtered as f32) > limits.amplitude * self.half_range {
+            Some(ArtifactKind::Amplitude)
+        } else if ((raw - last_raw).abs() as f32) > limits.slope * self.half_range {
+            Some(ArtifactKind::Slope)
+        } else if libm::fabsf(fast - slow) > limits.baseline * self.half_range {
+            Some(ArtifactKind::BaselineShift)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: i32 = 0;
+    const MAX: i32 = 4095;
+    const MID: i32 = 2048;
+    const SECOND: usize = 500;
+    /// 100 ms at 500 Hz
+    const BLANKING: usize = 50;
+    /// Half a period over the 50 samples of a burst
+    const PI_OVER_50: f32 = core::f32::consts::PI / 50.0;
+
+    /// EMG-like noise of up to `amplitude` counts, deterministic so that
+    /// failures reproduce
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self, amplitude: i32) -> i32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 16) as i32 % (2 * amplitude + 1) - amplitude
+        }
+    }
+
+    struct Replay {
+        started: Vec<(usize, ArtifactKind)>,
+        blanked: Vec<bool>,
+    }
+
+    /// Runs `seconds` of noise on mid-scale through a detector, `inject`
+    /// turns the sample index and clean raw sample into the raw and filtered
+    /// sample fed to it
+    fn replay(
+        sensitivity: ArtifactSensitivity,
+        seconds: usize,
+        mut inject: impl FnMut(usize, i32) -> (i32, i32),
+    ) -> Replay {
+        let mut detector = ArtifactDetector::new(MIN, MAX, SampleFrequency::Freq500Hz, sensitivity);
+        let mut noise = Noise(1);
+        let mut replay = Replay {
+            started: Vec::new(),
+            blanked: Vec::new(),
+        };
+        for n in 0..seconds * SECOND {
+            let (raw, filtered) = inject(n, MID + noise.next(100));
+            let (started, blanked) = detector.update(raw, filtered);
+            if let Some(kind) = started {
+                replay.started.push((n, kind));
+            }
+            replay.blanked.push(blanked);
+        }
+        replay
+    }
+
+    /// The clean signal is already centred, as the filter chain would leave it
+    fn clean(_: usize, raw: i32) -> (i32, i32) {
+        (raw, raw - MID)
+    }
+
+    #[test]
+    fn clean_emg_passes() {
+        for sensitivity in [
+            ArtifactSensitivity::Low,
+            ArtifactSensitivity::Medium,
+            ArtifactSensitivity::High,
+        ] {
+            let replay = replay(sensitivity, 5, clean);
+            assert!(replay.started.is_empty(), "{:?}", replay.started);
+            assert!(!replay.blanked.contains(&true));
+        }
+    }
+
+    #[test]
+    fn clipping_is_blanked() {
+        let replay = replay(ArtifactSensitivity::Off, 2, |n, raw| match n {
+            600..=609 => (MAX, MAX - MID),
+            _ => clean(n, raw),
+        });
+        assert_eq!(replay.started, [(600, ArtifactKind::Clipped)]);
+        // Blanking runs from the first clipped sample until 100 ms after the last
+        let blanked: Vec<usize> = (0..replay.blanked.len())
+            .filter(|&n| replay.blanked[n])
+            .collect();
+        assert_eq!(blanked.first(), Some(&600));
+        assert_eq!(blanked.last(), Some(&(609 + BLANKING - 1)));
+        assert_eq!(blanked.len(), 9 + BLANKING);
+    }
+
+    #[test]
+    fn electrode_tap_is_caught_by_the_slope() {
+        // A single raw spike the filter chain has not passed on yet
+        let replay = replay(ArtifactSensitivity::Medium, 2, |n, raw| match n {
+            700 => (raw + 1200, raw - MID),
+            _ => clean(n, raw),
+        });
+        assert_eq!(replay.started, [(700, ArtifactKind::Slope)]);
+    }
+
+    #[test]
+    fn movement_burst_is_caught_by_the_amplitude() {
+        let replay = replay(ArtifactSensitivity::Medium, 2, |n, raw| match n {
+            // A swing of 1000 counts at its peak, far above any EMG, left in the
+            // filtered signal. Amplitude is checked on that signal alone.
+            500..=549 => {
+                let swing = (1000.0 * libm::sinf(PI_OVER_50 * (n - 500) as f32)) as i32;
+                (raw, raw - MID + swing)
+            }
+            _ => clean(n, raw),
+        });
+        assert_eq!(replay.started.len(), 1);
+        assert_eq!(replay.started[0].1, ArtifactKind::Amplitude);
+    }
+
+    #[test]
+    fn cable_pull_shifts_the_baseline_until_it_settles() {
+        // The electrode offset jumps by 400 counts and stays, the filter chain
+        // removes it from the filtered signal
+        let replay = replay(ArtifactSensitivity::Medium, 6, |n, raw| match n {
+            1000.. => (raw + 400, raw - MID),
+            _ => clean(n, raw),
+        });
+        assert_eq!(replay.started.len(), 1);
+        let (start, kind) = replay.started[0];
+        assert_eq!(kind, ArtifactKind::BaselineShift);
+        assert!((1000..1000 + SECOND / 10).contains(&start), "{}", start);
+        // The slow baseline absorbs the offset and the signal is used again
+        assert!(!replay.blanked[4 * SECOND..].contains(&true));
+    }
+
+    #[test]
+    fn sensitivity_off_only_rejects_clipping() {
+        let replay = replay(ArtifactSensitivity::Off, 2, |n, raw| match n {
+            700 => (raw + 1200, raw - MID + 1200),
+            _ => clean(n, raw),
+        });
+        assert!(replay.started.is_empty());
+    }
+}
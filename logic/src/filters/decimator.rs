@@ -0,0 +1,178 @@
+use core::f32::consts::PI;
+
+/// Fractional bits of the fixed-point coefficients
+const COEFFICIENT_BITS: u32 = 15;
+
+/// Blackman-windowed sinc lowpass decimating by `FACTOR`, run as `FACTOR`
+/// polyphase branches of `PHASE_TAPS` taps each.
+///
+/// Works in fixed point so that the tap count affordable on the Cortex-M0+ is
+/// not limited by soft floats. Inputs must fit 16 bits.
+pub struct Decimator<const FACTOR: usize, const PHASE_TAPS: usize> {
+    // Q15 taps of branch `p`: h[p], h[p + FACTOR], h[p + 2 * FACTOR], ...
+    coefficients: [[i32; PHASE_TAPS]; FACTOR],
+    // Input history of each branch, the newest sample at `head`
+    history: [[i32; PHASE_TAPS]; FACTOR],
+    head: usize,
+    // Branch the next input belongs to, branch 0 completes an output
+    phase: usize,
+    accumulator: i32,
+    output_shift: u32,
+}
+
+impl<const FACTOR: usize, const PHASE_TAPS: usize> Decimator<FACTOR, PHASE_TAPS> {
+    /// `cutoff` is the -6 dB frequency as a fraction of the input rate, usually
+    /// the output Nyquist frequency `0.5 / FACTOR`. Outputs are scaled by
+    /// `2^gain_bits` to keep the resolution gained by averaging.
+    pub fn new(cutoff: f32, gain_bits: u32) -> Self {
+        let mut decimator = Self {
+            coefficients: [[0; PHASE_TAPS]; FACTOR],
+            history: [[0; PHASE_TAPS]; FACTOR],
+            head: 0,
+            phase: FACTOR - 1,
+            accumulator: 0,
+            output_shift: COEFFICIENT_BITS - gain_bits,
+        };
+        decimator.design(cutoff);
+        decimator
+    }
+
+    fn design(&mut self, cutoff: f32) {
+        let taps = FACTOR * PHASE_TAPS;
+        let center = (taps - 1) as f32 / 2.0;
+
+        let mut prototype = [[0.0f32; PHASE_TAPS]; FACTOR];
+        let mut sum = 0.0;
+        for n in 0..taps {
+            let t = n as f32 - center;
+            let sinc = if t == 0.0 {
+                2.0 * cutoff
+            } else {
+                libm::sinf(2.0 * PI * cutoff * t) / (PI * t)
+            };
+            let phase = 2.0 * PI * n as f32 / (taps - 1) as f32;
+            let window = 0.42 - 0.5 * libm::cosf(phase) + 0.08 * libm::cosf(2.0 * phase);
+
+            prototype[n % FACTOR][n / FACTOR] = sinc * window;
+            sum += sinc * window;
+        }
+
+        // Unity DC gain, with the rounding error of the quantized taps put
+        // into the largest one
+        let unity = 1i32 << COEFFICIENT_BITS;
+        let mut total = 0;
+        for (branch, taps) in self.coefficients.iter_mut().zip(prototype.iter()) {
+            for (coefficient, &tap) in branch.iter_mut().zip(taps.iter()) {
+                *coefficient = libm::roundf(tap / sum * unity as f32) as i32;
+                total += *coefficient;
+            }
+        }
+        let middle = taps / 2;
+        self.coefficients[middle % FACTOR][middle / FACTOR] += unity - total;
+    }
+
+    /// Feeds one input sample, every `FACTOR`th one completes an output.
+    pub fn push(&mut self, sample: i32) -> Option<i32> {
+        let history = &mut self.history[self.phase];
+        history[self.head] = sample;
+
+        // Newest to oldest input against the branch's taps, the history is
+        // circular so it is walked in two parts
+        let coefficients = &self.coefficients[self.phase];
+        let (older, newer) = history.split_at(self.head);
+        let (recent_taps, old_taps) = coefficients.split_at(PHASE_TAPS - self.head);
+        for (&c, &x) in recent_taps.iter().zip(newer.iter()) {
+            self.accumulator += c * x;
+        }
+        for (&c, &x) in old_taps.iter().zip(older.iter()) {
+            self.accumulator += c * x;
+        }
+
+        if self.phase > 0 {
+            self.phase -= 1;
+            return None;
+        }
+
+        let rounding = 1 << (self.output_shift - 1);
+        let output = (self.accumulator + rounding) >> self.output_shift;
+        self.accumulator = 0;
+        self.phase = FACTOR - 1;
+        // Older samples sit after the newest one, so the next one goes before it
+        self.head = if self.head == 0 {
+            PHASE_TAPS - 1
+        } else {
+            self.head - 1
+        };
+        Some(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The internal ADC configuration: 8 kHz in, 500 Hz out
+    const FACTOR: usize = 16;
+    const PHASE_TAPS: usize = 24;
+    const GAIN_BITS: u32 = 2;
+    const INPUT_RATE: f32 = 8000.0;
+    const OUTPUT_RATE: f32 = INPUT_RATE / FACTOR as f32;
+    const AMPLITUDE: f32 = 2000.0;
+    /// Outputs dropped while the filter fills
+    const SETTLE: usize = PHASE_TAPS * 2;
+    /// Outputs measured, a whole second
+    const MEASURED: usize = 500;
+
+    /// Gain in dB of the decimator for a sine at `freq` on a 12 bit ADC's
+    /// mid-scale, measured over the outputs after settling
+    fn gain_db(freq: f32) -> f32 {
+        let mut decimator = Decimator::<FACTOR, PHASE_TAPS>::new(0.5 / FACTOR as f32, GAIN_BITS);
+        let mut outputs = Vec::new();
+        let mut n = 0;
+        while outputs.len() < SETTLE + MEASURED {
+            let t = n as f32 / INPUT_RATE;
+            let sample = 2048.0 + AMPLITUDE * libm::sinf(2.0 * PI * freq * t);
+            if let Some(output) = decimator.push(libm::roundf(sample) as i32) {
+                outputs.push(output as f32 / (1 << GAIN_BITS) as f32);
+            }
+            n += 1;
+        }
+
+        let measured = &outputs[SETTLE..];
+        let mean = measured.iter().sum::<f32>() / MEASURED as f32;
+        let power = measured
+            .iter()
+            .map(|x| (x - mean) * (x - mean))
+            .sum::<f32>()
+            / MEASURED as f32;
+        10.0 * libm::log10f(power / (AMPLITUDE * AMPLITUDE / 2.0))
+    }
+
+    #[test]
+    fn dc_gain_is_exact() {
+        let mut decimator = Decimator::<FACTOR, PHASE_TAPS>::new(0.5 / FACTOR as f32, GAIN_BITS);
+        let outputs: Vec<i32> = (0..FACTOR * PHASE_TAPS * 4)
+            .filter_map(|_| decimator.push(1234))
+            .collect();
+        assert_eq!(outputs.last(), Some(&(1234 << GAIN_BITS)));
+    }
+
+    #[test]
+    fn passband_ripple() {
+        for freq in (10..=150).step_by(10) {
+            let gain = gain_db(freq as f32);
+            assert!(gain.abs() < 0.05, "{} Hz passed with {} dB", freq, gain);
+        }
+    }
+
+    #[test]
+    fn stopband_rejection() {
+        // Everything that would alias into the EMG band below 150 Hz
+        let mut freq = OUTPUT_RATE - 150.0;
+        while freq < INPUT_RATE / 2.0 {
+            let gain = gain_db(freq);
+            assert!(gain < -60.0, "{} Hz aliased with {} dB", freq, gain);
+            freq += 37.0;
+        }
+    }
+}
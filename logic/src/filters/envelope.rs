@@ -0,0 +1,341 @@
+use core::f32::consts::PI;
+
+use super::{mean::MovingAvg, EMG::SampleFrequency};
+
+/// Length of the box average used by [`EnvelopeKind::BoxAverage`]
+pub const BOX_AVERAGE_LEN: usize = 250;
+
+/// Hilbert transformer length, odd so that the in-phase branch is a whole-sample delay
+const HILBERT_TAPS: usize = 15;
+const HILBERT_DELAY: usize = HILBERT_TAPS / 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeKind {
+    /// Mean square over the last `BOX_AVERAGE_LEN` samples
+    BoxAverage = 0,
+    /// One-pole smoothing of the squared signal using the attack time constant
+    Exponential = 1,
+    /// Squared signal smoothed with separate rise and fall time constants
+    AttackRelease = 2,
+    /// Instantaneous power of the analytic signal, smoothed like `AttackRelease`
+    Hilbert = 3,
+}
+
+impl TryFrom<u8> for EnvelopeKind {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::BoxAverage),
+            1 => Ok(Self::Exponential),
+            2 => Ok(Self::AttackRelease),
+            3 => Ok(Self::Hilbert),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeSettings {
+    pub kind: EnvelopeKind,
+    pub attack_ms: u16,
+    pub release_ms: u16,
+}
+
+impl EnvelopeSettings {
+    /// Kind, attack time and release time (little endian milliseconds)
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let attack = self.attack_ms.to_le_bytes();
+        let release = self.release_ms.to_le_bytes();
+        [
+            self.kind as u8,
+            attack[0],
+            attack[1],
+            release[0],
+            release[1],
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8; 5]) -> Option<Self> {
+        Some(Self {
+            kind: EnvelopeKind::try_from(bytes[0]).ok()?,
+            attack_ms: u16::from_le_bytes([bytes[1], bytes[2]]),
+            release_ms: u16::from_le_bytes([bytes[3], bytes[4]]),
+        })
+    }
+}
+
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        Self {
+            kind: EnvelopeKind::BoxAverage,
+            attack_ms: 20,
+            release_ms: 150,
+        }
+    }
+}
+
+/// Smoothing coefficient of a one-pole filter with the given time constant
+fn coefficient(time_ms: u16, sample_freq: SampleFrequency) -> f32 {
+    if time_ms == 0 {
+        return 1.0;
+    }
+
+    let period_ms = 1000.0 / sample_freq as u32 as f32;
+    1.0 - libm::expf(-period_ms / time_ms as f32)
+}
+
+pub struct Ema {
+    alpha: f32,
+    value: f32,
+}
+
+impl Ema {
+    pub fn new(time_ms: u16, sample_freq: SampleFrequency, initial: f32) -> Self {
+        Self {
+            alpha: coefficient(time_ms, sample_freq),
+            value: initial,
+        }
+    }
+
+    pub fn update(&mut self, input: f32) -> f32 {
+        self.value += self.alpha * (input - self.value);
+        self.value
+    }
+}
+
+pub struct AttackRelease {
+    attack: f32,
+    release: f32,
+    value: f32,
+}
+
+impl AttackRelease {
+    pub fn new(
+        attack_ms: u16,
+        release_ms: u16,
+        sample_freq: SampleFrequency,
+        initial: f32,
+    ) -> Self {
+        Self {
+            attack: coefficient(attack_ms, sample_freq),
+            release: coefficient(release_ms, sample_freq),
+            value: initial,
+        }
+    }
+
+    pub fn update(&mut self, input: f32) -> f32 {
+        let alpha = if input > self.value {
+            self.attack
+        } else {
+            self.release
+        };
+        self.value += alpha * (input - self.value);
+        self.value
+    }
+}
+
+/// Envelope from a short FIR Hilbert transformer.
+///
+/// Only `HILBERT_DELAY` samples of latency are added before the
+/// attack/release smoothing.
+pub struct HilbertEnvelope {
+    taps: [f32; HILBERT_TAPS],
+    history: [f32; HILBERT_TAPS],
+    next: usize,
+    smoothing: AttackRelease,
+}
+
+impl HilbertEnvelope {
+    pub fn new(
+        attack_ms: u16,
+        release_ms: u16,
+        sample_freq: SampleFrequency,
+        initial: f32,
+    ) -> Self {
+        let mut taps = [0.0; HILBERT_TAPS];
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let n = i as i32 - HILBERT_DELAY as i32;
+            if n % 2 != 0 {
+                let window = 0.54 + 0.46 * libm::cosf(PI * n as f32 / (HILBERT_DELAY + 1) as f32);
+                *tap = 2.0 / (PI * n as f32) * window;
+            }
+        }
+
+        Self {
+            taps,
+            history: [0.0; HILBERT_TAPS],
+            next: 0,
+            smoothing: AttackRelease::new(attack_ms, release_ms, sample_freq, initial),
+        }
+    }
+
+    pub fn update(&mut self, input: f32) -> f32 {
+        self.history[self.next] = input;
+        self.next = (self.next + 1) % HILBERT_TAPS;
+
+        // history[next] is now the oldest sample
+        let mut quadrature = 0.0;
+        for (i, tap) in self.taps.iter().enumerate() {
+            quadrature += tap * self.history[(self.next + HILBERT_TAPS - 1 - i) % HILBERT_TAPS];
+        }
+        let in_phase = self.history[(self.next + HILBERT_DELAY) % HILBERT_TAPS];
+
+        // Halved so that the result matches the mean square of a sinusoid
+        let power = (in_phase * in_phase + quadrature * quadrature) / 2.0;
+        self.smoothing.update(power)
+    }
+}
+
+/// Follows the power of the filtered EMG signal, in squared ADC counts.
+#[allow(clippy::large_enum_variant)]
+pub enum Envelope {
+    BoxAverage(MovingAvg<i32, BOX_AVERAGE_LEN>),
+    Exponential(Ema),
+    AttackRelease(AttackRelease),
+    Hilbert(HilbertEnvelope),
+}
+
+impl Envelope {
+    /// Creates the envelope follower described by `settings`, starting at `initial`.
+    pub fn new(settings: EnvelopeSettings, sample_freq: SampleFrequency, initial: i32) -> Self {
+        let start = initial as f32;
+        match settings.kind {
+            EnvelopeKind::BoxAverage => {
+                let mut avg = MovingAvg::new(true);
+                avg.reading(initial);
+                Self::BoxAverage(avg)
+            }
+            EnvelopeKind::Exponential => {
+                Self::Exponential(Ema::new(settings.attack_ms, sample_freq, start))
+            }
+            EnvelopeKind::AttackRelease => Self::AttackRelease(AttackRelease::new(
+                settings.attack_ms,
+                settings.release_ms,
+                sample_freq,
+                start,
+            )),
+            EnvelopeKind::Hilbert => Self::Hilbert(HilbertEnvelope::new(
+                settings.attack_ms,
+                settings.release_ms,
+                sample_freq,
+                start,
+            )),
+        }
+    }
+
+    /// Envelope after `filtered_value`, saturating at `i32::MAX`
+    pub fn update(&mut self, filtered_value: i32) -> i32 {
+        let squared = filtered_value as i64 * filtered_value as i64;
+        match self {
+            Self::BoxAverage(avg) => avg.reading(squared.min(i32::MAX as i64) as i32),
+            Self::Exponential(ema) => ema.update(squared as f32) as i32,
+            Self::AttackRelease(follower) => follower.update(squared as f32) as i32,
+            Self::Hilbert(hilbert) => hilbert.update(filtered_value as f32) as i32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_FREQ: SampleFrequency = SampleFrequency::Freq500Hz;
+
+    fn settings(kind: EnvelopeKind) -> EnvelopeSettings {
+        EnvelopeSettings {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    /// Mean of the envelope over the last 100 of `samples` samples of a 50 Hz
+    /// sine, whole periods of it
+    fn sine_level(kind: EnvelopeKind, amplitude: f32, samples: usize) -> f32 {
+        let mut envelope = Envelope::new(settings(kind), SAMPLE_FREQ, 0);
+        let mut sum = 0.0;
+        for n in 0..samples {
+            let phase = 2.0 * PI * 50.0 * n as f32 / 500.0;
+            let output = envelope.update((amplitude * libm::sinf(phase)) as i32);
+            if n >= samples - 100 {
+                sum += output as f32;
+            }
+        }
+        sum / 100.0
+    }
+
+    #[test]
+    fn steady_state_is_the_mean_square_of_a_sine() {
+        // 1000 squared counts
+        let mean_square = 1000.0 * 1000.0 / 2.0;
+        for kind in [
+            EnvelopeKind::BoxAverage,
+            EnvelopeKind::Exponential,
+            EnvelopeKind::Hilbert,
+        ] {
+            let level = sine_level(kind, 1000.0, 2000);
+            assert!(
+                (level / mean_square - 1.0).abs() < 0.02,
+                "{} for kind {}",
+                level,
+                kind as u8
+            );
+        }
+
+        // The faster attack holds the level between the mean and the peak square
+        let level = sine_level(EnvelopeKind::AttackRelease, 1000.0, 2000);
+        assert!(
+            level > mean_square && level < 2.0 * mean_square,
+            "{}",
+            level
+        );
+    }
+
+    #[test]
+    fn time_constants_match_the_settings() {
+        let settings = EnvelopeSettings {
+            kind: EnvelopeKind::AttackRelease,
+            attack_ms: 20,
+            release_ms: 150,
+        };
+        let mut envelope = Envelope::new(settings, SAMPLE_FREQ, 0);
+        let level = 100.0 * 100.0;
+
+        // 20 ms at 500 Hz, 1 - 1/e of a step is reached after one time constant
+        let mut output = 0;
+        for _ in 0..10 {
+            output = envelope.update(100);
+        }
+        assert!((output as f32 / level - 0.632).abs() < 0.01, "{}", output);
+        for _ in 0..500 {
+            envelope.update(100);
+        }
+
+        // 150 ms, 1/e is left
+        for _ in 0..75 {
+            output = envelope.update(0);
+        }
+        assert!((output as f32 / level - 0.368).abs() < 0.01, "{}", output);
+    }
+
+    #[test]
+    fn large_values_saturate() {
+        for kind in [
+            EnvelopeKind::BoxAverage,
+            EnvelopeKind::Exponential,
+            EnvelopeKind::AttackRelease,
+        ] {
+            let settings = EnvelopeSettings {
+                kind,
+                attack_ms: 0,
+                release_ms: 0,
+            };
+            let mut envelope = Envelope::new(settings, SAMPLE_FREQ, 0);
+            let mut output = 0;
+            for _ in 0..BOX_AVERAGE_LEN {
+                output = envelope.update(-100_000);
+            }
+            assert_eq!(output, i32::MAX, "kind {}", kind as u8);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use fixed::{
+    types::extra::{LeEqU16, LeEqU32},
+    FixedI16, FixedI32,
+};
+
+/// Sample types that can be stored in a [`MovingAvg`].
+///
+/// Fixed-point samples are stored as their raw integer representation, the
+/// statistics are then in units of the least significant bit.
+pub trait Sample: Copy + Default + PartialOrd {
+    fn to_i64(self) -> i64;
+    fn from_i64(value: i64) -> Self;
+}
+
+impl Sample for i16 {
+    fn to_i64(self) -> i64 {
+        self as i64
+    }
+
+    fn from_i64(value: i64) -> Self {
+        value.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+}
+
+impl Sample for i32 {
+    fn to_i64(self) -> i64 {
+        self as i64
+    }
+
+    fn from_i64(value: i64) -> Self {
+        value.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+}
+
+impl<Frac: LeEqU16> Sample for FixedI16<Frac> {
+    fn to_i64(self) -> i64 {
+        self.to_bits() as i64
+    }
+
+    fn from_i64(value: i64) -> Self {
+        Self::from_bits(i16::from_i64(value))
+    }
+}
+
+impl<Frac: LeEqU32> Sample for FixedI32<Frac> {
+    fn to_i64(self) -> i64 {
+        self.to_bits() as i64
+    }
+
+    fn from_i64(value: i64) -> Self {
+        Self::from_bits(i32::from_i64(value))
+    }
+}
+
+/// Monotonic queue of slot indices used to track the window minimum or maximum.
+struct Wedge<const N: usize> {
+    slots: [usize; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Wedge<N> {
+    fn new() -> Self {
+        Self {
+            slots: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    fn front(&self) -> Option<usize> {
+        (self.len > 0).then(|| self.slots[self.head])
+    }
+
+    fn back(&self) -> Option<usize> {
+        (self.len > 0).then(|| self.slots[(self.head + self.len - 1) % N])
+    }
+
+    fn pop_front(&mut self) {
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+    }
+
+    fn pop_back(&mut self) {
+        self.len -= 1;
+    }
+
+    fn push_back(&mut self, slot: usize) {
+        self.slots[(self.head + self.len) % N] = slot;
+        self.len += 1;
+    }
+}
+
+/// Windowed statistics over the last `N` samples.
+///
+/// Sum, mean, variance, minimum and maximum are kept up to date in
+/// (amortised) constant time per sample.
+pub struct MovingAvg<T: Sample, const N: usize> {
+    avoid_div_by_zero: bool,
+    nbr_readings: usize,
+    sum: i64,
+    /// First reading since the last reset, the squares are taken around it
+    offset: i64,
+    /// Sum of the squared deviations from `offset`, wrapping. The sum over the
+    /// window is exact as long as it fits, whatever was added and removed to
+    /// get there; 24 bit samples need 50 bits per reading at most.
+    sum_sq: i64,
+    next: usize,
+    readings: [T; N],
+    min: Wedge<N>,
+    max: Wedge<N>,
+}
+
+impl<T: Sample, const N: usize> MovingAvg<T, N> {
+    pub fn new(avoid_div_by_zero: bool) -> Self {
+        MovingAvg {
+            avoid_div_by_zero,
+            nbr_readings: 0,
+            sum: 0,
+            offset: 0,
+            sum_sq: 0,
+            next: 0,
+            readings: [T::default(); N],
+            min: Wedge::new(),
+            max: Wedge::new(),
+        }
+    }
+
+    pub fn reading(&mut self, new_reading: T) -> T {
+        let value = new_reading.to_i64();
+        if self.nbr_readings == 0 {
+            self.offset = value;
+        }
+
+        // add each new data point to the sum until the readings array is filled
+        if self.nbr_readings < N {
+            self.nbr_readings += 1;
+        } else {
+            // once the array is filled, subtract the oldest data point
+            let oldest = self.readings[self.next].to_i64();
+            self.sum -= oldest;
+            self.sum_sq = self.sum_sq.wrapping_sub(self.squared_deviation(oldest));
+
+            if self.min.front() == Some(self.next) {
+                self.min.pop_front();
+            }
+            if self.max.front() == Some(self.next) {
+                self.max.pop_front();
+            }
+        }
+
+        self.sum += value;
+        self.sum_sq = self.sum_sq.wrapping_add(self.squared_deviation(value));
+
+        while self
+            .min
+            .back()
+            .is_some_and(|slot| self.readings[slot] >= new_reading)
+        {
+            self.min.pop_back();
+        }
+        self.min.push_back(self.next);
+
+        while self
+            .max
+            .back()
+            .is_some_and(|slot| self.readings[slot] <= new_reading)
+        {
+            self.max.pop_back();
+        }
+        self.max.push_back(self.next);
+
+        self.readings[self.next] = new_reading;
+        self.next = (self.next + 1) % N;
+
+        T::from_i64(Self::rounded_div(self.sum, self.nbr_readings))
+    }
+
+    pub fn get_avg(&self) -> T {
+        if self.nbr_readings > 0 || !self.avoid_div_by_zero {
+            T::from_i64(Self::rounded_div(self.sum, self.nbr_readings))
+        } else {
+            T::default()
+        }
+    }
+
+    pub fn get_avg_n(&self, n_points: usize) -> T {
+        if n_points < 1 || n_points > N || n_points > self.nbr_readings {
+            return T::default();
+        }
+
+        let mut sum: i64 = 0;
+        let mut i = self.next;
+
+        for _ in 0..n_points {
+            i = if i == 0 { N - 1 } else { i - 1 };
+            sum += self.readings[i].to_i64();
+        }
+
+        T::from_i64(Self::rounded_div(sum, n_points))
+    }
+
+    pub fn get_sum(&self) -> i64 {
+        self.sum
+    }
+
+    /// Population variance of the window
+    pub fn get_variance(&self) -> f32 {
+        if self.nbr_readings == 0 {
+            return 0.0;
+        }
+
+        let count = self.nbr_readings as f64;
+        let deviation = (self.sum - self.offset * self.nbr_readings as i64) as f64 / count;
+        let variance = self.sum_sq as f64 / count - deviation * deviation;
+        // Rounding may leave a constant window slightly below zero
+        variance.max(0.0) as f32
+    }
+
+    pub fn get_std_dev(&self) -> f32 {
+        libm::sqrtf(self.get_variance())
+    }
+
+    pub fn get_min(&self) -> Option<T> {
+        self.min.front().map(|slot| self.readings[slot])
+    }
+
+    pub fn get_max(&self) -> Option<T> {
+        self.max.front().map(|slot| self.readings[slot])
+    }
+
+    pub fn reset(&mut self) {
+        self.nbr_readings = 0;
+        self.sum = 0;
+        self.sum_sq = 0;
+        self.next = 0;
+        self.min.clear();
+        self.max.clear();
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.nbr_readings
+    }
+
+    /// Readings currently in the window, in storage order
+    pub fn get_readings(&self) -> &[T] {
+        &self.readings[..self.nbr_readings]
+    }
+
+    fn squared_deviation(&self, value: i64) -> i64 {
+        let deviation = value.wrapping_sub(self.offset);
+        deviation.wrapping_mul(deviation)
+    }
+
+    fn rounded_div(sum: i64, count: usize) -> i64 {
+        (sum + (count as i64 / 2)) / count as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variance_of_the_window() {
+        let mut avg = MovingAvg::<i32, 4>::new(true);
+        assert_eq!(avg.get_variance(), 0.0);
+        for reading in [100, 1, 2, 3, 4, 5] {
+            avg.reading(reading);
+        }
+        // 2, 3, 4 and 5 are left
+        assert_eq!(avg.get_avg(), 4);
+        assert_eq!(avg.get_variance(), 1.25);
+    }
+
+    #[test]
+    fn variance_of_large_samples() {
+        // Full scale 24 bit samples around a large offset
+        let mut avg = MovingAvg::<i32, 256>::new(true);
+        for n in 0..1000 {
+            avg.reading(8_000_000 + if n % 2 == 0 { 300_000 } else { -300_000 });
+        }
+        let std_dev = avg.get_std_dev();
+        assert!((std_dev - 300_000.0).abs() < 1.0, "{}", std_dev);
+        assert_eq!(avg.get_min(), Some(7_700_000));
+        assert_eq!(avg.get_max(), Some(8_300_000));
+    }
+
+    #[test]
+    fn variance_after_reset_and_drift() {
+        let mut avg = MovingAvg::<i32, 8>::new(true);
+        for reading in [-5_000_000, 5_000_000, 7, 1] {
+            avg.reading(reading);
+        }
+        avg.reset();
+        // The window drifts far away from the first reading
+        for n in 0..10_000 {
+            avg.reading(n * 1000 + if n % 2 == 0 { 10 } else { -10 });
+        }
+        assert!((avg.get_variance() - 5_240_100.0).abs() < 1.0);
+
+        let mut constant = MovingAvg::<i16, 16>::new(true);
+        for _ in 0..100 {
+            constant.reading(-1234);
+        }
+        assert_eq!(constant.get_variance(), 0.0);
+    }
+
+    #[test]
+    fn fixed_point_samples() {
+        use fixed::types::I16F16;
+
+        let mut avg = MovingAvg::<I16F16, 4>::new(true);
+        for reading in [10.0, 1.5, 2.5, 3.5, 4.5] {
+            avg.reading(I16F16::from_num(reading));
+        }
+        assert_eq!(avg.get_avg(), I16F16::from_num(3.0));
+        assert_eq!(avg.get_min(), Some(I16F16::from_num(1.5)));
+        assert_eq!(avg.get_max(), Some(I16F16::from_num(4.5)));
+        // Variance in units of the least significant bit squared
+        let lsb = 1.0 / 65536.0;
+        assert!((avg.get_variance() * lsb * lsb - 1.25).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,8 @@
+#[allow(non_snake_case)]
+pub mod EMG;
+pub mod adaptive_notch;
+pub mod artifact;
+pub mod decimator;
+pub mod envelope;
+pub mod mean;
+pub mod spectrum;
@@ -0,0 +1,233 @@
+use core::f32::consts::PI;
+
+use super::EMG::SampleFrequency;
+
+/// Number of samples per analysis window
+pub const FFT_SIZE: usize = 256;
+const HALF: usize = FFT_SIZE / 2;
+
+#[derive(Clone, Copy)]
+pub struct SpectralFeatures {
+    /// Mean power frequency in Hz
+    pub mean_freq: f32,
+    /// Median power frequency in Hz
+    pub median_freq: f32,
+    /// Mean square of the window before windowing
+    pub mean_square: f32,
+}
+
+/// Fixed-size real FFT (Hann windowed) computed as a half-size complex FFT.
+pub struct RealFft {
+    window: [f32; FFT_SIZE],
+    // cos/sin(2*pi*k/FFT_SIZE) for k in 0..FFT_SIZE/2
+    cos: [f32; HALF],
+    sin: [f32; HALF],
+    re: [f32; HALF],
+    im: [f32; HALF],
+    power: [f32; HALF + 1],
+}
+
+impl RealFft {
+    pub fn new() -> Self {
+        let mut fft = Self {
+            window: [0.0; FFT_SIZE],
+            cos: [0.0; HALF],
+            sin: [0.0; HALF],
+            re: [0.0; HALF],
+            im: [0.0; HALF],
+            power: [0.0; HALF + 1],
+        };
+
+        for (n, w) in fft.window.iter_mut().enumerate() {
+            *w = 0.5 - 0.5 * libm::cosf(2.0 * PI * n as f32 / FFT_SIZE as f32);
+        }
+
+        for k in 0..HALF {
+            let angle = 2.0 * PI * k as f32 / FFT_SIZE as f32;
+            fft.cos[k] = libm::cosf(angle);
+            fft.sin[k] = libm::sinf(angle);
+        }
+
+        fft
+    }
+
+    /// Computes the one-sided power spectrum of `samples` and derives its
+    /// mean and median frequencies.
+    pub fn analyze(
+        &mut self,
+        samples: &[f32; FFT_SIZE],
+        sample_freq: SampleFrequency,
+    ) -> SpectralFeatures {
+        let mut mean_square = 0.0;
+        for &sample in samples.iter() {
+            mean_square += sample * sample;
+        }
+        mean_square /= FFT_SIZE as f32;
+
+        // Pack even/odd samples into the real/imaginary parts of a half-size sequence
+        for n in 0..HALF {
+            self.re[n] = samples[2 * n] * self.window[2 * n];
+            self.im[n] = samples[2 * n + 1] * self.window[2 * n + 1];
+        }
+
+        self.complex_fft();
+        self.unpack_power();
+
+        let bin_width = sample_freq as u32 as f32 / FFT_SIZE as f32;
+
+        // DC bin is skipped, the high-pass filter leaves nothing useful there
+        let mut total = 0.0;
+        let mut weighted = 0.0;
+        for k in 1..=HALF {
+            total += self.power[k];
+            weighted += self.power[k] * k as f32 * bin_width;
+        }
+
+        if total <= 0.0 {
+            return SpectralFeatures {
+                mean_freq: 0.0,
+                median_freq: 0.0,
+                mean_square,
+            };
+        }
+
+        let half_total = total / 2.0;
+        let mut cumulative = 0.0;
+        let mut median_freq = HALF as f32 * bin_width;
+        for k in 1..=HALF {
+            let next = cumulative + self.power[k];
+            if next >= half_total {
+                // Interpolate inside the bin where half of the power is reached
+                let fraction = (half_total - cumulative) / self.power[k];
+                median_freq = (k as f32 - 0.5 + fraction) * bin_width;
+                break;
+            }
+            cumulative = next;
+        }
+
+        SpectralFeatures {
+            mean_freq: weighted / total,
+            median_freq,
+            mean_square,
+        }
+    }
+
+    fn complex_fft(&mut self) {
+        let bits = HALF.trailing_zeros();
+        for i in 0..HALF {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if j > i {
+                self.re.swap(i, j);
+                self.im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= HALF {
+            let step = FFT_SIZE / len;
+            for start in (0..HALF).step_by(len) {
+                for j in 0..len / 2 {
+                    let wr = self.cos[j * step];
+                    let wi = -self.sin[j * step];
+                    let a = start + j;
+                    let b = a + len / 2;
+
+                    let tr = self.re[b] * wr - self.im[b] * wi;
+                    let ti = self.re[b] * wi + self.im[b] * wr;
+
+                    self.re[b] = self.re[a] - tr;
+                    self.im[b] = self.im[a] - ti;
+                    self.re[a] += tr;
+                    self.im[a] += ti;
+                }
+            }
+            len *= 2;
+        }
+    }
+
+    fn unpack_power(&mut self) {
+        for k in 0..=HALF {
+            let (zr, zi) = (self.re[k % HALF], self.im[k % HALF]);
+            let (cr, ci) = (self.re[(HALF - k) % HALF], -self.im[(HALF - k) % HALF]);
+
+            // Spectra of the even and odd samples
+            let (er, ei) = ((zr + cr) / 2.0, (zi + ci) / 2.0);
+            let (or, oi) = ((zi - ci) / 2.0, -(zr - cr) / 2.0);
+
+            let (wr, wi) = if k < HALF {
+                (self.cos[k], -self.sin[k])
+            } else {
+                (-1.0, 0.0)
+            };
+
+            let xr = er + wr * or - wi * oi;
+            let xi = ei + wr * oi + wi * or;
+            self.power[k] = xr * xr + xi * xi;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_FREQ: SampleFrequency = SampleFrequency::Freq500Hz;
+    /// Hz per bin at `SAMPLE_FREQ`
+    const BIN_WIDTH: f32 = 500.0 / FFT_SIZE as f32;
+
+    fn sine(bin: f32, amplitude: f32) -> [f32; FFT_SIZE] {
+        core::array::from_fn(|n| {
+            amplitude * libm::sinf(2.0 * PI * bin * n as f32 / FFT_SIZE as f32 + 0.3)
+        })
+    }
+
+    #[test]
+    fn sine_on_a_bin() {
+        let features = RealFft::new().analyze(&sine(20.0, 100.0), SAMPLE_FREQ);
+        let frequency = 20.0 * BIN_WIDTH;
+        assert!(
+            (features.median_freq - frequency).abs() < 0.05,
+            "{}",
+            features.median_freq
+        );
+        assert!(
+            (features.mean_freq - frequency).abs() < 0.05,
+            "{}",
+            features.mean_freq
+        );
+        assert!((features.mean_square - 5000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn sine_between_two_bins() {
+        let features = RealFft::new().analyze(&sine(30.5, 100.0), SAMPLE_FREQ);
+        let frequency = 30.5 * BIN_WIDTH;
+        assert!(
+            (features.median_freq - frequency).abs() < 0.1,
+            "{}",
+            features.median_freq
+        );
+        assert!(
+            (features.mean_freq - frequency).abs() < 0.1,
+            "{}",
+            features.mean_freq
+        );
+    }
+
+    #[test]
+    fn dc_only_leaks_into_the_first_bin() {
+        let features = RealFft::new().analyze(&[250.0; FFT_SIZE], SAMPLE_FREQ);
+        // The DC bin is skipped, which leaves the window's leakage into the first bin
+        assert!((features.median_freq - BIN_WIDTH).abs() < 0.01);
+        assert!((features.mean_freq - BIN_WIDTH).abs() < 0.01);
+        assert_eq!(features.mean_square, 62_500.0);
+    }
+
+    #[test]
+    fn silence_has_no_frequency() {
+        let features = RealFft::new().analyze(&[0.0; FFT_SIZE], SAMPLE_FREQ);
+        assert_eq!(features.median_freq, 0.0);
+        assert_eq!(features.mean_freq, 0.0);
+        assert_eq!(features.mean_square, 0.0);
+    }
+}
@@ -0,0 +1,161 @@
+//! Failed conversions of an EMG channel and the fault state they lead to.
+
+use crate::filters::EMG::SampleFrequency;
+
+/// Consecutive windows within the limit before a faulted channel is trusted again
+const RECOVERY_WINDOWS: u8 = 3;
+/// Default share of failed conversions, in per mille, above which a channel is faulted
+pub const DEFAULT_ERROR_RATE_LIMIT: u16 = 50;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum HealthChange {
+    // Error rate of the last window in per mille, above the limit
+    Fault { error_rate: u16 },
+    Recovered,
+}
+
+/// Hides failed conversions of one channel and watches their rate.
+pub struct ChannelHealth {
+    /// Samples per error rate evaluation (1 s)
+    window_len: u32,
+    last_good: i32,
+    errors: u32,
+    window_samples: u32,
+    window_errors: u32,
+    faulted: bool,
+    clean_windows: u8,
+}
+
+impl ChannelHealth {
+    /// `initial` stands in for failed conversions until a good sample arrives.
+    pub fn new(initial: i32, sample_freq: SampleFrequency) -> Self {
+        Self {
+            window_len: sample_freq as u32,
+            last_good: initial,
+            errors: 0,
+            window_samples: 0,
+            window_errors: 0,
+            faulted: false,
+            clean_windows: 0,
+        }
+    }
+
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Returns the sample to process, the last good one in place of a failed
+    /// conversion, and any change of the channel's fault state.
+    pub fn update(
+        &mut self,
+        sample: i32,
+        failed: bool,
+        limit_permille: u16,
+    ) -> (i32, Option<HealthChange>) {
+        let sample = if failed {
+            self.errors = self.errors.wrapping_add(1);
+            self.window_errors += 1;
+            self.last_good
+        } else {
+            self.last_good = sample;
+            sample
+        };
+
+        self.window_samples += 1;
+        if self.window_samples < self.window_len {
+            return (sample, None);
+        }
+
+        let error_rate = (self.window_errors * 1000 / self.window_samples) as u16;
+        self.window_samples = 0;
+        self.window_errors = 0;
+
+        (sample, self.evaluate(error_rate, limit_permille))
+    }
+
+    fn evaluate(&mut self, error_rate: u16, limit_permille: u16) -> Option<HealthChange> {
+        if error_rate > limit_permille {
+            self.clean_windows = 0;
+            if self.faulted {
+                return None;
+            }
+            self.faulted = true;
+            return Some(HealthChange::Fault { error_rate });
+        }
+
+        if !self.faulted {
+            return None;
+        }
+
+        self.clean_windows += 1;
+        if self.clean_windows < RECOVERY_WINDOWS {
+            return None;
+        }
+
+        self.faulted = false;
+        self.clean_windows = 0;
+        Some(HealthChange::Recovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: u16 = 50;
+    const WINDOW: u32 = 500;
+
+    /// Runs one window with `errors` failed conversions, spread evenly.
+    /// `errors` must divide the window.
+    fn window(health: &mut ChannelHealth, errors: u32) -> Option<HealthChange> {
+        let mut change = None;
+        for n in 0..WINDOW {
+            let failed = errors > 0 && n % (WINDOW / errors) == 0;
+            let (_, result) = health.update(100, failed, LIMIT);
+            if result.is_some() {
+                assert_eq!(n, WINDOW - 1);
+                change = result;
+            }
+        }
+        change
+    }
+
+    #[test]
+    fn failed_conversions_are_replaced_by_the_last_good_one() {
+        let mut health = ChannelHealth::new(2048, SampleFrequency::Freq500Hz);
+        assert_eq!(health.update(0, true, LIMIT), (2048, None));
+        assert_eq!(health.update(1000, false, LIMIT), (1000, None));
+        assert_eq!(health.update(0, true, LIMIT), (1000, None));
+        assert_eq!(health.errors(), 2);
+    }
+
+    #[test]
+    fn fault_above_the_limit_only() {
+        let mut health = ChannelHealth::new(0, SampleFrequency::Freq500Hz);
+        // 25 of 500 is exactly the limit of 50 per mille
+        assert_eq!(window(&mut health, 25), None);
+        assert_eq!(
+            window(&mut health, 50),
+            Some(HealthChange::Fault { error_rate: 100 })
+        );
+        // Reported once while the fault lasts
+        assert_eq!(window(&mut health, 50), None);
+        assert_eq!(health.errors(), 125);
+    }
+
+    #[test]
+    fn recovery_needs_consecutive_clean_windows() {
+        let mut health = ChannelHealth::new(0, SampleFrequency::Freq500Hz);
+        assert!(window(&mut health, 100).is_some());
+
+        assert_eq!(window(&mut health, 0), None);
+        assert_eq!(window(&mut health, 0), None);
+        // A bad window starts the count again
+        assert_eq!(window(&mut health, 100), None);
+        assert_eq!(window(&mut health, 0), None);
+        assert_eq!(window(&mut health, 0), None);
+        assert_eq!(window(&mut health, 0), Some(HealthChange::Recovered));
+        assert_eq!(window(&mut health, 0), None);
+    }
+}
@@ -0,0 +1,19 @@
+//! Signal processing, protocol encodings and the program state machine.
+//!
+//! Nothing here touches the RP2040, so the firmware's logic can be tested on
+//! the host:
+//!
+//! `cargo test --manifest-path logic/Cargo.toml --target x86_64-unknown-linux-gnu`
+#![cfg_attr(not(test), no_std)]
+// Most types have a `const fn new` for statics rather than `Default`
+#![allow(clippy::new_without_default, clippy::derivable_impls)]
+
+pub mod ads129x;
+pub mod detector;
+pub mod device_info;
+pub mod filters;
+pub mod health;
+pub mod limits;
+pub mod machine;
+pub mod record;
+pub mod ring;
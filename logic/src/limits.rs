@@ -0,0 +1,265 @@
+//! Joint position encoding and the limits applied to `SetPosition`.
+//!
+//! `SetPosition` gives each joint a range, its minimum and maximum position,
+//! that the hand moves the joint into. `ResponsePosition` reports the joints
+//! in the same layout.
+
+use defmt::Format;
+
+pub const JOINTS: usize = 6;
+/// Minimum and maximum of each joint, little endian u16
+pub const POSITION_BYTES: usize = JOINTS * 4;
+/// Soft minimum and maximum of each joint followed by the velocity limit
+pub const LIMITS_BYTES: usize = JOINTS * 4 + 2;
+
+/// Counts per second a joint may be commanded to move by default
+pub const DEFAULT_MAX_VELOCITY: u16 = 4000;
+
+/// Positions a joint is commanded to or reported in, `min <= max`
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct JointRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+/// Rejects payloads where a joint's minimum lies above its maximum
+pub fn parse_positions(payload: &[u8]) -> Option<[JointRange; JOINTS]> {
+    if payload.len() != POSITION_BYTES {
+        return None;
+    }
+    let value = |offset: usize| u16::from_le_bytes([payload[offset], payload[offset + 1]]);
+    let ranges: [JointRange; JOINTS] = core::array::from_fn(|joint| JointRange {
+        min: value(joint * 4),
+        max: value(joint * 4 + 2),
+    });
+    ranges
+        .iter()
+        .all(|range| range.min <= range.max)
+        .then_some(ranges)
+}
+
+pub fn encode_positions(ranges: &[JointRange; JOINTS]) -> [u8; POSITION_BYTES] {
+    let mut bytes = [0; POSITION_BYTES];
+    for (chunk, range) in bytes.chunks_exact_mut(4).zip(ranges.iter()) {
+        chunk[..2].copy_from_slice(&range.min.to_le_bytes());
+        chunk[2..].copy_from_slice(&range.max.to_le_bytes());
+    }
+    bytes
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct JointLimits {
+    pub min: [u16; JOINTS],
+    pub max: [u16; JOINTS],
+    /// Fastest commanded movement of any joint, in counts per second
+    pub max_velocity: u16,
+}
+
+impl JointLimits {
+    pub const fn new() -> Self {
+        Self {
+            min: [0; JOINTS],
+            max: [u16::MAX; JOINTS],
+            max_velocity: DEFAULT_MAX_VELOCITY,
+        }
+    }
+
+    /// Minimum and maximum per joint, then the velocity limit, little endian
+    pub fn to_bytes(&self) -> [u8; LIMITS_BYTES] {
+        let mut bytes = [0; LIMITS_BYTES];
+        for (joint, chunk) in bytes[..JOINTS * 4].chunks_exact_mut(4).enumerate() {
+            chunk[..2].copy_from_slice(&self.min[joint].to_le_bytes());
+            chunk[2..].copy_from_slice(&self.max[joint].to_le_bytes());
+        }
+        bytes[JOINTS * 4..].copy_from_slice(&self.max_velocity.to_le_bytes());
+        bytes
+    }
+
+    /// Rejects limits where a minimum lies above its maximum, and a velocity
+    /// limit of zero, which would freeze every joint
+    pub fn from_bytes(bytes: &[u8; LIMITS_BYTES]) -> Option<Self> {
+        let value = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let limits = Self {
+            min: core::array::from_fn(|joint| value(joint * 4)),
+            max: core::array::from_fn(|joint| value(joint * 4 + 2)),
+            max_velocity: value(JOINTS * 4),
+        };
+        let ordered = limits
+            .min
+            .iter()
+            .zip(limits.max.iter())
+            .all(|(min, max)| min <= max);
+        (ordered && limits.max_velocity > 0).then_some(limits)
+    }
+
+    /// Ranges with neither end further from the joints' known ranges, if
+    /// any, than the velocity limit allows in `elapsed_ms`, then moved inside
+    /// the soft limits. The soft limits win when a joint is outside them, so
+    /// a joint left there by tightened limits is brought back at once.
+    pub fn apply(
+        &self,
+        targets: &[JointRange; JOINTS],
+        reference: Option<&[JointRange; JOINTS]>,
+        elapsed_ms: u32,
+    ) -> [JointRange; JOINTS] {
+        let step = (self.max_velocity as u32 * elapsed_ms / 1000).min(u16::MAX as u32) as u16;
+        let towards = |target: u16, from: u16| {
+            target.clamp(from.saturating_sub(step), from.saturating_add(step))
+        };
+        // Clamping both ends into the same or into ordered intervals keeps min <= max
+        core::array::from_fn(|joint| {
+            let (low, high) = (self.min[joint], self.max[joint]);
+            let mut range = targets[joint];
+            if let Some(reference) = reference {
+                range.min = towards(range.min, reference[joint].min);
+                range.max = towards(range.max, reference[joint].max);
+            }
+            JointRange {
+                min: range.min.clamp(low, high),
+                max: range.max.clamp(low, high),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn range(min: u16, max: u16) -> JointRange {
+        JointRange { min, max }
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let ranges =
+            core::array::from_fn(|joint| range(joint as u16 * 100, joint as u16 * 100 + 50));
+        let bytes = encode_positions(&ranges);
+        assert_eq!(&bytes[..4], &[0, 0, 50, 0]);
+        assert_eq!(parse_positions(&bytes), Some(ranges));
+    }
+
+    #[test]
+    fn inverted_or_short_positions_are_rejected() {
+        let mut ranges = [range(10, 20); JOINTS];
+        ranges[3] = range(30, 20);
+        assert_eq!(parse_positions(&encode_positions(&ranges)), None);
+        assert_eq!(parse_positions(&[0; POSITION_BYTES - 1]), None);
+    }
+
+    #[test]
+    fn limits_round_trip_and_validation() {
+        let mut limits = JointLimits::new();
+        limits.min[2] = 100;
+        limits.max[2] = 900;
+        limits.max_velocity = 1234;
+        assert_eq!(JointLimits::from_bytes(&limits.to_bytes()), Some(limits));
+
+        let mut zero_velocity = limits;
+        zero_velocity.max_velocity = 0;
+        assert_eq!(JointLimits::from_bytes(&zero_velocity.to_bytes()), None);
+
+        let mut inverted = limits;
+        inverted.min[5] = 1000;
+        inverted.max[5] = 999;
+        assert_eq!(JointLimits::from_bytes(&inverted.to_bytes()), None);
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_soft_limits() {
+        let mut limits = JointLimits::new();
+        limits.min = [1000; JOINTS];
+        limits.max = [3000; JOINTS];
+
+        let targets = [
+            range(500, 2000),
+            range(2500, 4000),
+            range(0, 100),
+            range(3500, 3600),
+            range(1500, 1600),
+            range(0, u16::MAX),
+        ];
+        let limited = limits.apply(&targets, None, 0);
+        assert_eq!(
+            limited,
+            [
+                range(1000, 2000),
+                range(2500, 3000),
+                range(1000, 1000),
+                range(3000, 3000),
+                range(1500, 1600),
+                range(1000, 3000)
+            ]
+        );
+    }
+
+    #[test]
+    fn velocity_limit_follows_the_reference() {
+        let limits = JointLimits::new();
+        let reference = [range(1000, 1200); JOINTS];
+        let targets = [
+            range(3000, 3500),
+            range(0, 100),
+            range(1050, 1150),
+            range(0, u16::MAX),
+            range(1000, 1200),
+            range(900, 5000),
+        ];
+
+        // 4000 counts per second over 50 ms allows 200 counts
+        let limited = limits.apply(&targets, Some(&reference), 50);
+        assert_eq!(
+            limited,
+            [
+                range(1200, 1400),
+                range(800, 1000),
+                range(1050, 1150),
+                range(800, 1400),
+                range(1000, 1200),
+                range(900, 1400)
+            ]
+        );
+        for range in limited {
+            assert!(range.min <= range.max);
+        }
+    }
+
+    #[test]
+    fn soft_limits_win_over_the_velocity_limit() {
+        let mut limits = JointLimits::new();
+        limits.min = [1000; JOINTS];
+        limits.max = [3000; JOINTS];
+        // The joints were left outside limits that have since been tightened
+        let reference = [
+            range(5000, 5000),
+            range(200, 400),
+            range(500, 5000),
+            range(2000, 2500),
+            range(5000, 5000),
+            range(200, 400),
+        ];
+        let targets = [
+            range(5000, 5000),
+            range(200, 400),
+            range(500, 5000),
+            range(2000, 2500),
+            range(2000, 2500),
+            range(2000, 2500),
+        ];
+
+        let limited = limits.apply(&targets, Some(&reference), 50);
+        assert_eq!(
+            limited,
+            [
+                range(3000, 3000),
+                range(1000, 1000),
+                range(1000, 3000),
+                range(2000, 2500),
+                range(3000, 3000),
+                range(1000, 1000)
+            ]
+        );
+    }
+}
@@ -0,0 +1,500 @@
+//! Program stage transitions. The orchestrator feeds the table triggers and
+//! carries out the actions of the stages left and entered.
+
+use defmt::Format;
+
+#[derive(Copy, Clone, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum ProgramStage {
+    /// Tasks are starting, nothing has been measured yet
+    Boot,
+    /// Waiting for the user, no motion
+    Idle,
+    Calibration,
+    Operation,
+    /// Operation locked by the user, EMG is still processed but the hand holds
+    /// its position
+    Paused,
+    /// A sensor or the hand link failed, the hand is incompatible or the
+    /// emergency stop is latched, no motion until it recovers
+    Error,
+    /// Final stage, nothing leaves it
+    Shutdown,
+}
+
+/// Stage changes asked for by the user over BLE or UART
+#[derive(Copy, Clone, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum UserRequest {
+    Stop = 0,
+    Calibrate = 1,
+    Pause = 2,
+    Resume = 3,
+    Shutdown = 4,
+}
+
+impl TryFrom<u8> for UserRequest {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Stop),
+            1 => Ok(Self::Calibrate),
+            2 => Ok(Self::Pause),
+            3 => Ok(Self::Resume),
+            4 => Ok(Self::Shutdown),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Trigger {
+    BootComplete,
+    CalibrationFinished,
+    User(UserRequest),
+    SensorFault,
+    CommsLost,
+    LowBattery,
+    EmergencyStop,
+    /// The hand controller speaks another protocol major version
+    Incompatible,
+    /// A sensor fault, the hand link or the battery recovered, or the
+    /// emergency stop was cleared or a compatible hand answered, see [`Guards`]
+    Recovered,
+}
+
+/// Conditions the guarded transitions check, kept up to date by the orchestrator
+#[derive(Copy, Clone, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Guards {
+    pub sensors_ok: bool,
+    pub comms_ok: bool,
+    pub battery_ok: bool,
+    /// No emergency stop is latched
+    pub released: bool,
+    /// The hand controller answered and its protocol version matches
+    pub compatible: bool,
+}
+
+impl Guards {
+    pub const fn new() -> Self {
+        Self {
+            sensors_ok: true,
+            comms_ok: true,
+            battery_ok: true,
+            released: true,
+            compatible: false,
+        }
+    }
+
+    /// Sensors and battery allow measuring
+    fn can_calibrate(&self) -> bool {
+        self.sensors_ok && self.battery_ok
+    }
+
+    /// Everything needed to drive the hand
+    fn can_operate(&self) -> bool {
+        self.can_calibrate() && self.comms_ok && self.released && self.compatible
+    }
+}
+
+/// Feedback telling the user that the hand was locked or unlocked
+#[derive(Copy, Clone, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Confirmation {
+    Locked,
+    Unlocked,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Action {
+    StartCalibration,
+    StopMotion,
+    Confirm(Confirmation),
+}
+
+/// Stage reached from `stage` on `trigger`, `None` when the trigger does not
+/// apply or a guard blocks it.
+pub fn next_stage(stage: ProgramStage, trigger: Trigger, guards: &Guards) -> Option<ProgramStage> {
+    use ProgramStage::*;
+
+    let next = match (stage, trigger) {
+        (Shutdown, _) => return None,
+        (_, Trigger::User(UserRequest::Shutdown)) => Shutdown,
+
+        // Faults win over every request
+        (Boot | Idle | Calibration | Operation | Paused, Trigger::SensorFault) => Error,
+        (Boot | Idle | Calibration | Operation | Paused, Trigger::EmergencyStop) => Error,
+        (Calibration | Operation | Paused, Trigger::CommsLost | Trigger::Incompatible) => Error,
+        (Calibration | Operation | Paused, Trigger::LowBattery) => Idle,
+
+        (Boot, Trigger::BootComplete) if guards.can_calibrate() => Calibration,
+        (Boot, Trigger::BootComplete) => Idle,
+
+        (Idle, Trigger::User(UserRequest::Calibrate)) if guards.can_calibrate() => Calibration,
+
+        (Calibration, Trigger::CalibrationFinished) if guards.can_operate() => Operation,
+        (Calibration, Trigger::CalibrationFinished) => Idle,
+
+        (Operation, Trigger::User(UserRequest::Pause)) => Paused,
+        (Paused, Trigger::User(UserRequest::Resume)) if guards.can_operate() => Operation,
+        (Operation | Paused, Trigger::User(UserRequest::Calibrate)) if guards.can_calibrate() => {
+            Calibration
+        }
+
+        (Calibration | Operation | Paused | Error, Trigger::User(UserRequest::Stop)) => Idle,
+
+        // Signal levels may have changed, calibrate again before operating
+        (Error, Trigger::Recovered) if guards.can_operate() => Calibration,
+        // Calibration found something missing, such as a hand that had not
+        // identified yet or a low battery
+        (Idle, Trigger::Recovered) if guards.can_operate() => Calibration,
+
+        _ => return None,
+    };
+
+    Some(next)
+}
+
+/// Actions when `stage` is left
+pub fn exit_actions(stage: ProgramStage) -> &'static [Action] {
+    match stage {
+        ProgramStage::Operation => &[Action::StopMotion],
+        ProgramStage::Paused => &[Action::Confirm(Confirmation::Unlocked)],
+        _ => &[],
+    }
+}
+
+/// Actions when `stage` is entered
+pub fn entry_actions(stage: ProgramStage) -> &'static [Action] {
+    match stage {
+        ProgramStage::Calibration => &[Action::StartCalibration],
+        ProgramStage::Paused => &[Action::Confirm(Confirmation::Locked)],
+        _ => &[],
+    }
+}
+
+/// Numbers calibration runs. The completion of a run may still be on its way
+/// when the machine leaves Calibration and enters it again, only the latest
+/// run may finish the stage.
+pub struct CalibrationRuns {
+    latest: u32,
+}
+
+impl CalibrationRuns {
+    pub const fn new() -> Self {
+        Self { latest: 0 }
+    }
+
+    /// Numbers the run started on entering Calibration
+    pub fn start(&mut self) -> u32 {
+        self.latest = self.latest.wrapping_add(1);
+        self.latest
+    }
+
+    /// Trigger for the completion of `run`, `None` when it is stale
+    pub fn finished(&self, run: u32) -> Option<Trigger> {
+        (run == self.latest).then_some(Trigger::CalibrationFinished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ProgramStage::*;
+
+    const STAGES: [ProgramStage; 7] = [Boot, Idle, Calibration, Operation, Paused, Error, Shutdown];
+
+    const TRIGGERS: [Trigger; 13] = [
+        Trigger::BootComplete,
+        Trigger::CalibrationFinished,
+        Trigger::User(UserRequest::Stop),
+        Trigger::User(UserRequest::Calibrate),
+        Trigger::User(UserRequest::Pause),
+        Trigger::User(UserRequest::Resume),
+        Trigger::User(UserRequest::Shutdown),
+        Trigger::SensorFault,
+        Trigger::CommsLost,
+        Trigger::LowBattery,
+        Trigger::EmergencyStop,
+        Trigger::Incompatible,
+        Trigger::Recovered,
+    ];
+
+    /// Every combination of the five guard conditions
+    fn all_guards() -> impl Iterator<Item = Guards> {
+        (0..32u8).map(|bits| Guards {
+            sensors_ok: bits & 1 != 0,
+            comms_ok: bits & 2 != 0,
+            battery_ok: bits & 4 != 0,
+            released: bits & 8 != 0,
+            compatible: bits & 16 != 0,
+        })
+    }
+
+    /// Calls `check` with every (stage, trigger, guards) combination and the
+    /// resulting stage
+    fn for_each_transition(
+        mut check: impl FnMut(ProgramStage, Trigger, &Guards, Option<ProgramStage>),
+    ) {
+        for stage in STAGES {
+            for trigger in TRIGGERS {
+                for guards in all_guards() {
+                    check(stage, trigger, &guards, next_stage(stage, trigger, &guards));
+                }
+            }
+        }
+    }
+
+    fn active(stage: ProgramStage) -> bool {
+        matches!(stage, Boot | Idle | Calibration | Operation | Paused)
+    }
+
+    #[test]
+    fn shutdown_is_final() {
+        for_each_transition(|stage, trigger, _, next| {
+            if stage == Shutdown {
+                assert_eq!(next, None, "{:?} left Shutdown", trigger);
+            }
+        });
+    }
+
+    #[test]
+    fn shutdown_request_is_always_honoured() {
+        for_each_transition(|stage, trigger, _, next| {
+            if stage != Shutdown && trigger == Trigger::User(UserRequest::Shutdown) {
+                assert_eq!(next, Some(Shutdown), "{:?} ignored shutdown", stage);
+            }
+        });
+    }
+
+    #[test]
+    fn faults_stop_every_active_stage() {
+        for_each_transition(|stage, trigger, guards, next| {
+            if !active(stage) {
+                return;
+            }
+            match trigger {
+                Trigger::SensorFault | Trigger::EmergencyStop => {
+                    assert_eq!(
+                        next,
+                        Some(Error),
+                        "{:?} on {:?} {:?}",
+                        stage,
+                        trigger,
+                        guards
+                    )
+                }
+                Trigger::CommsLost | Trigger::Incompatible if stage != Boot && stage != Idle => {
+                    assert_eq!(
+                        next,
+                        Some(Error),
+                        "{:?} on {:?} {:?}",
+                        stage,
+                        trigger,
+                        guards
+                    )
+                }
+                _ => {}
+            }
+        });
+    }
+
+    #[test]
+    fn operation_needs_every_guard() {
+        for_each_transition(|stage, trigger, guards, next| {
+            if next == Some(Operation) {
+                assert!(
+                    guards.can_operate(),
+                    "{:?} on {:?} operates with {:?}",
+                    stage,
+                    trigger,
+                    guards
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn calibration_needs_sensors_and_battery() {
+        for_each_transition(|stage, trigger, guards, next| {
+            if next == Some(Calibration) {
+                assert!(
+                    guards.can_calibrate(),
+                    "{:?} on {:?} calibrates with {:?}",
+                    stage,
+                    trigger,
+                    guards
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn error_is_left_by_stop_recovery_or_shutdown() {
+        for_each_transition(|stage, trigger, guards, next| {
+            if stage != Error {
+                return;
+            }
+            let expected = match trigger {
+                Trigger::User(UserRequest::Stop) => Some(Idle),
+                Trigger::User(UserRequest::Shutdown) => Some(Shutdown),
+                Trigger::Recovered if guards.can_operate() => Some(Calibration),
+                _ => None,
+            };
+            assert_eq!(next, expected, "Error on {:?} with {:?}", trigger, guards);
+        });
+    }
+
+    #[test]
+    fn no_stage_is_reentered_or_returns_to_boot() {
+        for_each_transition(|stage, trigger, _, next| {
+            assert_ne!(next, Some(stage), "{:?} on {:?}", stage, trigger);
+            assert_ne!(next, Some(Boot), "{:?} on {:?}", stage, trigger);
+        });
+    }
+
+    #[test]
+    fn leaving_operation_stops_motion() {
+        for_each_transition(|stage, _, _, next| {
+            if stage == Operation && next.is_some() {
+                assert!(exit_actions(stage).contains(&Action::StopMotion));
+            }
+        });
+    }
+
+    #[test]
+    fn guarded_rows() {
+        let all_ok = Guards {
+            sensors_ok: true,
+            comms_ok: true,
+            battery_ok: true,
+            released: true,
+            compatible: true,
+        };
+        let latched = Guards {
+            released: false,
+            ..all_ok
+        };
+        let no_sensors = Guards {
+            sensors_ok: false,
+            ..all_ok
+        };
+
+        assert_eq!(
+            next_stage(Boot, Trigger::BootComplete, &all_ok),
+            Some(Calibration)
+        );
+        assert_eq!(
+            next_stage(Boot, Trigger::BootComplete, &no_sensors),
+            Some(Idle)
+        );
+        assert_eq!(
+            next_stage(Calibration, Trigger::CalibrationFinished, &all_ok),
+            Some(Operation)
+        );
+        assert_eq!(
+            next_stage(Calibration, Trigger::CalibrationFinished, &latched),
+            Some(Idle)
+        );
+        assert_eq!(
+            next_stage(Paused, Trigger::User(UserRequest::Resume), &latched),
+            None
+        );
+        assert_eq!(next_stage(Error, Trigger::Recovered, &latched), None);
+        assert_eq!(
+            next_stage(Error, Trigger::Recovered, &all_ok),
+            Some(Calibration)
+        );
+    }
+
+    #[test]
+    fn operation_waits_for_the_hand_to_identify() {
+        let unidentified = Guards::new();
+        assert_eq!(
+            next_stage(Boot, Trigger::BootComplete, &unidentified),
+            Some(Calibration)
+        );
+        assert_eq!(
+            next_stage(Calibration, Trigger::CalibrationFinished, &unidentified),
+            Some(Idle)
+        );
+        let identified = Guards {
+            compatible: true,
+            ..unidentified
+        };
+        assert_eq!(
+            next_stage(Calibration, Trigger::CalibrationFinished, &identified),
+            Some(Operation)
+        );
+    }
+
+    #[test]
+    fn late_identification_calibrates_again() {
+        let mut guards = Guards::new();
+        let mut runs = CalibrationRuns::new();
+
+        let stage = step(Boot, Trigger::BootComplete, &guards, &mut runs);
+        let stage = step(stage, Trigger::CalibrationFinished, &guards, &mut runs);
+        assert_eq!(stage, Idle);
+
+        // A compatible hand answers, the orchestrator reports it as recovered
+        guards.compatible = true;
+        let stage = step(stage, Trigger::Recovered, &guards, &mut runs);
+        assert_eq!(stage, Calibration);
+        let trigger = runs.finished(2).unwrap();
+        assert_eq!(step(stage, trigger, &guards, &mut runs), Operation);
+    }
+
+    #[test]
+    fn idle_recovers_only_when_everything_is_back() {
+        for_each_transition(|stage, trigger, guards, next| {
+            if stage == Idle && trigger == Trigger::Recovered {
+                let expected = guards.can_operate().then_some(Calibration);
+                assert_eq!(next, expected, "Idle on Recovered with {:?}", guards);
+            }
+        });
+    }
+
+    /// Moves like the orchestrator does, starting a run on entering Calibration
+    fn step(
+        stage: ProgramStage,
+        trigger: Trigger,
+        guards: &Guards,
+        runs: &mut CalibrationRuns,
+    ) -> ProgramStage {
+        let Some(next) = next_stage(stage, trigger, guards) else {
+            return stage;
+        };
+        if entry_actions(next).contains(&Action::StartCalibration) {
+            runs.start();
+        }
+        next
+    }
+
+    #[test]
+    fn stale_calibration_does_not_finish_a_new_one() {
+        let guards = Guards {
+            compatible: true,
+            ..Guards::new()
+        };
+        let mut runs = CalibrationRuns::new();
+
+        let stage = step(Boot, Trigger::BootComplete, &guards, &mut runs);
+        assert_eq!(stage, Calibration);
+        let abandoned = 1;
+        let stage = step(stage, Trigger::SensorFault, &guards, &mut runs);
+        assert_eq!(stage, Error);
+        let stage = step(stage, Trigger::Recovered, &guards, &mut runs);
+        assert_eq!(stage, Calibration);
+
+        // The first run completes while the second still measures
+        assert_eq!(runs.finished(abandoned), None);
+        let current = abandoned + 1;
+        let trigger = runs.finished(current).unwrap();
+        assert_eq!(step(stage, trigger, &guards, &mut runs), Operation);
+    }
+}
@@ -0,0 +1,302 @@
+//! Crash record layout.
+//!
+//! Little endian, fixed size:
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic                                        |
+//! | 4      | 1    | layout version                               |
+//! | 5      | 1    | core that panicked                           |
+//! | 6      | 1    | file length                                  |
+//! | 7      | 1    | message length                               |
+//! | 8      | 4    | uptime in ms                                 |
+//! | 12     | 4    | line                                         |
+//! | 16     | 12   | SP, LR and PC in the panic handler           |
+//! | 28     | 32   | top of the stack, `STACK_WORDS` words        |
+//! | 60     | 20   | end of the source file path                  |
+//! | 80     | 46   | start of the panic message                   |
+//! | 126    | 2    | CRC-16/CCITT-FALSE of the preceding bytes    |
+
+use core::fmt;
+
+use defmt::Format;
+
+pub const RECORD_LEN: usize = 128;
+pub const STACK_WORDS: usize = 8;
+pub const FILE_LEN: usize = 20;
+pub const MESSAGE_LEN: usize = 46;
+
+const MAGIC: u32 = 0x4853_5243; // "CRSH"
+const VERSION: u8 = 1;
+
+const STACK_OFFSET: usize = 28;
+const FILE_OFFSET: usize = STACK_OFFSET + STACK_WORDS * 4;
+const MESSAGE_OFFSET: usize = FILE_OFFSET + FILE_LEN;
+const CRC_OFFSET: usize = MESSAGE_OFFSET + MESSAGE_LEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum DecodeError {
+    /// Erased flash or cleared RAM, no record was ever written
+    Empty,
+    BadMagic,
+    UnsupportedVersion,
+    BadLength,
+    BadCrc,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct CrashRecord {
+    pub core: u8,
+    pub uptime_ms: u32,
+    pub line: u32,
+    pub sp: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub stack: [u32; STACK_WORDS],
+    file: [u8; FILE_LEN],
+    file_len: u8,
+    message: [u8; MESSAGE_LEN],
+    message_len: u8,
+}
+
+impl CrashRecord {
+    pub const fn new() -> Self {
+        Self {
+            core: 0,
+            uptime_ms: 0,
+            line: 0,
+            sp: 0,
+            lr: 0,
+            pc: 0,
+            stack: [0; STACK_WORDS],
+            file: [0; FILE_LEN],
+            file_len: 0,
+            message: [0; MESSAGE_LEN],
+            message_len: 0,
+        }
+    }
+
+    /// Keeps the end of `path`, the file name is more telling than the crate
+    /// directory it lives in
+    pub fn set_file(&mut self, path: &str) {
+        let mut start = path.len().saturating_sub(FILE_LEN);
+        while !path.is_char_boundary(start) {
+            start += 1;
+        }
+        let tail = &path.as_bytes()[start..];
+        self.file[..tail.len()].copy_from_slice(tail);
+        self.file_len = tail.len() as u8;
+    }
+
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or("")
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("")
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0u8; RECORD_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4] = VERSION;
+        bytes[5] = self.core;
+        bytes[6] = self.file_len;
+        bytes[7] = self.message_len;
+        bytes[8..12].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.line.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.sp.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.lr.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.pc.to_le_bytes());
+        for (chunk, word) in bytes[STACK_OFFSET..FILE_OFFSET]
+            .chunks_exact_mut(4)
+            .zip(self.stack.iter())
+        {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes[FILE_OFFSET..MESSAGE_OFFSET].copy_from_slice(&self.file);
+        bytes[MESSAGE_OFFSET..CRC_OFFSET].copy_from_slice(&self.message);
+
+        let crc = crc16::State::<crc16::CCITT_FALSE>::calculate(&bytes[..CRC_OFFSET]);
+        bytes[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; RECORD_LEN]) -> Result<Self, DecodeError> {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+
+        match word(0) {
+            MAGIC => {}
+            0 | u32::MAX => return Err(DecodeError::Empty),
+            _ => return Err(DecodeError::BadMagic),
+        }
+        if bytes[4] != VERSION {
+            return Err(DecodeError::UnsupportedVersion);
+        }
+        let crc = u16::from_le_bytes([bytes[CRC_OFFSET], bytes[CRC_OFFSET + 1]]);
+        if crc != crc16::State::<crc16::CCITT_FALSE>::calculate(&bytes[..CRC_OFFSET]) {
+            return Err(DecodeError::BadCrc);
+        }
+
+        let file_len = bytes[6];
+        let message_len = bytes[7];
+        if file_len as usize > FILE_LEN || message_len as usize > MESSAGE_LEN {
+            return Err(DecodeError::BadLength);
+        }
+
+        let mut record = Self::new();
+        record.core = bytes[5];
+        record.file_len = file_len;
+        record.message_len = message_len;
+        record.uptime_ms = word(8);
+        record.line = word(12);
+        record.sp = word(16);
+        record.lr = word(20);
+        record.pc = word(24);
+        for (index, word_value) in record.stack.iter_mut().enumerate() {
+            *word_value = word(STACK_OFFSET + index * 4);
+        }
+        record
+            .file
+            .copy_from_slice(&bytes[FILE_OFFSET..MESSAGE_OFFSET]);
+        record
+            .message
+            .copy_from_slice(&bytes[MESSAGE_OFFSET..CRC_OFFSET]);
+        Ok(record)
+    }
+}
+
+/// Formats the panic message into the record, dropping whatever does not fit
+impl fmt::Write for CrashRecord {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let used = self.message_len as usize;
+        let mut len = s.len().min(MESSAGE_LEN - used);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.message[used..used + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.message_len += len as u8;
+        Ok(())
+    }
+}
+
+impl Format for CrashRecord {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "core {} at {} ms, {}:{}: {}, sp {=u32:#x} lr {=u32:#x} pc {=u32:#x}",
+            self.core,
+            self.uptime_ms,
+            self.file(),
+            self.line,
+            self.message(),
+            self.sp,
+            self.lr,
+            self.pc
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+
+    fn sample() -> CrashRecord {
+        let mut record = CrashRecord::new();
+        record.core = 1;
+        record.uptime_ms = 123_456;
+        record.line = 42;
+        record.sp = 0x2004_1F00;
+        record.lr = 0x1000_2345;
+        record.pc = 0x1000_6789;
+        for (index, word) in record.stack.iter_mut().enumerate() {
+            *word = 0xDEAD_0000 | index as u32;
+        }
+        record.set_file("src/emg.rs");
+        write!(record, "index out of bounds: {}", 7).unwrap();
+        record
+    }
+
+    #[test]
+    fn round_trip() {
+        let record = sample();
+        let decoded = CrashRecord::decode(&record.encode()).unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.file(), "src/emg.rs");
+        assert_eq!(decoded.message(), "index out of bounds: 7");
+    }
+
+    #[test]
+    fn corrupted_record_fails_the_crc() {
+        let mut bytes = sample().encode();
+        bytes[MESSAGE_OFFSET] ^= 0x01;
+        assert_eq!(CrashRecord::decode(&bytes), Err(DecodeError::BadCrc));
+
+        let mut bytes = sample().encode();
+        bytes[CRC_OFFSET + 1] ^= 0x80;
+        assert_eq!(CrashRecord::decode(&bytes), Err(DecodeError::BadCrc));
+    }
+
+    #[test]
+    fn erased_and_cleared_slots_are_empty() {
+        assert_eq!(
+            CrashRecord::decode(&[0xFF; RECORD_LEN]),
+            Err(DecodeError::Empty)
+        );
+        assert_eq!(
+            CrashRecord::decode(&[0; RECORD_LEN]),
+            Err(DecodeError::Empty)
+        );
+    }
+
+    #[test]
+    fn foreign_data_is_rejected() {
+        let mut bytes = sample().encode();
+        bytes[0] = b'X';
+        assert_eq!(CrashRecord::decode(&bytes), Err(DecodeError::BadMagic));
+
+        let mut bytes = sample().encode();
+        bytes[4] = VERSION + 1;
+        assert_eq!(
+            CrashRecord::decode(&bytes),
+            Err(DecodeError::UnsupportedVersion)
+        );
+    }
+
+    #[test]
+    fn message_is_truncated_at_the_limit() {
+        let mut record = CrashRecord::new();
+        write!(record, "{}", "x".repeat(MESSAGE_LEN - 1)).unwrap();
+        // The two byte character no longer fits and is dropped whole
+        write!(record, "\u{e9}").unwrap();
+        assert_eq!(record.message().len(), MESSAGE_LEN - 1);
+        write!(record, "yz").unwrap();
+        assert_eq!(record.message().len(), MESSAGE_LEN);
+        assert!(record.message().ends_with('y'));
+
+        let decoded = CrashRecord::decode(&record.encode()).unwrap();
+        assert_eq!(decoded.message(), record.message());
+    }
+
+    #[test]
+    fn file_keeps_the_end_of_the_path() {
+        let mut record = CrashRecord::new();
+        record.set_file("/home/user/picow/src/state/command_handler.rs");
+        assert_eq!(record.file(), "e/command_handler.rs");
+
+        // The cut would split the two byte character, which is dropped
+        record.set_file("src/\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}/mains.rs");
+        assert_eq!(record.file(), "\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}/mains.rs");
+    }
+}
@@ -0,0 +1,311 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+use portable_atomic::AtomicBool;
+
+/// Stamp of a slot whose value is being replaced, or that was never written
+const WRITING: u32 = 0;
+
+/// Stamp of the value with sequence number `sequence`. The low bit is always
+/// set, so that no sequence number, not even after wrapping, reads as `WRITING`.
+const fn stamp(sequence: u32) -> u32 {
+    sequence << 1 | 1
+}
+
+struct Slot<T> {
+    /// `stamp` of the stored value, or `WRITING`
+    stamp: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Self {
+            stamp: AtomicU32::new(WRITING),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+/// Single-producer, multi-consumer ring buffer of `Copy` values.
+///
+/// The producer never waits: once the ring is full it overwrites the oldest
+/// value. Every consumer owns a [`RingReader`] with its own cursor and learns
+/// how many values it missed when it falls more than `N` values behind.
+/// Slots are guarded by a sequence stamp, so a value that is overwritten
+/// while being copied is detected rather than returned torn.
+pub struct SampleRing<T, const N: usize> {
+    /// Number of values pushed so far
+    head: AtomicU32,
+    writer_taken: AtomicBool,
+    slots: [Slot<T>; N],
+}
+
+// Values are only ever copied out and torn copies are discarded
+unsafe impl<T: Copy + Send, const N: usize> Sync for SampleRing<T, N> {}
+
+/// Values a reader lost because the producer lapped it
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Overrun {
+    pub lost: u32,
+}
+
+impl<T: Copy, const N: usize> SampleRing<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicU32::new(0),
+            writer_taken: AtomicBool::new(false),
+            slots: [const { Slot::new() }; N],
+        }
+    }
+
+    /// Hands out the single producer handle. Returns `None` if it was already taken.
+    pub fn writer(&'static self) -> Option<RingWriter<T, N>> {
+        if self.writer_taken.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(RingWriter { ring: self })
+    }
+
+    /// Creates a consumer that starts with the next value pushed.
+    pub fn reader(&'static self) -> RingReader<T, N> {
+        RingReader {
+            ring: self,
+            cursor: self.head.load(Ordering::Acquire),
+        }
+    }
+
+    /// Most recently pushed value
+    pub fn latest(&self) -> Option<T> {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if head == 0 {
+                return None;
+            }
+            // A failed read means the producer moved on, retry with the new head
+            if let Some(value) = self.read(head.wrapping_sub(1)) {
+                return Some(value);
+            }
+        }
+    }
+
+    fn slot(&self, sequence: u32) -> &Slot<T> {
+        &self.slots[sequence as usize % N]
+    }
+
+    /// Copies the value with the given sequence number, if it is still stored.
+    fn read(&self, sequence: u32) -> Option<T> {
+        let slot = self.slot(sequence);
+        let stored = slot.stamp.load(Ordering::Acquire);
+        if stored != stamp(sequence) {
+            return None;
+        }
+
+        // Volatile so that a concurrent write cannot be assumed away, the
+        // stamp check below rejects the copy in that case
+        let value = unsafe { ptr::read_volatile(slot.value.get()) };
+        fence(Ordering::Acquire);
+        if slot.stamp.load(Ordering::Relaxed) != stored {
+            return None;
+        }
+
+        Some(unsafe { value.assume_init() })
+    }
+}
+
+pub struct RingWriter<T: 'static, const N: usize> {
+    ring: &'static SampleRing<T, N>,
+}
+
+impl<T: Copy, const N: usize> RingWriter<T, N> {
+    pub fn push(&mut self, value: T) {
+        let sequence = self.ring.head.load(Ordering::Relaxed);
+        let slot = self.ring.slot(sequence);
+
+        slot.stamp.store(WRITING, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(slot.value.get(), MaybeUninit::new(value)) };
+        slot.stamp.store(stamp(sequence), Ordering::Release);
+
+        self.ring
+            .head
+            .store(sequence.wrapping_add(1), Ordering::Release);
+    }
+}
+
+pub struct RingReader<T: 'static, const N: usize> {
+    ring: &'static SampleRing<T, N>,
+    cursor: u32,
+}
+
+impl<T: Copy, const N: usize> RingReader<T, N> {
+    /// Returns the next unread value, `Ok(None)` once caught up.
+    ///
+    /// After an `Overrun` the reader continues with the oldest value still stored.
+    pub fn read(&mut self) -> Result<Option<T>, Overrun> {
+        let head = self.ring.head.load(Ordering::Acquire);
+        if self.cursor == head {
+            return Ok(None);
+        }
+
+        if head.wrapping_sub(self.cursor) <= N as u32 {
+            if let Some(value) = self.ring.read(self.cursor) {
+                self.cursor = self.cursor.wrapping_add(1);
+                return Ok(Some(value));
+            }
+        }
+
+        // Lapped, possibly while copying. Skip to the oldest value that the
+        // producer will not overwrite right away.
+        let head = self.ring.head.load(Ordering::Acquire);
+        let oldest = head.wrapping_sub(N as u32 - 1);
+        let lost = oldest.wrapping_sub(self.cursor);
+        self.cursor = oldest;
+        Err(Overrun { lost })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leak<T: Copy, const N: usize>() -> &'static SampleRing<T, N> {
+        Box::leak(Box::new(SampleRing::new()))
+    }
+
+    #[test]
+    fn reads_every_value_in_order() {
+        let ring = leak::<u32, 8>();
+        let mut writer = ring.writer().unwrap();
+        assert!(ring.writer().is_none());
+        assert_eq!(ring.latest(), None);
+
+        let mut reader = ring.reader();
+        assert_eq!(reader.read(), Ok(None));
+        for value in 10..15 {
+            writer.push(value);
+        }
+        for value in 10..15 {
+            assert_eq!(reader.read(), Ok(Some(value)));
+        }
+        assert_eq!(reader.read(), Ok(None));
+        assert_eq!(ring.latest(), Some(14));
+
+        // A reader created later starts with the next value
+        let mut late = ring.reader();
+        writer.push(15);
+        assert_eq!(late.read(), Ok(Some(15)));
+        assert_eq!(reader.read(), Ok(Some(15)));
+    }
+
+    #[test]
+    fn lapped_reader_learns_what_it_lost() {
+        let ring = leak::<u32, 4>();
+        let mut writer = ring.writer().unwrap();
+        let mut reader = ring.reader();
+        for value in 0..10 {
+            writer.push(value);
+        }
+
+        // 7, 8 and 9 are kept, one slot short of the ring so that the next
+        // push does not overwrite the value read next
+        assert_eq!(reader.read(), Err(Overrun { lost: 7 }));
+        for value in 7..10 {
+            assert_eq!(reader.read(), Ok(Some(value)));
+        }
+        assert_eq!(reader.read(), Ok(None));
+    }
+
+    #[test]
+    fn value_being_written_is_not_read() {
+        let ring = leak::<u32, 4>();
+        let mut writer = ring.writer().unwrap();
+        let mut reader = ring.reader();
+        for value in 0..4 {
+            writer.push(value);
+        }
+
+        // The producer is replacing the reader's next value with the fifth
+        ring.slot(4).stamp.store(WRITING, Ordering::Relaxed);
+        assert_eq!(reader.read(), Err(Overrun { lost: 1 }));
+        for value in 1..4 {
+            assert_eq!(reader.read(), Ok(Some(value)));
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let ring = leak::<u32, 4>();
+        ring.head.store(u32::MAX - 1, Ordering::Relaxed);
+        let mut writer = ring.writer().unwrap();
+        let mut reader = ring.reader();
+        for value in 0..3 {
+            writer.push(value);
+        }
+        for value in 0..3 {
+            assert_eq!(reader.read(), Ok(Some(value)));
+        }
+
+        // The slot of sequence number u32::MAX is being overwritten with
+        // sequence number 3. With stamps of the sequence number plus one
+        // u32::MAX would have read as the value being written.
+        let ring = leak::<u32, 4>();
+        ring.head.store(u32::MAX - 3, Ordering::Relaxed);
+        let mut writer = ring.writer().unwrap();
+        let mut reader = ring.reader();
+        for value in 0..7 {
+            writer.push(value);
+        }
+        reader.cursor = u32::MAX;
+        ring.slot(3).stamp.store(WRITING, Ordering::Relaxed);
+        assert_eq!(ring.read(u32::MAX), None);
+        assert_eq!(reader.read(), Err(Overrun { lost: 1 }));
+        for value in 4..7 {
+            assert_eq!(reader.read(), Ok(Some(value)));
+        }
+    }
+
+    #[test]
+    fn concurrent_readers_never_see_torn_values() {
+        const PUSHES: u32 = 200_000;
+        let ring = leak::<[u32; 16], 8>();
+        let mut writer = ring.writer().unwrap();
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut reader = ring.reader();
+                std::thread::spawn(move || {
+                    let mut expected = 0;
+                    let mut lost = 0;
+                    while expected < PUSHES {
+                        match reader.read() {
+                            Ok(Some(value)) => {
+                                assert!(value.iter().all(|&v| v == value[0]), "torn {:?}", value);
+                                assert_eq!(value[0], expected);
+                                expected += 1;
+                            }
+                            Ok(None) => std::thread::yield_now(),
+                            Err(overrun) => {
+                                expected += overrun.lost;
+                                lost += overrun.lost;
+                            }
+                        }
+                    }
+                    lost
+                })
+            })
+            .collect();
+
+        for value in 0..PUSHES {
+            writer.push([value; 16]);
+        }
+        for reader in readers {
+            let lost = reader.join().unwrap();
+            assert!(lost < PUSHES);
+        }
+    }
+}
@@ -1,15 +1,745 @@
+use defmt::*;
 use embassy_rp::{
     adc::{Adc, Async, Config, InterruptHandler},
-    bind_interrupts,
+    bind_interrupts, pac,
     peripherals::ADC,
 };
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::Channel,
+    mutex::{Mutex, MutexGuard},
+};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    emg::{EMG_CHANNELS, SAMPLE_FREQUENCY},
+    watchdog::{self, Supervised},
+};
 
 bind_interrupts!(pub struct AdcIrqs {
     ADC_IRQ_FIFO => InterruptHandler;
 });
 
+/// Samples per channel delivered in one block (50 ms at 500 Hz)
+pub const BLOCK_LEN: usize = 25;
+
+/// One conversion of every EMG channel, in acquisition frame order
+pub type Frame = [i32; EMG_CHANNELS];
+/// Bit `n` is set when frame position `n` failed to convert
+pub type FrameFaults = u8;
+
+/// Faults of a frame whose conversions all failed
+const ALL_FAULTY: FrameFaults = ((1u16 << EMG_CHANNELS) - 1) as FrameFaults;
+
+/// Samples of all EMG channels, converted at a fixed, hardware-clocked rate.
+pub struct SampleBlock {
+    /// Index of the first frame since acquisition started, including frames
+    /// lost between blocks
+    pub first_sample: u32,
+    /// Time at which the block's first frame was converted
+    pub started_at: Instant,
+    pub samples: [Frame; BLOCK_LEN],
+    /// Failed conversions of each frame, their samples are meaningless
+    pub faults: [FrameFaults; BLOCK_LEN],
+    /// Bit `n` is set when frame position `n` reported a hardware lead-off
+    /// during the block
+    pub lead_off: u8,
+}
+
+pub static SAMPLE_BLOCKS: Channel<CriticalSectionRawMutex, SampleBlock, 4> = Channel::new();
+
 pub fn init_adc(adc: ADC) -> Adc<'static, Async> {
     let adc = Adc::new(adc, AdcIrqs, Config::default());
 
     adc
 }
+
+/// Shares the converter between the EMG acquisition and other users.
+///
+/// The acquisition is never paused: while the converter runs free, a loan
+/// slips each of its conversions into the gap after an EMG conversion.
+pub struct AdcLease {
+    borrowed: Mutex<CriticalSectionRawMutex, ()>,
+}
+
+/// Returns the converter to other borrowers when dropped
+pub struct AdcLoan<'a> {
+    _borrowed: MutexGuard<'a, CriticalSectionRawMutex, ()>,
+}
+
+pub static ADC_LEASE: AdcLease = AdcLease::new();
+
+impl AdcLease {
+    const fn new() -> Self {
+        Self {
+            borrowed: Mutex::new(()),
+        }
+    }
+
+    /// Waits until other loans have been returned
+    pub async fn borrow(&self) -> AdcLoan<'_> {
+        AdcLoan {
+            _borrowed: self.borrowed.lock().await,
+        }
+    }
+}
+
+impl AdcLoan<'_> {
+    /// Single conversion of `input`, `None` if the converter flagged an error.
+    ///
+    /// While the converter runs free this core's interrupts are masked until
+    /// the next EMG conversion has finished, up to one conversion period, and
+    /// the conversion is done well before the one after is due. The FIFO is
+    /// disabled meanwhile, which keeps the result out of the EMG stream, and
+    /// the round robin is put back to the EMG input it was about to convert.
+    pub fn convert(&mut self, input: u8) -> Option<u16> {
+        let adc = pac::ADC;
+        cortex_m::interrupt::free(|_| {
+            if adc.cs().read().start_many() {
+                while adc.cs().read().ready() {}
+            }
+            while !adc.cs().read().ready() {}
+
+            let cs = adc.cs().read();
+            let fcs = adc.fcs().read();
+            adc.fcs().modify(|w| w.set_en(false));
+            adc.cs().modify(|w| {
+                w.set_ts_en(true);
+                w.set_ainsel(input);
+                w.set_start_once(true);
+            });
+            while !adc.cs().read().ready() {}
+
+            let failed = adc.cs().read().err();
+            let raw = adc.result().read().result();
+            adc.cs().write_value(cs);
+            adc.fcs().write_value(fcs);
+
+            (!failed).then_some(raw)
+        })
+    }
+}
+
+/// Index and conversion time of the first frame of a block
+#[derive(Clone, Copy)]
+pub struct BlockStart {
+    pub first_sample: u32,
+    pub started_at: Instant,
+}
+
+/// Front end delivering EMG frames at `SAMPLE_FREQUENCY`.
+///
+/// Samples are scaled to at most 16 bits so that every source can feed the
+/// same filter chain, which also removes the source's DC offset.
+pub trait EmgSource {
+    type Error: Format;
+
+    /// Fills `samples` with consecutive frames and flags failed conversions
+    /// in `faults`. An error means no sample of the block could be read.
+    async fn read_block(
+        &mut self,
+        samples: &mut [Frame; BLOCK_LEN],
+        faults: &mut [FrameFaults; BLOCK_LEN],
+    ) -> Result<(), Self::Error>;
+
+    /// Lead-off flags of the last block, for front ends with lead-off detection
+    fn lead_off(&self) -> u8 {
+        0
+    }
+
+    /// Start of the last block, for sources that count their conversions.
+    /// Blocks of other sources are timed from when they were requested.
+    fn block_start(&self) -> Option<BlockStart> {
+        None
+    }
+}
+
+#[cfg(not(feature = "ads129x"))]
+pub use internal::{adc_acquisition_task, SAMPLE_MAX, SAMPLE_MIN};
+
+#[cfg(not(feature = "ads129x"))]
+mod internal {
+    use core::{
+        cell::UnsafeCell,
+        sync::atomic::{compiler_fence, AtomicU32, Ordering},
+    };
+
+    use defmt::*;
+    use embassy_rp::{
+        adc::{Adc, Async, Channel as AdcChannel},
+        dma::Channel,
+        gpio::Pull,
+        pac::{
+            self,
+            dma::{
+                regs::CtrlTrig,
+                vals::{DataSize, TreqSel},
+            },
+        },
+        peripherals::{DMA_CH3, DMA_CH6},
+    };
+    use embassy_time::{Duration, Instant, Timer, TICK_HZ};
+
+    use super::{acquire, BlockStart, EmgSource, Frame, FrameFaults, BLOCK_LEN};
+    #[cfg(feature = "oversampling")]
+    use crate::filters::decimator::Decimator;
+    use crate::{
+        emg::{CHANNEL_TABLE, EMG_CHANNELS, SAMPLE_FREQUENCY},
+        resources::EmgInputResources,
+    };
+
+    /// ADC clock feeding the conversion timer
+    const ADC_CLOCK_HZ: u32 = 48_000_000;
+
+    /// Conversions of each channel per delivered sample (8 kHz at 500 Hz)
+    #[cfg(feature = "oversampling")]
+    const OVERSAMPLING: usize = 16;
+    #[cfg(not(feature = "oversampling"))]
+    const OVERSAMPLING: usize = 1;
+    /// Bits of resolution gained by averaging, half a bit per doubling of the rate
+    const GAIN_BITS: u32 = OVERSAMPLING.ilog2() / 2;
+    /// Taps per polyphase branch of the anti-alias filter, 384 in total at 16x
+    #[cfg(feature = "oversampling")]
+    const PHASE_TAPS: usize = 24;
+
+    /// Raw codes of the 12 bit converter's rails, scaled by the oversampling gain
+    pub const SAMPLE_MIN: i32 = 0;
+    pub const SAMPLE_MAX: i32 = 4095 << GAIN_BITS;
+
+    /// Conversions in one block, all channels interleaved
+    const CONVERSIONS: usize = BLOCK_LEN * OVERSAMPLING * EMG_CHANNELS;
+    /// Conversions that go into one delivered frame
+    const FRAME_CONVERSIONS: u64 = (OVERSAMPLING * EMG_CHANNELS) as u64;
+
+    /// Error flag the FIFO adds to a conversion, above its 12 bit result
+    const FIFO_ERROR: u16 = 1 << 15;
+    const FIFO_RESULT: u16 = 0x0fff;
+
+    /// Clock divider giving one conversion every
+    /// `1 / (sample rate * oversampling * channels)` seconds
+    const fn conversion_divider() -> u16 {
+        let conversions_per_second =
+            SAMPLE_FREQUENCY as u32 * OVERSAMPLING as u32 * EMG_CHANNELS as u32;
+        (ADC_CLOCK_HZ / conversions_per_second - 1) as u16
+    }
+
+    const fn gcd(a: u64, b: u64) -> u64 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    /// ADC clock cycles per conversion
+    const CONVERSION_CYCLES: u64 = conversion_divider() as u64 + 1;
+    /// Timer ticks per ADC clock cycle as the smallest ratio, which keeps
+    /// conversion times from overflowing
+    const TICKS_PER_CYCLE: (u64, u64) = {
+        let divisor = gcd(TICK_HZ, ADC_CLOCK_HZ as u64);
+        (TICK_HZ / divisor, ADC_CLOCK_HZ as u64 / divisor)
+    };
+
+    /// Both halves of the buffer the DMA converts into, one after the other
+    struct Ring(UnsafeCell<[[u16; CONVERSIONS]; 2]>);
+
+    // Only ever read with volatile reads of the half the DMA is not writing
+    unsafe impl Sync for Ring {}
+
+    static RING: Ring = Ring(UnsafeCell::new([[0; CONVERSIONS]; 2]));
+
+    /// Start addresses of the halves, which the control channel reads in turn.
+    /// Aligned to its size so that the DMA's address ring wraps around it.
+    #[repr(align(8))]
+    struct HalfAddresses([AtomicU32; 2]);
+
+    static HALF_ADDRESSES: HalfAddresses = HalfAddresses([AtomicU32::new(0), AtomicU32::new(0)]);
+    /// Size of `HalfAddresses` as a power of two
+    const HALF_ADDRESSES_RING: u8 = 3;
+
+    /// The RP2040's ADC converting the EMG inputs in round-robin mode without
+    /// a pause, drained by DMA into the two halves of a ring in turn.
+    ///
+    /// The data channel converts one half and chains to the control channel,
+    /// which points it at the other half and restarts it. Blocks are taken
+    /// from the half the data channel has left, and the count of conversions
+    /// it has written gives their frame index and conversion time.
+    ///
+    /// With the `oversampling` feature every channel is converted at
+    /// `OVERSAMPLING` times the processing rate and decimated by a polyphase
+    /// FIR, which keeps aliases out of the EMG band and averages down the
+    /// converter's noise.
+    pub struct InternalAdc {
+        // Owned for as long as the converter runs, without being used
+        _adc: Adc<'static, Async>,
+        _inputs: [AdcChannel<'static>; EMG_CHANNELS],
+        data_dma: DMA_CH3,
+        control_dma: DMA_CH6,
+        /// Copy of the half of the block being delivered
+        buffer: [u16; CONVERSIONS],
+        /// Time at which the converter was started
+        started: Instant,
+        /// Index of the first conversion of the next block to deliver
+        next: u64,
+        block_start: Option<BlockStart>,
+        #[cfg(feature = "oversampling")]
+        decimators: [Decimator<OVERSAMPLING, PHASE_TAPS>; EMG_CHANNELS],
+        // Last good conversion per frame position, repeated for failed ones
+        #[cfg(feature = "oversampling")]
+        last_good: [i32; EMG_CHANNELS],
+    }
+
+    impl InternalAdc {
+        /// `inputs` must be listed in ascending ADC input order, which is the
+        /// order the round-robin converts them in.
+        pub fn new(
+            adc: Adc<'static, Async>,
+            inputs: [AdcChannel<'static>; EMG_CHANNELS],
+            data_dma: DMA_CH3,
+            control_dma: DMA_CH6,
+        ) -> Self {
+            Self {
+                _adc: adc,
+                _inputs: inputs,
+                data_dma,
+                control_dma,
+                buffer: [0; CONVERSIONS],
+                started: Instant::now(),
+                next: 0,
+                block_start: None,
+                // Cut off at the Nyquist frequency of the processing rate
+                #[cfg(feature = "oversampling")]
+                decimators: core::array::from_fn(|_| {
+                    Decimator::new(0.5 / OVERSAMPLING as f32, GAIN_BITS)
+                }),
+                #[cfg(feature = "oversampling")]
+                last_good: [(SAMPLE_MAX >> GAIN_BITS) / 2; EMG_CHANNELS],
+            }
+        }
+
+        /// Starts the converter and both DMA channels, which keep running
+        /// without the CPU from then on
+        fn start(&mut self) {
+            let adc = pac::ADC;
+            adc.cs().modify(|w| w.set_start_many(false));
+            while !adc.cs().read().ready() {}
+            adc.fcs().write(|w| {
+                w.set_en(true);
+                w.set_dreq_en(true);
+                w.set_thresh(1);
+                // Keep each conversion's error flag instead of failing the block
+                w.set_err(true);
+            });
+            while !adc.fcs().read().empty() {
+                let _ = adc.fifo().read();
+            }
+            adc.fcs().modify(|w| {
+                w.set_over(true);
+                w.set_underf(true);
+            });
+
+            let ring = RING.0.get() as *mut [u16; CONVERSIONS];
+            for (half, address) in HALF_ADDRESSES.0.iter().enumerate() {
+                address.store(unsafe { ring.add(half) } as u32, Ordering::Relaxed);
+            }
+
+            let data = self.data_dma.regs();
+            let control = self.control_dma.regs();
+
+            // Armed without being triggered, the data channel's first
+            // completion starts it on the second half
+            let mut ctrl = CtrlTrig::default();
+            ctrl.set_en(true);
+            ctrl.set_data_size(DataSize::SIZE_WORD);
+            ctrl.set_incr_read(true);
+            ctrl.set_ring_size(HALF_ADDRESSES_RING);
+            ctrl.set_treq_sel(TreqSel::PERMANENT);
+            ctrl.set_chain_to(self.control_dma.number());
+            ctrl.set_irq_quiet(true);
+            control
+                .read_addr()
+                .write_value(HALF_ADDRESSES.0[1].as_ptr() as u32);
+            control
+                .write_addr()
+                .write_value(data.al2_write_addr_trig().as_ptr() as u32);
+            control.trans_count().write_value(1);
+            control.al1_ctrl().write_value(ctrl.0);
+
+            data.read_addr().write_value(adc.fifo().as_ptr() as u32);
+            data.write_addr()
+                .write_value(HALF_ADDRESSES.0[0].load(Ordering::Relaxed));
+            // Reloaded on every trigger
+            data.trans_count().write_value(CONVERSIONS as u32);
+            compiler_fence(Ordering::SeqCst);
+            data.ctrl_trig().write(|w| {
+                w.set_en(true);
+                w.set_high_priority(true);
+                w.set_data_size(DataSize::SIZE_HALFWORD);
+                w.set_incr_write(true);
+                w.set_treq_sel(TreqSel::ADC);
+                w.set_chain_to(self.control_dma.number());
+                w.set_irq_quiet(true);
+            });
+
+            let first_input = CHANNEL_TABLE
+                .iter()
+                .map(|channel| channel.adc_input)
+                .min()
+                .unwrap_or(0);
+            let inputs = CHANNEL_TABLE
+                .iter()
+                .fold(0, |mask, channel| mask | 1 << channel.adc_input);
+            adc.div().write(|w| w.set_int(conversion_divider()));
+            adc.cs().modify(|w| {
+                w.set_ainsel(first_input);
+                w.set_rrobin(inputs);
+                w.set_start_many(true);
+            });
+            self.started = Instant::now();
+        }
+
+        /// Time at which conversion `index` is started
+        fn conversion_time(&self, index: u64) -> Instant {
+            let (ticks, cycles) = TICKS_PER_CYCLE;
+            self.started + Duration::from_ticks(index * CONVERSION_CYCLES * ticks / cycles)
+        }
+
+        /// Conversions the DMA has written since the converter was started.
+        ///
+        /// Its write position gives the count modulo the ring, the laps are
+        /// taken from the time since the start, which the hardware-clocked
+        /// conversions keep to far less than a block.
+        fn written(&self) -> u64 {
+            let (ticks, cycles) = TICKS_PER_CYCLE;
+            let elapsed = Instant::now().saturating_duration_since(self.started);
+            let estimate = elapsed.as_ticks() * cycles / (ticks * CONVERSION_CYCLES);
+
+            let ring = 2 * CONVERSIONS as u64;
+            let base = HALF_ADDRESSES.0[0].load(Ordering::Relaxed);
+            // The end of one half is the start of the other
+            let offset = self.data_dma.regs().write_addr().read().wrapping_sub(base);
+            let position = (offset / 2) as u64 % ring;
+            // The count with this position that is closest to the estimate
+            let laps = (estimate + ring / 2).saturating_sub(position) / ring;
+            laps * ring + position
+        }
+
+        #[cfg(not(feature = "oversampling"))]
+        fn convert(
+            &mut self,
+            samples: &mut [Frame; BLOCK_LEN],
+            faults: &mut [FrameFaults; BLOCK_LEN],
+        ) {
+            for ((frame, frame_faults), chunk) in samples
+                .iter_mut()
+                .zip(faults.iter_mut())
+                .zip(self.buffer.chunks_exact(EMG_CHANNELS))
+            {
+                *frame_faults = 0;
+                for (position, (sample, &raw)) in frame.iter_mut().zip(chunk).enumerate() {
+                    *sample = (raw & FIFO_RESULT) as i32;
+                    if raw & FIFO_ERROR != 0 {
+                        *frame_faults |= 1 << position;
+                    }
+                }
+            }
+        }
+
+        /// A frame is flagged when any conversion that went into it failed
+        #[cfg(feature = "oversampling")]
+        fn convert(
+            &mut self,
+            samples: &mut [Frame; BLOCK_LEN],
+            faults: &mut [FrameFaults; BLOCK_LEN],
+        ) {
+            for ((frame, frame_faults), conversions) in samples
+                .iter_mut()
+                .zip(faults.iter_mut())
+                .zip(self.buffer.chunks_exact(EMG_CHANNELS * OVERSAMPLING))
+            {
+                *frame_faults = 0;
+                for chunk in conversions.chunks_exact(EMG_CHANNELS) {
+                    for (position, &raw) in chunk.iter().enumerate() {
+                        if raw & FIFO_ERROR == 0 {
+                            self.last_good[position] = (raw & FIFO_RESULT) as i32;
+                        } else {
+                            *frame_faults |= 1 << position;
+                        }
+                        // Every block holds whole frames, so the decimators
+                        // complete an output on a frame's last conversion
+                        if let Some(output) =
+                            self.decimators[position].push(self.last_good[position])
+                        {
+                            frame[position] = output;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    impl EmgSource for InternalAdc {
+        type Error = core::convert::Infallible;
+
+        async fn read_block(
+            &mut self,
+            samples: &mut [Frame; BLOCK_LEN],
+            faults: &mut [FrameFaults; BLOCK_LEN],
+        ) -> Result<(), Self::Error> {
+            let conversions = CONVERSIONS as u64;
+            loop {
+                let end = self.next + conversions;
+                let written = self.written();
+                if written < end {
+                    // Not before the next conversion, should the converter
+                    // have started later than the estimate
+                    let period = self.conversion_time(1) - self.started;
+                    let due = self.conversion_time(end).max(Instant::now() + period);
+                    Timer::at(due).await;
+                    continue;
+                }
+                // The DMA is back in the block's half
+                if written > end + conversions {
+                    let latest = (written / conversions - 1) * conversions;
+                    let lost = (latest - self.next) / FRAME_CONVERSIONS;
+                    warn!("EMG acquisition overrun, {} frames lost", lost);
+                    self.next = latest;
+                    continue;
+                }
+
+                let half = (self.next / conversions % 2) as usize;
+                let ring = RING.0.get() as *const [u16; CONVERSIONS];
+                compiler_fence(Ordering::SeqCst);
+                self.buffer = unsafe { core::ptr::read_volatile(ring.add(half)) };
+                compiler_fence(Ordering::SeqCst);
+                // Overwritten while being copied, skipped like any overrun
+                if self.written() > end + conversions {
+                    continue;
+                }
+
+                self.block_start = Some(BlockStart {
+                    first_sample: (self.next / FRAME_CONVERSIONS) as u32,
+                    started_at: self.conversion_time(self.next),
+                });
+                self.next = end;
+                self.convert(samples, faults);
+                return Ok(());
+            }
+        }
+
+        fn block_start(&self) -> Option<BlockStart> {
+            self.block_start
+        }
+    }
+
+    /// Channels of the ADC inputs `CHANNEL_TABLE` wires the electrodes to, in
+    /// frame order
+    fn emg_inputs(r: EmgInputResources) -> [AdcChannel<'static>; EMG_CHANNELS] {
+        let mut pins = (Some(r.input_0), Some(r.input_1), Some(r.input_2));
+        let mut previous = None;
+        core::array::from_fn(|position| {
+            let channel = unwrap!(CHANNEL_TABLE
+                .iter()
+                .find(|channel| channel.frame_index == position));
+            // Frames are in conversion order
+            assert!(previous < Some(channel.adc_input));
+            previous = Some(channel.adc_input);
+            match channel.adc_input {
+                0 => AdcChannel::new_pin(unwrap!(pins.0.take()), Pull::None),
+                1 => AdcChannel::new_pin(unwrap!(pins.1.take()), Pull::None),
+                2 => AdcChannel::new_pin(unwrap!(pins.2.take()), Pull::None),
+                input => panic!("{} has no ADC input {}", channel.name, input),
+            }
+        })
+    }
+
+    #[embassy_executor::task]
+    pub async fn adc_acquisition_task(
+        adc: Adc<'static, Async>,
+        inputs: EmgInputResources,
+        data_dma: DMA_CH3,
+        control_dma: DMA_CH6,
+    ) {
+        info!("ADC acquisition task started!");
+        let mut source = InternalAdc::new(adc, emg_inputs(inputs), data_dma, control_dma);
+        source.start();
+        acquire(source).await
+    }
+}
+
+#[cfg(feature = "ads129x")]
+pub use external::{ads129x_acquisition_task, SAMPLE_MAX, SAMPLE_MIN};
+
+#[cfg(feature = "ads129x")]
+mod external {
+    use defmt::*;
+    use embassy_embedded_hal::shared_bus::asynch::spi::{SpiDevice, SpiDeviceError};
+    use embassy_rp::{
+        gpio::{Input, Level, Output, Pull},
+        peripherals::SPI1,
+        spi::{self, Phase, Polarity, Spi},
+    };
+    use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+    use embassy_time::Delay;
+    use static_cell::StaticCell;
+
+    use super::{acquire, EmgSource, Frame, FrameFaults, ALL_FAULTY, BLOCK_LEN};
+    use crate::{
+        ads129x::{self, Ads129x, DataRate},
+        emg::{EMG_CHANNELS, SAMPLE_FREQUENCY},
+        filters::EMG::SampleFrequency,
+        resources::AdsResources,
+    };
+
+    type AdsSpi = SpiDevice<'static, NoopRawMutex, Spi<'static, SPI1, spi::Async>, Output<'static>>;
+    type AdsError = ads129x::Error<
+        SpiDeviceError<spi::Error, core::convert::Infallible>,
+        core::convert::Infallible,
+    >;
+
+    /// SCLK rate, well above the 27 bytes per frame an ADS1299 needs at 2 kSPS
+    const SPI_FREQUENCY: u32 = 2_000_000;
+    /// 24 bit conversions are shifted down to the 16 bit range of the filter chain
+    const SAMPLE_SHIFT: u32 = 8;
+
+    /// Rails of the shifted samples
+    pub const SAMPLE_MIN: i32 = i16::MIN as i32;
+    pub const SAMPLE_MAX: i32 = i16::MAX as i32;
+
+    pub struct ExternalAdc {
+        device: Ads129x<AdsSpi, Input<'static>, Delay, EMG_CHANNELS>,
+        lead_off: u8,
+    }
+
+    impl EmgSource for ExternalAdc {
+        type Error = AdsError;
+
+        async fn read_block(
+            &mut self,
+            samples: &mut [Frame; BLOCK_LEN],
+            faults: &mut [FrameFaults; BLOCK_LEN],
+        ) -> Result<(), Self::Error> {
+            self.lead_off = 0;
+            let mut last_error = None;
+            for (frame, frame_faults) in samples.iter_mut().zip(faults.iter_mut()) {
+                match self.device.read_frame().await {
+                    Ok(conversion) => {
+                        *frame_faults = 0;
+                        self.lead_off |= conversion.lead_off;
+                        for (sample, raw) in frame.iter_mut().zip(conversion.samples) {
+                            *sample = raw >> SAMPLE_SHIFT;
+                        }
+                    }
+                    Err(e) => {
+                        *frame_faults = ALL_FAULTY;
+                        last_error = Some(e);
+                    }
+                }
+            }
+
+            // A block without a single good frame points at the bus, not at a sample
+            match last_error {
+                Some(e) if faults.iter().all(|&f| f == ALL_FAULTY) => Err(e),
+                _ => Ok(()),
+            }
+        }
+
+        fn lead_off(&self) -> u8 {
+            self.lead_off
+        }
+    }
+
+    #[embassy_executor::task]
+    pub async fn ads129x_acquisition_task(r: AdsResources) {
+        info!("ADS129x acquisition task started!");
+
+        let mut config = spi::Config::default();
+        config.frequency = SPI_FREQUENCY;
+        config.phase = Phase::CaptureOnSecondTransition;
+        config.polarity = Polarity::IdleLow;
+
+        static SPI_BUS: StaticCell<Mutex<NoopRawMutex, Spi<'static, SPI1, spi::Async>>> =
+            StaticCell::new();
+        let bus = SPI_BUS.init(Mutex::new(Spi::new(
+            r.spi, r.clk, r.mosi, r.miso, r.dma_tx, r.dma_rx, config,
+        )));
+        let spi = SpiDevice::new(bus, Output::new(r.cs, Level::High));
+        let drdy = Input::new(r.drdy, Pull::Up);
+
+        let mut device = Ads129x::new(spi, drdy, Delay);
+        let config = ads129x::Config {
+            data_rate: match SAMPLE_FREQUENCY {
+                SampleFrequency::Freq500Hz => DataRate::Sps500,
+                SampleFrequency::Freq1000Hz => DataRate::Sps1000,
+            },
+            ..Default::default()
+        };
+
+        match device.init(config).await {
+            Ok(variant) => info!("Found {}", variant),
+            Err(e) => {
+                error!("Failed to initialize ADS129x: {}", e);
+                return;
+            }
+        }
+
+        acquire(ExternalAdc {
+            device,
+            lead_off: 0,
+        })
+        .await
+    }
+}
+
+/// Streams sample blocks from `source` to the EMG processing task.
+async fn acquire<S: EmgSource>(mut source: S) -> ! {
+    let frame_period = Duration::from_micros(1_000_000 / SAMPLE_FREQUENCY as u64);
+    let block_duration = frame_period * BLOCK_LEN as u32;
+
+    let mut next_sample: u32 = 0;
+    let mut expected_start: Option<Instant> = None;
+
+    loop {
+        let requested_at = Instant::now();
+        let mut block = SampleBlock {
+            first_sample: next_sample,
+            started_at: requested_at,
+            samples: [[0; EMG_CHANNELS]; BLOCK_LEN],
+            faults: [0; BLOCK_LEN],
+            lead_off: 0,
+        };
+        // The block is still delivered so that processing keeps its timing,
+        // with every sample flagged
+        match source
+            .read_block(&mut block.samples, &mut block.faults)
+            .await
+        {
+            Ok(()) => block.lead_off = source.lead_off(),
+            Err(e) => {
+                warn!("EMG acquisition failed: {}", e);
+                block.faults = [ALL_FAULTY; BLOCK_LEN];
+            }
+        }
+
+        if let Some(start) = source.block_start() {
+            block.first_sample = start.first_sample;
+            block.started_at = start.started_at;
+        } else if let Some(expected) = expected_start {
+            // Frames that would have been converted while the source was idle between blocks
+            let late = requested_at.saturating_duration_since(expected);
+            let missed = (late.as_ticks() / frame_period.as_ticks()) as u32;
+            if missed > 0 {
+                warn!("EMG acquisition restarted late, {} frames lost", missed);
+                block.first_sample = next_sample.wrapping_add(missed);
+            }
+        }
+
+        next_sample = block.first_sample.wrapping_add(BLOCK_LEN as u32);
+        expected_start = Some(block.started_at + block_duration);
+        watchdog::check_in(Supervised::Acquisition);
+
+        if SAMPLE_BLOCKS.try_send(block).is_err() {
+            warn!("EMG processing is falling behind, dropping sample block");
+        }
+    }
+}
@@ -2,7 +2,7 @@ use core::sync::atomic::Ordering;
 
 use bt_hci::controller::ExternalController;
 use cyw43_pio::PioSpi;
-use defmt::{info, unwrap};
+use defmt::{debug, info, unwrap, warn};
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 use embassy_rp::{
@@ -11,13 +11,45 @@ use embassy_rp::{
     peripherals::{DMA_CH0, PIO0},
     pio::{self, Pio},
 };
-use embassy_time::Timer;
+use embassy_time::{with_timeout, Duration, Timer};
 use static_cell::StaticCell;
 use trouble_host::{prelude::*, Address, Controller, HostResources, PacketQos};
 
 use crate::{
-    emg::{EMG1_VALUE, EMG2_VALUE},
+    commands::device_info::DEVICE_INFO_LEN,
+    contact::CONTACT_STATUS,
+    crash::{record::RECORD_LEN, CRASH_LOG},
+    cycles::{self, CycleStats},
+    device::{self, HAND_INFO},
+    emg::{
+        ARTIFACT_EPISODES, ARTIFACT_SENSITIVITY, CHANNEL_TABLE, EMG_CHANNELS, EMG_SAMPLES,
+        ENVELOPE_SETTINGS, FILTER_SETTINGS,
+    },
+    estop,
+    fatigue::{FATIGUE_INDEX, MEDIAN_FREQ},
+    filters::{artifact::ArtifactSensitivity, envelope::EnvelopeSettings, EMG::FilterSettings},
+    health::{DEFAULT_ERROR_RATE_LIMIT, ERROR_RATE_LIMIT, SAMPLE_ERRORS},
+    joints::{
+        limits::{encode_positions, JointLimits, LIMITS_BYTES, POSITION_BYTES},
+        JOINT_MODEL,
+    },
+    led,
+    monitor::{
+        BATTERY_LEVEL, DEFAULT_LOW_BATTERY, DEFAULT_OVER_TEMPERATURE, LOW_BATTERY,
+        OVER_TEMPERATURE, TEMPERATURE, VSYS,
+    },
+    protection::{
+        detector::PRESSURE_SENSORS, DEFAULT_FORCE_LIMIT, FORCE_LIMITS, MAX_FORCE_LIMIT,
+        MIN_FORCE_LIMIT,
+    },
     resources::BltResources,
+    state::{
+        command_handler::{DEFAULT_HEARTBEAT_TIMEOUT, HEARTBEAT_TIMEOUT, MIN_HEARTBEAT_TIMEOUT},
+        events::{Events, EVENT_CHANNEL},
+        machine::UserRequest,
+        PROGRAM_STATE, STAGE_CHANGES,
+    },
+    watchdog::{self, Supervised, RESET_CAUSE, RESET_CULPRIT},
 };
 
 bind_interrupts!(struct BltIrqs {
@@ -39,101 +71,170 @@ const CONNECTIONS_MAX: usize = 1;
 /// Max number of L2CAP channels.
 const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
 const MAX_ATTRIBUTES: usize = 10;
+/// Written to `crash_control` to erase the crash log
+const CLEAR_CRASH_LOG: u8 = 0xFF;
+/// Advertising is restarted this often while nobody connects, each restart
+/// goes through the stack and checks in with the watchdog
+const ADVERTISING_ROUND: Duration = Duration::from_millis(500);
 type Resources<C> = HostResources<C, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX, L2CAP_MTU>;
 
 // Define the service for prosthetic arm
+//
+// Per-channel values are packed as one little endian u16 per EMG channel, in
+// the order of `emg::CHANNEL_TABLE`.
 #[gatt_service(uuid = "1815")]
 struct ProstheticArmService {
     #[characteristic(uuid = "2A58", read, notify)]
-    erm_sensor_1: u16,
+    emg_values: [u8; EMG_CHANNELS * 2],
 
-    #[characteristic(uuid = "2A59", read, notify)]
-    erm_sensor_2: u16,
+    /// Number of configured EMG channels
+    #[characteristic(uuid = "7344", read)]
+    channel_count: u8,
 
+    /// Minimum and maximum sensitivity of each channel
     #[characteristic(uuid = "7345", read, write)]
-    sensitivity_min_1: u16,
-
-    #[characteristic(uuid = "7346", read, write)]
-    sensitivity_max_1: u16,
-
-    #[characteristic(uuid = "7347", read, write)]
-    sensitivity_min_2: u16,
-
-    #[characteristic(uuid = "7348", read, write)]
-    sensitivity_max_2: u16,
-}
-
-fn erm_sensor_1_on_read(_connection: &Connection) {
-    info!("[gatt] Read event on ERM sensor 1");
-}
-
-fn erm_sensor_2_on_read(_connection: &Connection) {
-    info!("[gatt] Read event on ERM sensor 2");
-}
-
-fn sensitivity_min_1_on_read(_connection: &Connection) {
-    info!("[gatt] Read event on sensitivity minimum 1");
-}
-
-fn sensitivity_min_1_on_write(_connection: &Connection, data: &[u8]) -> Result<(), ()> {
-    if data.len() == 2 {
-        let value = u16::from_le_bytes([data[0], data[1]]);
-        info!("[gatt] New sensitivity minimum 1: {}", value);
-        Ok(())
-    } else {
-        info!("[gatt] Invalid sensitivity minimum 1 data");
-        Err(())
-    }
-}
-
-fn sensitivity_max_1_on_read(_connection: &Connection) {
-    info!("[gatt] Read event on sensitivity maximum 1");
+    sensitivity: [u8; EMG_CHANNELS * 4],
+
+    /// Median power frequency in 0.1 Hz
+    #[characteristic(uuid = "7349", read, notify)]
+    median_freq: [u8; EMG_CHANNELS * 2],
+
+    /// Median frequency drop from the session baseline in percent
+    #[characteristic(uuid = "734B", read, notify)]
+    fatigue: [u8; EMG_CHANNELS * 2],
+
+    /// Bit 0: notch, bit 1: lowpass, bit 2: highpass, bit 3: 60 Hz notch,
+    /// bit 4: adaptive notch
+    #[characteristic(uuid = "734D", read, write)]
+    filter_config: u8,
+
+    /// Envelope kind (0: box, 1: exponential, 2: attack/release, 3: Hilbert),
+    /// attack and release time in ms as little endian u16
+    #[characteristic(uuid = "734E", read, write)]
+    envelope_config: [u8; 5],
+
+    /// Electrode contact per channel: 0 in contact, 1 saturated, 2 DC offset,
+    /// 3 mains hum, 4 hardware lead-off
+    #[characteristic(uuid = "734F", read, notify)]
+    contact: [u8; EMG_CHANNELS],
+
+    /// Artifact rejection sensitivity (0: clipping only, 1: low, 2: medium, 3: high)
+    #[characteristic(uuid = "7350", read, write)]
+    artifact_config: u8,
+
+    /// Artifacts rejected per channel since boot
+    #[characteristic(uuid = "7351", read, notify)]
+    artifacts: [u8; EMG_CHANNELS * 2],
+
+    /// Failed conversions per channel since boot
+    #[characteristic(uuid = "7352", read, notify)]
+    sensor_errors: [u8; EMG_CHANNELS * 2],
+
+    /// Failed conversion rate, in per mille, at which a channel is faulted (little endian u16)
+    #[characteristic(uuid = "7353", read, write)]
+    error_rate_limit: [u8; 2],
+
+    /// VSYS in mV (little endian u16) and die temperature in 0.1 °C (little endian i16)
+    #[characteristic(uuid = "7354", read, notify)]
+    supply: [u8; 4],
+
+    /// Low battery VSYS in mV (little endian u16) and over-temperature limit
+    /// in 0.1 °C (little endian i16)
+    #[characteristic(uuid = "7355", read, write)]
+    supply_limits: [u8; 4],
+
+    /// Program stage (0: boot, 1: idle, 2: calibration, 3: operation,
+    /// 4: paused, 5: error, 6: shutdown)
+    #[characteristic(uuid = "7356", read, notify)]
+    stage: u8,
+
+    /// Requested stage change (0: stop, 1: calibrate, 2: pause, 3: resume, 4: shut down)
+    #[characteristic(uuid = "7357", write)]
+    stage_request: u8,
+
+    /// Cause of the last reset (0: power-on, 1: forced, 2: watchdog,
+    /// 3: panic) and the task that missed its deadline (0: acquisition,
+    /// 1: processing, 2: bluetooth, 3: command handler, 0xFF: none)
+    #[characteristic(uuid = "7358", read)]
+    reset_reason: [u8; 2],
+
+    /// Number of stored crash records
+    #[characteristic(uuid = "7359", read)]
+    crash_count: u8,
+
+    /// Crash record selected with `crash_control`, see `crash::record` for
+    /// the layout, all zero if there is none
+    #[characteristic(uuid = "735A", read)]
+    crash_record: [u8; RECORD_LEN],
+
+    /// Index of the crash record to read, 0xFF erases every record
+    #[characteristic(uuid = "735B", write)]
+    crash_control: u8,
+
+    /// Time in ms without a packet from the hand after which motion is
+    /// stopped (little endian u16, at least 200)
+    #[characteristic(uuid = "735C", read, write)]
+    heartbeat_timeout: [u8; 2],
+
+    /// 1 while the emergency stop is latched. Writing 1 stops the hand, 0
+    /// clears the emergency stop unless the hand triggered it.
+    #[characteristic(uuid = "735D", read, write, notify)]
+    emergency_stop: u8,
+
+    /// Pressure limit of each of the hand's 3 pressure sensors, in raw
+    /// sensor counts (little endian u16), clamped to 200..=4000
+    #[characteristic(uuid = "735E", read, write)]
+    force_limits: [u8; PRESSURE_SENSORS * 2],
+
+    /// Minimum and maximum position of each of the hand's 6 joints, as the
+    /// hand last reported them (little endian u16 pairs)
+    #[characteristic(uuid = "735F", read, notify)]
+    joint_positions: [u8; POSITION_BYTES],
+
+    /// Soft minimum and maximum of each joint (little endian u16 pairs)
+    /// followed by the velocity limit in counts per second (little endian u16)
+    #[characteristic(uuid = "7360", read, write)]
+    joint_limits: [u8; LIMITS_BYTES],
+
+    /// Protocol major and minor version, firmware major, minor and patch
+    /// version, hardware revision, 8 byte serial and bitmap of supported
+    /// command codes (little endian u32) of this controller
+    #[characteristic(uuid = "7361", read)]
+    device_info: [u8; DEVICE_INFO_LEN],
+
+    /// The same for the hand controller, all zero until it answered
+    #[characteristic(uuid = "7362", read, notify)]
+    hand_info: [u8; DEVICE_INFO_LEN],
 }
 
-fn sensitivity_max_1_on_write(_connection: &Connection, data: &[u8]) -> Result<(), ()> {
-    if data.len() == 2 {
-        let value = u16::from_le_bytes([data[0], data[1]]);
-        info!("[gatt] New sensitivity maximum 1: {}", value);
-        Ok(())
-    } else {
-        info!("[gatt] Invalid sensitivity maximum 1 data");
-        Err(())
-    }
+#[gatt_service(uuid = "180F")]
+struct BatteryService {
+    /// Charge estimate in percent
+    #[characteristic(uuid = "2A19", read, notify)]
+    battery_level: u8,
 }
 
-fn sensitivity_min_2_on_read(_connection: &Connection) {
-    info!("[gatt] Read event on sensitivity minimum 2");
+/// VSYS or its low battery limit followed by a temperature, as little endian
+fn supply_bytes(millivolts: u16, decicelsius: i16) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    bytes[..2].copy_from_slice(&millivolts.to_le_bytes());
+    bytes[2..].copy_from_slice(&decicelsius.to_le_bytes());
+    bytes
 }
 
-fn sensitivity_min_2_on_write(_connection: &Connection, data: &[u8]) -> Result<(), ()> {
-    if data.len() == 2 {
-        let value = u16::from_le_bytes([data[0], data[1]]);
-        info!("[gatt] New sensitivity minimum 2: {}", value);
-        Ok(())
-    } else {
-        info!("[gatt] Invalid sensitivity minimum 2 data");
-        Err(())
-    }
-}
-
-fn sensitivity_max_2_on_read(_connection: &Connection) {
-    info!("[gatt] Read event on sensitivity maximum 2");
-}
-
-fn sensitivity_max_2_on_write(_connection: &Connection, data: &[u8]) -> Result<(), ()> {
-    if data.len() == 2 {
-        let value = u16::from_le_bytes([data[0], data[1]]);
-        info!("[gatt] New sensitivity maximum 2: {}", value);
-        Ok(())
-    } else {
-        info!("[gatt] Invalid sensitivity maximum 2 data");
-        Err(())
+/// Packs one little endian u16 per EMG channel
+fn per_channel(value: impl Fn(usize) -> u16) -> [u8; EMG_CHANNELS * 2] {
+    let mut bytes = [0; EMG_CHANNELS * 2];
+    for (channel, chunk) in bytes.chunks_exact_mut(2).enumerate() {
+        chunk.copy_from_slice(&value(channel).to_le_bytes());
     }
+    bytes
 }
 
 #[gatt_server]
 struct Server {
     prosthetic_arm_service: ProstheticArmService,
+    battery_service: BatteryService,
 }
 
 #[embassy_executor::task]
@@ -155,6 +256,7 @@ pub async fn initialize_bluetooth(spawner: Spawner, p: BltResources) -> () {
         cyw43::new_with_bluetooth(state, pwr, spi, fw, btfw).await;
     unwrap!(spawner.spawn(cyw43_task(runner)));
     control.init(clm).await;
+    unwrap!(spawner.spawn(led::status_led_task(control)));
 
     let controller: ExternalController<_, 10> = ExternalController::new(bt_device);
 
@@ -175,16 +277,125 @@ pub async fn initialize_bluetooth(spawner: Spawner, p: BltResources) -> () {
     )
     .unwrap();
 
+    let channel_count = EMG_CHANNELS as u8;
+    if server
+        .set(&server.prosthetic_arm_service.channel_count, &channel_count)
+        .is_err()
+    {
+        info!("Failed to set channel count");
+    }
+
+    let filter_config = FilterSettings::default().to_bits();
+    if server
+        .set(&server.prosthetic_arm_service.filter_config, &filter_config)
+        .is_err()
+    {
+        info!("Failed to set initial filter configuration");
+    }
+
+    let envelope_config = EnvelopeSettings::default().to_bytes();
+    if server
+        .set(
+            &server.prosthetic_arm_service.envelope_config,
+            &envelope_config,
+        )
+        .is_err()
+    {
+        info!("Failed to set initial envelope configuration");
+    }
+
+    let artifact_config = ArtifactSensitivity::default() as u8;
+    if server
+        .set(
+            &server.prosthetic_arm_service.artifact_config,
+            &artifact_config,
+        )
+        .is_err()
+    {
+        info!("Failed to set initial artifact configuration");
+    }
+
+    let error_rate_limit = DEFAULT_ERROR_RATE_LIMIT.to_le_bytes();
+    if server
+        .set(
+            &server.prosthetic_arm_service.error_rate_limit,
+            &error_rate_limit,
+        )
+        .is_err()
+    {
+        info!("Failed to set initial error rate limit");
+    }
+
+    let supply_limits = supply_bytes(DEFAULT_LOW_BATTERY, DEFAULT_OVER_TEMPERATURE);
+    if server
+        .set(&server.prosthetic_arm_service.supply_limits, &supply_limits)
+        .is_err()
+    {
+        info!("Failed to set initial supply limits");
+    }
+
+    let reset_reason = [
+        RESET_CAUSE.load(Ordering::Relaxed),
+        RESET_CULPRIT.load(Ordering::Relaxed),
+    ];
+    if server
+        .set(&server.prosthetic_arm_service.reset_reason, &reset_reason)
+        .is_err()
+    {
+        info!("Failed to set reset reason");
+    }
+
+    let heartbeat_timeout = DEFAULT_HEARTBEAT_TIMEOUT.to_le_bytes();
+    if server
+        .set(
+            &server.prosthetic_arm_service.heartbeat_timeout,
+            &heartbeat_timeout,
+        )
+        .is_err()
+    {
+        info!("Failed to set initial heartbeat timeout");
+    }
+
+    let mut force_limits = [0; PRESSURE_SENSORS * 2];
+    for chunk in force_limits.chunks_exact_mut(2) {
+        chunk.copy_from_slice(&DEFAULT_FORCE_LIMIT.to_le_bytes());
+    }
+    if server
+        .set(&server.prosthetic_arm_service.force_limits, &force_limits)
+        .is_err()
+    {
+        info!("Failed to set initial force limits");
+    }
+
+    let joint_limits = JointLimits::new().to_bytes();
+    if server
+        .set(&server.prosthetic_arm_service.joint_limits, &joint_limits)
+        .is_err()
+    {
+        info!("Failed to set initial joint limits");
+    }
+
+    let device_info = device::own_info().to_bytes();
+    if server
+        .set(&server.prosthetic_arm_service.device_info, &device_info)
+        .is_err()
+    {
+        info!("Failed to set device info");
+    }
+
+    select_crash_record(&server, 0).await;
+
     let ble_background_task = select(ble_task(runner), gatt_task(&server));
 
     let app_task = async {
         loop {
             match advertise("ProstheticArm", &mut peripheral).await {
-                Ok(conn) => {
+                Ok(Some(conn)) => {
                     let connection_task = conn_task(&server, &conn);
                     let sensor_task = sensor_update_task(&server, &conn);
                     select(connection_task, sensor_task).await;
                 }
+                Ok(None) => {}
                 Err(e) => {
                     info!("Error advertising: {:?}", e);
                     break;
@@ -216,37 +427,237 @@ async fn gatt_task<C: Controller>(
 }
 
 async fn sensor_update_task<C: Controller>(server: &Server<'_, '_, C>, conn: &Connection<'_>) {
-    let erm1 = server.prosthetic_arm_service.erm_sensor_1;
-    let erm2 = server.prosthetic_arm_service.erm_sensor_2;
+    let emg = server.prosthetic_arm_service.emg_values;
+    let mut samples = EMG_SAMPLES.reader();
+    let mut streaming_cycles = CycleStats::new("EMG streaming");
+    // Supply readings change every few seconds, only notify new ones
+    let mut last_supply = None;
+    let mut last_estop = None;
+    let mut last_joints = None;
+    let mut last_hand_info = None;
+    // Stage changes are delivered in order, the current stage is sent first
+    let mut stages = STAGE_CHANGES.subscriber().ok();
+    let mut stage = Some(*PROGRAM_STATE.lock().await);
 
     loop {
-        let sensor1_value: u16 = EMG1_VALUE.load(Ordering::Relaxed) as u16;
-        let sensor2_value: u16 = EMG2_VALUE.load(Ordering::Relaxed) as u16;
+        // Average of every sample since the previous notification
+        let mut sums = [0i64; EMG_CHANNELS];
+        let mut count = 0;
+        let start = cycles::now();
+        loop {
+            match samples.read() {
+                Ok(Some(sample)) => {
+                    for (sum, &value) in sums.iter_mut().zip(sample.values.iter()) {
+                        *sum += value as i64;
+                    }
+                    count += 1;
+                }
+                Ok(None) => break,
+                Err(overrun) => warn!("[adv] {} EMG samples lost", overrun.lost),
+            }
+        }
+        let emg_values = per_channel(|channel| (sums[channel] / count.max(1)) as u16);
+        streaming_cycles.record(cycles::since(start), count as u32);
 
-        if server.notify(&erm1, conn, &sensor1_value).await.is_err() {
-            info!("[adv] error notifying ERM1 value");
+        if server.notify(&emg, conn, &emg_values).await.is_err() {
+            info!("[adv] error notifying EMG values");
             break;
         }
 
-        if server.notify(&erm2, conn, &sensor2_value).await.is_err() {
-            info!("[adv] error notifying ERM2 value");
+        if update_fatigue(server, conn).await.is_err() {
+            info!("[adv] error notifying fatigue values");
             break;
         }
 
+        let contact =
+            core::array::from_fn(|channel| CONTACT_STATUS[channel].load(Ordering::Relaxed));
+        if server
+            .notify(&server.prosthetic_arm_service.contact, conn, &contact)
+            .await
+            .is_err()
+        {
+            info!("[adv] error notifying contact status");
+            break;
+        }
+
+        let artifacts = per_channel(|channel| {
+            ARTIFACT_EPISODES[channel]
+                .load(Ordering::Relaxed)
+                .min(u16::MAX as u32) as u16
+        });
+        if server
+            .notify(&server.prosthetic_arm_service.artifacts, conn, &artifacts)
+            .await
+            .is_err()
+        {
+            info!("[adv] error notifying artifact counts");
+            break;
+        }
+
+        let errors = per_channel(|channel| {
+            SAMPLE_ERRORS[channel]
+                .load(Ordering::Relaxed)
+                .min(u16::MAX as u32) as u16
+        });
+        if server
+            .notify(&server.prosthetic_arm_service.sensor_errors, conn, &errors)
+            .await
+            .is_err()
+        {
+            info!("[adv] error notifying sensor errors");
+            break;
+        }
+
+        let supply = supply_bytes(
+            VSYS.load(Ordering::Relaxed),
+            TEMPERATURE.load(Ordering::Relaxed),
+        );
+        if last_supply != Some(supply) {
+            last_supply = Some(supply);
+            if update_supply(server, conn, &supply).await.is_err() {
+                info!("[adv] error notifying supply");
+                break;
+            }
+        }
+
+        let latched = estop::is_latched() as u8;
+        if last_estop != Some(latched) {
+            last_estop = Some(latched);
+            if server
+                .notify(
+                    &server.prosthetic_arm_service.emergency_stop,
+                    conn,
+                    &latched,
+                )
+                .await
+                .is_err()
+            {
+                info!("[adv] error notifying emergency stop");
+                break;
+            }
+        }
+
+        let joints = JOINT_MODEL.lock().await.measured;
+        if let Some(positions) = joints.filter(|_| last_joints != joints) {
+            last_joints = joints;
+            if server
+                .notify(
+                    &server.prosthetic_arm_service.joint_positions,
+                    conn,
+                    &encode_positions(&positions),
+                )
+                .await
+                .is_err()
+            {
+                info!("[adv] error notifying joint positions");
+                break;
+            }
+        }
+
+        let hand_info = *HAND_INFO.lock().await;
+        if let Some(info) = hand_info.filter(|_| last_hand_info != hand_info) {
+            last_hand_info = hand_info;
+            if server
+                .notify(
+                    &server.prosthetic_arm_service.hand_info,
+                    conn,
+                    &info.to_bytes(),
+                )
+                .await
+                .is_err()
+            {
+                info!("[adv] error notifying hand controller info");
+                break;
+            }
+        }
+
+        if let Some(subscriber) = stages.as_mut() {
+            while let Some(next) = subscriber.try_next_message_pure() {
+                stage = Some(next);
+            }
+        }
+        if let Some(current) = stage.take() {
+            if server
+                .notify(&server.prosthetic_arm_service.stage, conn, &(current as u8))
+                .await
+                .is_err()
+            {
+                info!("[adv] error notifying program stage");
+                break;
+            }
+        }
+
+        // Every notification of the round went through the stack
+        watchdog::check_in(Supervised::Bluetooth);
         Timer::after_millis(100).await;
     }
 }
 
+/// Shows crash record `index` and the number of stored records
+async fn select_crash_record<C: Controller>(server: &Server<'_, '_, C>, index: u8) {
+    let (count, record) = match CRASH_LOG.lock().await.as_mut() {
+        Some(log) => (log.count() as u8, log.raw(index as usize)),
+        None => (0, None),
+    };
+
+    let service = &server.prosthetic_arm_service;
+    let record = record.unwrap_or([0; RECORD_LEN]);
+    if server.set(&service.crash_count, &count).is_err()
+        || server.set(&service.crash_record, &record).is_err()
+    {
+        info!("Failed to set crash record");
+    }
+}
+
+async fn update_fatigue<C: Controller>(
+    server: &Server<'_, '_, C>,
+    conn: &Connection<'_>,
+) -> Result<(), BleHostError<C::Error>> {
+    let service = &server.prosthetic_arm_service;
+
+    let median = per_channel(|channel| MEDIAN_FREQ[channel].load(Ordering::Relaxed));
+    let fatigue = per_channel(|channel| FATIGUE_INDEX[channel].load(Ordering::Relaxed));
+
+    server.notify(&service.median_freq, conn, &median).await?;
+    server.notify(&service.fatigue, conn, &fatigue).await?;
+
+    Ok(())
+}
+
+async fn update_supply<C: Controller>(
+    server: &Server<'_, '_, C>,
+    conn: &Connection<'_>,
+    supply: &[u8; 4],
+) -> Result<(), BleHostError<C::Error>> {
+    let level = BATTERY_LEVEL.load(Ordering::Relaxed);
+
+    server
+        .notify(&server.prosthetic_arm_service.supply, conn, supply)
+        .await?;
+    server
+        .notify(&server.battery_service.battery_level, conn, &level)
+        .await?;
+
+    Ok(())
+}
+
 async fn conn_task<C: Controller>(
     server: &Server<'_, '_, C>,
     conn: &Connection<'_>,
 ) -> Result<(), BleHostError<C::Error>> {
-    let erm1 = server.prosthetic_arm_service.erm_sensor_1;
-    let erm2 = server.prosthetic_arm_service.erm_sensor_2;
-    let sens_min1 = server.prosthetic_arm_service.sensitivity_min_1;
-    let sens_max1 = server.prosthetic_arm_service.sensitivity_max_1;
-    let sens_min2 = server.prosthetic_arm_service.sensitivity_min_2;
-    let sens_max2 = server.prosthetic_arm_service.sensitivity_max_2;
+    let emg = server.prosthetic_arm_service.emg_values;
+    let sensitivity = server.prosthetic_arm_service.sensitivity;
+    let filter_config = server.prosthetic_arm_service.filter_config;
+    let envelope_config = server.prosthetic_arm_service.envelope_config;
+    let artifact_config = server.prosthetic_arm_service.artifact_config;
+    let error_rate_limit = server.prosthetic_arm_service.error_rate_limit;
+    let supply_limits = server.prosthetic_arm_service.supply_limits;
+    let stage_request = server.prosthetic_arm_service.stage_request;
+    let crash_control = server.prosthetic_arm_service.crash_control;
+    let heartbeat_timeout = server.prosthetic_arm_service.heartbeat_timeout;
+    let emergency_stop = server.prosthetic_arm_service.emergency_stop;
+    let force_limits = server.prosthetic_arm_service.force_limits;
+    let joint_limits = server.prosthetic_arm_service.joint_limits;
 
     loop {
         match conn.next().await {
@@ -256,39 +667,148 @@ async fn conn_task<C: Controller>(
             }
             ConnectionEvent::Gatt { event, .. } => match event {
                 GattEvent::Read { value_handle } => {
-                    if value_handle == erm1.handle {
-                        let value = server.get(&erm1);
-                        info!("[gatt] Read ERM1 value: {:?}", value);
-                    } else if value_handle == erm2.handle {
-                        let value = server.get(&erm2);
-                        info!("[gatt] Read ERM2 value: {:?}", value);
-                    } else if value_handle == sens_min1.handle {
-                        let value = server.get(&sens_min1);
-                        info!("[gatt] Read sensitivity min 1: {:?}", value);
-                    } else if value_handle == sens_max1.handle {
-                        let value = server.get(&sens_max1);
-                        info!("[gatt] Read sensitivity max 1: {:?}", value);
-                    } else if value_handle == sens_min2.handle {
-                        let value = server.get(&sens_min2);
-                        info!("[gatt] Read sensitivity min 2: {:?}", value);
-                    } else if value_handle == sens_max2.handle {
-                        let value = server.get(&sens_max2);
-                        info!("[gatt] Read sensitivity max 2: {:?}", value);
+                    if value_handle == emg.handle {
+                        let value = server.get(&emg);
+                        info!("[gatt] Read EMG values: {:?}", value);
+                    } else if value_handle == sensitivity.handle {
+                        let value = server.get(&sensitivity);
+                        info!("[gatt] Read sensitivity: {:?}", value);
                     }
                 }
                 GattEvent::Write { value_handle } => {
-                    if value_handle == sens_min1.handle {
-                        let value = server.get(&sens_min1);
-                        info!("[gatt] New sensitivity min 1: {:?}", value);
-                    } else if value_handle == sens_max1.handle {
-                        let value = server.get(&sens_max1);
-                        info!("[gatt] New sensitivity max 1: {:?}", value);
-                    } else if value_handle == sens_min2.handle {
-                        let value = server.get(&sens_min2);
-                        info!("[gatt] New sensitivity min 2: {:?}", value);
-                    } else if value_handle == sens_max2.handle {
-                        let value = server.get(&sens_max2);
-                        info!("[gatt] New sensitivity max 2: {:?}", value);
+                    if value_handle == sensitivity.handle {
+                        if let Ok(value) = server.get(&sensitivity) {
+                            for (config, bytes) in CHANNEL_TABLE.iter().zip(value.chunks_exact(4)) {
+                                let min = u16::from_le_bytes([bytes[0], bytes[1]]);
+                                let max = u16::from_le_bytes([bytes[2], bytes[3]]);
+                                info!(
+                                    "[gatt] New sensitivity of {}: min {}, max {}",
+                                    config.name, min, max
+                                );
+                            }
+                        }
+                    } else if value_handle == filter_config.handle {
+                        if let Ok(bits) = server.get(&filter_config) {
+                            info!("[gatt] New filter configuration: {=u8:#x}", bits);
+                            FILTER_SETTINGS.signal(FilterSettings::from_bits(bits));
+                        }
+                    } else if value_handle == envelope_config.handle {
+                        match server
+                            .get(&envelope_config)
+                            .ok()
+                            .and_then(|bytes| EnvelopeSettings::from_bytes(&bytes))
+                        {
+                            Some(settings) => {
+                                info!("[gatt] New envelope configuration");
+                                ENVELOPE_SETTINGS.signal(settings);
+                            }
+                            None => info!("[gatt] Invalid envelope configuration"),
+                        }
+                    } else if value_handle == artifact_config.handle {
+                        match server
+                            .get(&artifact_config)
+                            .ok()
+                            .and_then(|value| ArtifactSensitivity::try_from(value).ok())
+                        {
+                            Some(sensitivity) => {
+                                info!("[gatt] New artifact sensitivity: {}", sensitivity as u8);
+                                ARTIFACT_SENSITIVITY.signal(sensitivity);
+                            }
+                            None => info!("[gatt] Invalid artifact sensitivity"),
+                        }
+                    } else if value_handle == error_rate_limit.handle {
+                        if let Ok(bytes) = server.get(&error_rate_limit) {
+                            let limit = u16::from_le_bytes(bytes);
+                            info!("[gatt] New error rate limit: {} per mille", limit);
+                            ERROR_RATE_LIMIT.store(limit, Ordering::Relaxed);
+                        }
+                    } else if value_handle == supply_limits.handle {
+                        if let Ok(bytes) = server.get(&supply_limits) {
+                            let low_battery = u16::from_le_bytes([bytes[0], bytes[1]]);
+                            let over_temperature = i16::from_le_bytes([bytes[2], bytes[3]]);
+                            info!(
+                                "[gatt] New supply limits: low battery {} mV, over-temperature {} C",
+                                low_battery,
+                                over_temperature as f32 / 10.0
+                            );
+                            LOW_BATTERY.store(low_battery, Ordering::Relaxed);
+                            OVER_TEMPERATURE.store(over_temperature, Ordering::Relaxed);
+                        }
+                    } else if value_handle == stage_request.handle {
+                        match server
+                            .get(&stage_request)
+                            .ok()
+                            .and_then(|value| UserRequest::try_from(value).ok())
+                        {
+                            Some(request) => {
+                                info!("[gatt] Stage change requested: {}", request);
+                                EVENT_CHANNEL.send(Events::UserRequest { request }).await;
+                            }
+                            None => info!("[gatt] Invalid stage request"),
+                        }
+                    } else if value_handle == crash_control.handle {
+                        if let Ok(index) = server.get(&crash_control) {
+                            if index == CLEAR_CRASH_LOG {
+                                if let Some(log) = CRASH_LOG.lock().await.as_mut() {
+                                    log.clear();
+                                }
+                                info!("[gatt] Crash log cleared");
+                                select_crash_record(server, 0).await;
+                            } else {
+                                info!("[gatt] Crash record {} selected", index);
+                                select_crash_record(server, index).await;
+                            }
+                        }
+                    } else if value_handle == heartbeat_timeout.handle {
+                        if let Ok(bytes) = server.get(&heartbeat_timeout) {
+                            let timeout = u16::from_le_bytes(bytes).max(MIN_HEARTBEAT_TIMEOUT);
+                            info!("[gatt] New heartbeat timeout: {} ms", timeout);
+                            HEARTBEAT_TIMEOUT.store(timeout, Ordering::Relaxed);
+                            // Reads show the timeout in effect
+                            if server
+                                .set(&heartbeat_timeout, &timeout.to_le_bytes())
+                                .is_err()
+                            {
+                                info!("[gatt] Failed to update heartbeat timeout");
+                            }
+                        }
+                    } else if value_handle == emergency_stop.handle {
+                        match server.get(&emergency_stop) {
+                            Ok(0) => estop::clear(estop::Source::Ble),
+                            Ok(_) => estop::trigger(estop::Source::Ble),
+                            Err(_) => info!("[gatt] Invalid emergency stop value"),
+                        }
+                        // A refused clear leaves the latch set, reads show it
+                        let latched = estop::is_latched() as u8;
+                        if server.set(&emergency_stop, &latched).is_err() {
+                            info!("[gatt] Failed to update emergency stop");
+                        }
+                    } else if value_handle == force_limits.handle {
+                        if let Ok(mut bytes) = server.get(&force_limits) {
+                            for (sensor, chunk) in bytes.chunks_exact_mut(2).enumerate() {
+                                let limit = u16::from_le_bytes([chunk[0], chunk[1]])
+                                    .clamp(MIN_FORCE_LIMIT, MAX_FORCE_LIMIT);
+                                info!("[gatt] New force limit of sensor {}: {}", sensor, limit);
+                                FORCE_LIMITS[sensor].store(limit, Ordering::Relaxed);
+                                chunk.copy_from_slice(&limit.to_le_bytes());
+                            }
+                            // Reads show the limits in effect
+                            if server.set(&force_limits, &bytes).is_err() {
+                                info!("[gatt] Failed to update force limits");
+                            }
+                        }
+                    } else if value_handle == joint_limits.handle {
+                        match server
+                            .get(&joint_limits)
+                            .ok()
+                            .and_then(|bytes| JointLimits::from_bytes(&bytes))
+                        {
+                            Some(limits) => {
+                                info!("[gatt] New joint limits");
+                                JOINT_MODEL.lock().await.limits = limits;
+                            }
+                            None => info!("[gatt] Invalid joint limits"),
+                        }
                     }
                 }
             },
@@ -298,15 +818,16 @@ async fn conn_task<C: Controller>(
     Ok(())
 }
 
+/// Advertises for one `ADVERTISING_ROUND`, `None` if nobody connected meanwhile
 async fn advertise<'a, C: Controller>(
     name: &'a str,
     peripheral: &mut Peripheral<'a, C>,
-) -> Result<Connection<'a>, BleHostError<C::Error>> {
+) -> Result<Option<Connection<'a>>, BleHostError<C::Error>> {
     let mut advertiser_data = [0; 31];
     AdStructure::encode_slice(
         &[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::ServiceUuids16(&[Uuid::Uuid16([0x15, 0x18])]),
+            AdStructure::ServiceUuids16(&[Uuid::Uuid16([0x15, 0x18]), Uuid::Uuid16([0x0F, 0x18])]),
             AdStructure::CompleteLocalName(name.as_bytes()),
         ],
         &mut advertiser_data[..],
@@ -320,8 +841,13 @@ async fn advertise<'a, C: Controller>(
             },
         )
         .await?;
-    info!("[adv] advertising");
-    let conn = advertiser.accept().await?;
+    // The controller took the advertising commands
+    watchdog::check_in(Supervised::Bluetooth);
+    debug!("[adv] advertising");
+    let Ok(conn) = with_timeout(ADVERTISING_ROUND, advertiser.accept()).await else {
+        return Ok(None);
+    };
+    let conn = conn?;
     info!("[adv] connection established");
-    Ok(conn)
+    Ok(Some(conn))
 }
@@ -15,6 +15,9 @@ macro_rules! define_commands {
         }
 
         impl CommandType {
+            /// Bit `n` set for the command with code `n`
+            pub const BITMAP: u32 = 0 $(| (1 << $code))*;
+
             const fn max_payload_size(&self) -> u8 {
                 match self {
                     $(
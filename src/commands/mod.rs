@@ -1,5 +1,6 @@
 mod define_command;
 use core::sync::atomic::Ordering;
+pub use picow_logic::device_info;
 
 use define_command::define_commands;
 use defmt::Format;
@@ -38,8 +39,46 @@ define_commands! {
     SetSpeed = 0x08, 2,
 
     /// Service and Configuration Commands
+    /// Asks for a `ResponseDeviceInfo`, sent by either side
     GetDeviceInfo = 0x10, 0,
+    /// Supply and temperature of the EMG controller
+    /// Payload: 6 bytes (VSYS in mV, temperature in 0.1 °C, battery percent, alarm bits)
+    ReportStatus = 0x11, 6,
+    /// Lock or unlock request from the hand, e.g. its button
+    /// Payload: 1 byte (1 to lock, 0 to unlock)
+    LockRequest = 0x12, 1,
+    /// Why the EMG controller last reset, sent at boot
+    /// Payload: 2 bytes (cause, task that missed its deadline or 0xFF)
+    ResetReport = 0x13, 2,
+    /// Requests a stored crash record
+    /// Payload: 1 byte (record index)
+    RequestCrashLog = 0x14, 1,
+    /// Part of a crash record, sent in order until the record is complete
+    /// Payload: record index, records stored, byte offset, up to 29 record bytes.
+    /// Only the first two when the record does not exist.
+    CrashReport = 0x15, 32,
+    /// Stops every motor at once and latches until `ClearEmergencyStop`, sent by
+    /// either side
     EmergencyStop = 0x16, 0,
+    /// Erases every stored crash record
+    ClearCrashLog = 0x17, 0,
+    /// Sent periodically, the hand answers with `HeartbeatAck` and the same request id
+    Heartbeat = 0x18, 0,
+    HeartbeatAck = 0x19, 0,
+    /// Releases a latched `EmergencyStop`, sent by either side. Each side only
+    /// releases a stop it sent itself.
+    ClearEmergencyStop = 0x1A, 0,
+    /// Answer to `GetDeviceInfo`
+    /// Payload: 18 bytes (protocol major and minor, firmware major, minor and patch,
+    /// hardware revision, 8 byte serial, u32 bitmap of supported command codes)
+    ResponseDeviceInfo = 0x1B, 18,
+}
+
+impl CommandType {
+    /// Commands that make the hand move
+    pub fn moves(self) -> bool {
+        matches!(self, Self::SetPosition | Self::StartMotion | Self::SetSpeed)
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,198 @@
+use core::{
+    f32::consts::PI,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{
+    emg::EMG_CHANNELS,
+    filters::EMG::{NotchFrequency, SampleFrequency},
+};
+
+/// Evaluation windows per second, giving a 10 Hz Goertzel bin spacing
+const WINDOWS_PER_SECOND: u32 = 10;
+/// Fraction of the full scale treated as "at the rail"
+const SATURATION_MARGIN: f32 = 0.02;
+/// Fraction of a window's samples that must be at a rail to count as saturated
+const SATURATION_FRACTION: f32 = 0.5;
+/// Largest accepted distance of the window mean from mid-scale, as a fraction of half the range
+const DC_OFFSET_LIMIT: f32 = 0.6;
+/// Mains power above this multiple of the filtered EMG power indicates a floating electrode
+const HUM_RATIO_LIMIT: f32 = 10.0;
+/// Mains amplitude, as a fraction of the full scale, below which hum is never a fault
+const HUM_AMPLITUDE_FLOOR: f32 = 0.01;
+/// Consecutive bad windows before contact is reported lost (300 ms)
+const LOST_WINDOWS: u8 = 3;
+/// Consecutive good windows before contact is reported restored (500 ms)
+const RESTORED_WINDOWS: u8 = 5;
+
+/// Reason an electrode was judged to have lost skin contact
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ContactFault {
+    /// Signal stuck at one of the converter rails
+    Saturation = 1,
+    /// Signal far from the front end's bias level
+    DcOffset = 2,
+    /// Mains pickup dominates the EMG band
+    MainsHum = 3,
+    /// Front end's own lead-off comparator tripped
+    LeadOff = 4,
+}
+
+/// Contact state per channel: 0 when in contact, otherwise the `ContactFault` code
+pub static CONTACT_STATUS: [AtomicU8; EMG_CHANNELS] = [const { AtomicU8::new(0) }; EMG_CHANNELS];
+
+/// Whether every electrode currently has skin contact
+pub fn all_in_contact() -> bool {
+    CONTACT_STATUS
+        .iter()
+        .all(|status| status.load(Ordering::Relaxed) == 0)
+}
+
+pub enum ContactChange {
+    Lost(ContactFault),
+    Restored,
+}
+
+/// Judges electrode contact from raw and filtered samples, one window at a time.
+pub struct ContactDetector {
+    min: i32,
+    max: i32,
+    window_len: u32,
+    goertzel_coef: f32,
+
+    count: u32,
+    sum: i64,
+    saturated: u32,
+    filtered_sq: f32,
+    s1: f32,
+    s2: f32,
+    lead_off: bool,
+
+    fault: Option<ContactFault>,
+    streak: u8,
+}
+
+impl ContactDetector {
+    /// `min` and `max` are the raw sample codes of the converter rails.
+    pub fn new(min: i32, max: i32, sample_freq: SampleFrequency, mains: NotchFrequency) -> Self {
+        let mut detector = Self {
+            min,
+            max,
+            window_len: sample_freq as u32 / WINDOWS_PER_SECOND,
+            goertzel_coef: 0.0,
+            count: 0,
+            sum: 0,
+            saturated: 0,
+            filtered_sq: 0.0,
+            s1: 0.0,
+            s2: 0.0,
+            lead_off: false,
+            fault: None,
+            streak: 0,
+        };
+        detector.set_mains(sample_freq, mains);
+        detector
+    }
+
+    /// Retunes the hum detector and restarts the current window.
+    pub fn set_mains(&mut self, sample_freq: SampleFrequency, mains: NotchFrequency) {
+        let omega = 2.0 * PI * mains as u32 as f32 / sample_freq as u32 as f32;
+        self.goertzel_coef = 2.0 * libm::cosf(omega);
+        self.clear_window();
+    }
+
+    /// Hardware lead-off flag, held until the end of the current window
+    pub fn set_lead_off(&mut self, lead_off: bool) {
+        self.lead_off |= lead_off;
+    }
+
+    pub fn update(&mut self, raw: i32, filtered: i32) -> Option<ContactChange> {
+        let range = (self.max - self.min) as f32;
+        let margin = (range * SATURATION_MARGIN) as i32;
+        if raw <= self.min + margin || raw >= self.max - margin {
+            self.saturated += 1;
+        }
+
+        self.sum += raw as i64;
+        self.filtered_sq += filtered as f32 * filtered as f32;
+
+        let s0 = raw as f32 + self.goertzel_coef * self.s1 - self.s2;
+        self.s2 = self.s1;
+        self.s1 = s0;
+
+        self.count += 1;
+        if self.count < self.window_len {
+            return None;
+        }
+
+        let fault = self.evaluate();
+        self.clear_window();
+        self.debounce(fault)
+    }
+
+    fn evaluate(&self) -> Option<ContactFault> {
+        let n = self.count as f32;
+        let range = (self.max - self.min) as f32;
+
+        if self.lead_off {
+            return Some(ContactFault::LeadOff);
+        }
+
+        if self.saturated as f32 >= n * SATURATION_FRACTION {
+            return Some(ContactFault::Saturation);
+        }
+
+        let mid = (self.max + self.min) as f32 / 2.0;
+        let mean = self.sum as f32 / n;
+        if libm::fabsf(mean - mid) > range / 2.0 * DC_OFFSET_LIMIT {
+            return Some(ContactFault::DcOffset);
+        }
+
+        // The window holds a whole number of mains periods, so the bin
+        // rejects the DC level; 2|X|^2 / N^2 is the tone's mean square
+        let magnitude_sq =
+            self.s1 * self.s1 + self.s2 * self.s2 - self.goertzel_coef * self.s1 * self.s2;
+        let hum_power = 2.0 * magnitude_sq / (n * n);
+        let emg_power = self.filtered_sq / n;
+        let floor = range * HUM_AMPLITUDE_FLOOR;
+        if hum_power > floor * floor / 2.0 && hum_power > emg_power * HUM_RATIO_LIMIT {
+            return Some(ContactFault::MainsHum);
+        }
+
+        None
+    }
+
+    /// Only reports a change once the new state held for several windows.
+    fn debounce(&mut self, fault: Option<ContactFault>) -> Option<ContactChange> {
+        let changing = fault.is_some() != self.fault.is_some();
+        if !changing {
+            self.streak = 0;
+            return None;
+        }
+
+        self.streak += 1;
+        match fault {
+            Some(fault) if self.streak >= LOST_WINDOWS => {
+                self.streak = 0;
+                self.fault = Some(fault);
+                Some(ContactChange::Lost(fault))
+            }
+            None if self.streak >= RESTORED_WINDOWS => {
+                self.streak = 0;
+                self.fault = None;
+                Some(ContactChange::Restored)
+            }
+            _ => None,
+        }
+    }
+
+    fn clear_window(&mut self) {
+        self.count = 0;
+        self.sum = 0;
+        self.saturated = 0;
+        self.filtered_sq = 0.0;
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+        self.lead_off = false;
+    }
+}
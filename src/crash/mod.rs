@@ -0,0 +1,165 @@
+//! Panic records that survive the reset.
+//!
+//! The panic handler only writes RAM, which is left alone by the reset and
+//! not initialized by the runtime. `init` moves the record to the last flash
+//! sector on the next boot, where it stays until cleared over UART or BLE.
+
+pub use picow_logic::record;
+
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::peripheral::SCB;
+use cortex_m::register::{lr, msp, pc};
+use defmt::*;
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::pac;
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::watchdog;
+use record::{CrashRecord, DecodeError, RECORD_LEN};
+
+/// Flash of the Pico W
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+pub type BlockingFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+/// Offset of the crash log sector, kept out of the program by `memory.x`
+const LOG_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+/// Records the sector holds, the oldest are erased when it is full
+pub const LOG_SLOTS: usize = ERASE_SIZE / RECORD_LEN;
+/// End of the striped and scratch RAM banks
+const RAM_END: usize = 0x2004_2000;
+
+/// Record of the last panic, valid only if it decodes
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<[u8; RECORD_LEN]> = MaybeUninit::uninit();
+
+/// Set when the previous run ended in a panic
+pub static CRASHED: AtomicBool = AtomicBool::new(false);
+
+pub static CRASH_LOG: Mutex<CriticalSectionRawMutex, Option<CrashLog>> = Mutex::new(None);
+
+/// Crash records in the last flash sector, stored one after another from its
+/// start, erased slots read as all ones.
+pub struct CrashLog {
+    flash: BlockingFlash,
+}
+
+impl CrashLog {
+    fn slot_offset(slot: usize) -> u32 {
+        LOG_OFFSET + (slot * RECORD_LEN) as u32
+    }
+
+    /// Raw record in `slot`, also when it does not decode so that a damaged
+    /// one can still be inspected
+    pub fn raw(&mut self, slot: usize) -> Option<[u8; RECORD_LEN]> {
+        if slot >= LOG_SLOTS {
+            return None;
+        }
+        let mut bytes = [0; RECORD_LEN];
+        if let Err(e) = self
+            .flash
+            .blocking_read(Self::slot_offset(slot), &mut bytes)
+        {
+            warn!("Failed to read crash record {}: {}", slot, e);
+            return None;
+        }
+        bytes.iter().any(|&byte| byte != 0xFF).then_some(bytes)
+    }
+
+    /// Number of stored records
+    pub fn count(&mut self) -> usize {
+        (0..LOG_SLOTS)
+            .find(|&slot| self.raw(slot).is_none())
+            .unwrap_or(LOG_SLOTS)
+    }
+
+    fn append(&mut self, record: &CrashRecord) {
+        let mut slot = self.count();
+        if slot == LOG_SLOTS {
+            warn!("Crash log full, dropping the older records");
+            self.clear();
+            slot = 0;
+        }
+        if let Err(e) = self
+            .flash
+            .blocking_write(Self::slot_offset(slot), &record.encode())
+        {
+            warn!("Failed to store crash record: {}", e);
+        }
+    }
+
+    /// Erases the sector, core 1 is paused meanwhile
+    pub fn clear(&mut self) {
+        let result = self
+            .flash
+            .blocking_erase(LOG_OFFSET, LOG_OFFSET + ERASE_SIZE as u32);
+        // No task made progress during the erase
+        watchdog::excuse_stall();
+        if let Err(e) = result {
+            warn!("Failed to clear crash log: {}", e);
+        }
+    }
+}
+
+/// Moves the record of a panic in the previous run to flash.
+///
+/// Runs before core 1 is started, so flash can be written without pausing it.
+pub async fn init(flash: BlockingFlash) {
+    let mut log = CrashLog { flash };
+
+    let ram = unsafe { addr_of_mut!(PANIC_RECORD).cast::<[u8; RECORD_LEN]>() };
+    match CrashRecord::decode(&unsafe { ram.read_volatile() }) {
+        Ok(record) => {
+            error!("Previous run panicked: {}", record);
+            CRASHED.store(true, Ordering::Relaxed);
+            log.append(&record);
+        }
+        Err(DecodeError::Empty) => {}
+        // RAM holds noise after power-on
+        Err(e) => debug!("No crash record in RAM: {}", e),
+    }
+    unsafe { ram.write_volatile([0; RECORD_LEN]) };
+
+    info!("{} crash records stored", log.count());
+    *CRASH_LOG.lock().await = Some(log);
+}
+
+/// Records the panic for the next boot, logs it for an attached debugger and
+/// resets both cores.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    let mut record = CrashRecord::new();
+    record.core = pac::SIO.cpuid().read() as u8;
+    record.uptime_ms = Instant::now().as_millis() as u32;
+    record.sp = msp::read();
+    record.lr = lr::read();
+    record.pc = pc::read();
+    for (index, word) in record.stack.iter_mut().enumerate() {
+        let address = record.sp as usize + index * 4;
+        if address + 4 <= RAM_END {
+            *word = unsafe { (address as *const u32).read_volatile() };
+        }
+    }
+    if let Some(location) = info.location() {
+        record.set_file(location.file());
+        record.line = location.line();
+    }
+    let _ = write!(record, "{}", info.message());
+
+    unsafe {
+        addr_of_mut!(PANIC_RECORD)
+            .cast::<[u8; RECORD_LEN]>()
+            .write_volatile(record.encode())
+    };
+
+    error!("{}", Display2Format(info));
+    SCB::sys_reset()
+}
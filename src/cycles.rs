@@ -0,0 +1,80 @@
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use defmt::info;
+use embassy_rp::{clocks::clk_sys_freq, pac};
+
+/// SysTick is a 24 bit down-counter
+const SYST_MASK: u32 = 0x00FF_FFFF;
+/// Measurements summarised per report (10 s of samples at 500 Hz)
+const REPORT_INTERVAL: u32 = 5000;
+
+/// Starts SysTick as a free-running cycle counter on the calling core.
+///
+/// Every core has its own SysTick, so this has to run on each core that
+/// measures cycles.
+pub fn init() {
+    let mut syst = unsafe { cortex_m::Peripherals::steal() }.SYST;
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(SYST_MASK);
+    syst.clear_current();
+    syst.enable_counter();
+}
+
+pub fn now() -> u32 {
+    SYST::get_current()
+}
+
+/// Cycles from `start` until now, valid for spans below 2^24 cycles
+pub fn since(start: u32) -> u32 {
+    start.wrapping_sub(now()) & SYST_MASK
+}
+
+/// Per-sample cycle statistics of one piece of work, logged periodically.
+pub struct CycleStats {
+    name: &'static str,
+    count: u32,
+    total: u64,
+    max: u32,
+}
+
+impl CycleStats {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: 0,
+            total: 0,
+            max: 0,
+        }
+    }
+
+    /// Adds `cycles` spent on `samples` samples. The maximum is the largest
+    /// per-sample average of a single call.
+    pub fn record(&mut self, cycles: u32, samples: u32) {
+        if samples == 0 {
+            return;
+        }
+
+        self.count += samples;
+        self.total += cycles as u64;
+        self.max = self.max.max(cycles / samples);
+
+        if self.count >= REPORT_INTERVAL {
+            self.report();
+            *self = Self::new(self.name);
+        }
+    }
+
+    fn report(&self) {
+        let core = pac::SIO.cpuid().read();
+        let mean = (self.total / self.count as u64) as u32;
+        let cycles_per_us = clk_sys_freq() / 1_000_000;
+        info!(
+            "Core {} {}: {} cycles ({} us) per sample on average, {} at most, over {} samples",
+            core,
+            self.name,
+            mean,
+            mean / cycles_per_us,
+            self.max,
+            self.count
+        );
+    }
+}
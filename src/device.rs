@@ -0,0 +1,110 @@
+use core::sync::atomic::Ordering;
+
+use defmt::*;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Timer};
+use portable_atomic::AtomicU64;
+
+use crate::{
+    commands::{
+        device_info::{DeviceInfo, PROTOCOL_MAJOR, PROTOCOL_MINOR},
+        CommandType, Packet,
+    },
+    crash::BlockingFlash,
+    state::events::{Events, EVENT_CHANNEL},
+};
+
+/// Revision of the EMG controller board
+const HARDWARE_REVISION: u8 = 1;
+/// Longest wait for the hand to answer `GetDeviceInfo`
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+/// Interval at which an unanswered query is repeated, the hand may boot later
+const RETRY_PERIOD: Duration = Duration::from_secs(2);
+
+/// Unique id of the flash chip, our serial number
+static SERIAL: AtomicU64 = AtomicU64::new(0);
+
+/// Info the hand controller sent, `None` until it answered
+pub static HAND_INFO: Mutex<CriticalSectionRawMutex, Option<DeviceInfo>> = Mutex::new(None);
+/// `ResponseDeviceInfo` packets from the hand
+pub static HAND_RESPONSES: Signal<CriticalSectionRawMutex, DeviceInfo> = Signal::new();
+
+/// Reads the serial number, before core 1 is started
+pub fn read_serial(flash: &mut BlockingFlash) {
+    let mut id = [0; 8];
+    match flash.blocking_unique_id(&mut id) {
+        Ok(()) => SERIAL.store(u64::from_le_bytes(id), Ordering::Relaxed),
+        Err(e) => warn!("Failed to read the flash unique id: {}", e),
+    }
+}
+
+/// What this controller reports about itself
+pub fn own_info() -> DeviceInfo {
+    let version = |part: &str| part.parse().unwrap_or(0);
+    DeviceInfo {
+        protocol_major: PROTOCOL_MAJOR,
+        protocol_minor: PROTOCOL_MINOR,
+        firmware: [
+            version(env!("CARGO_PKG_VERSION_MAJOR")),
+            version(env!("CARGO_PKG_VERSION_MINOR")),
+            version(env!("CARGO_PKG_VERSION_PATCH")),
+        ],
+        hardware_revision: HARDWARE_REVISION,
+        serial: SERIAL.load(Ordering::Relaxed).to_le_bytes(),
+        commands: CommandType::BITMAP,
+    }
+}
+
+/// Stores the hand's info and tells the orchestrator when its compatibility changed
+async fn identify_hand(info: DeviceInfo) {
+    if info.compatible() {
+        info!("Hand controller: {}", info);
+    } else {
+        error!(
+            "Hand controller speaks protocol {}.{}, we speak {}.{}, operation refused",
+            info.protocol_major, info.protocol_minor, PROTOCOL_MAJOR, PROTOCOL_MINOR
+        );
+    }
+
+    let previous = HAND_INFO.lock().await.replace(info);
+    // Operation waits for the first answer, later ones only matter on a change
+    let report = match previous {
+        None => true,
+        Some(previous) => previous.compatible() != info.compatible(),
+    };
+    if report {
+        EVENT_CHANNEL
+            .send(Events::HandIdentified {
+                compatible: info.compatible(),
+            })
+            .await;
+    }
+}
+
+/// Queries the hand controller until it answers, then follows the info it
+/// sends after restarting.
+#[embassy_executor::task]
+pub async fn device_info_task() {
+    info!("Device info exchange started!");
+
+    loop {
+        if let Some(packet) = Packet::with_payload(CommandType::GetDeviceInfo, &[]) {
+            packet.send().await;
+        }
+        match with_timeout(QUERY_TIMEOUT, HAND_RESPONSES.wait()).await {
+            Ok(info) => {
+                identify_hand(info).await;
+                break;
+            }
+            Err(_) => {
+                debug!("Hand controller did not answer GetDeviceInfo");
+                Timer::after(RETRY_PERIOD).await;
+            }
+        }
+    }
+
+    loop {
+        let info = HAND_RESPONSES.wait().await;
+        identify_hand(info).await;
+    }
+}
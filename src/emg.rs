@@ -1,82 +1,366 @@
-use core::sync::atomic::{AtomicI32, Ordering};
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use defmt::*;
-use embassy_rp::adc::{Adc, Async, Channel as AdcChannel};
-use embassy_time::{Duration, Ticker};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
 
-use crate::filters::{
-    mean::MovingAvg,
-    EMG::{EMGFilters, NotchFrequency, SampleFrequency},
+use crate::{
+    adc::{BLOCK_LEN, SAMPLE_BLOCKS, SAMPLE_MAX, SAMPLE_MIN},
+    contact::{ContactChange, ContactDetector, CONTACT_STATUS},
+    cycles::{self, CycleStats},
+    fatigue::{FatigueWindow, FATIGUE_WINDOWS},
+    filters::{
+        artifact::{ArtifactDetector, ArtifactSensitivity},
+        envelope::{Envelope, EnvelopeSettings},
+        spectrum::FFT_SIZE,
+        EMG::{EMGFilters, FilterSettings, SampleFrequency},
+    },
+    health::{ChannelHealth, HealthChange, ERROR_RATE_LIMIT, SAMPLE_ERRORS},
+    ring::SampleRing,
+    state::events::{Events, EVENT_CHANNEL},
+    watchdog::{self, Supervised},
 };
 
-pub static EMG1_VALUE: AtomicI32 = AtomicI32::new(0);
-pub static EMG2_VALUE: AtomicI32 = AtomicI32::new(0);
+pub const SAMPLE_FREQUENCY: SampleFrequency = SampleFrequency::Freq500Hz;
+pub const EMG_CHANNELS: usize = 2;
+
+/// Describes one logical EMG channel
+pub struct ChannelConfig {
+    /// Name used in logs
+    pub name: &'static str,
+    /// Position of the channel in an acquisition frame
+    pub frame_index: usize,
+    /// ADC input the electrode is wired to, on GPIO 26 + `adc_input`
+    #[cfg(not(feature = "ads129x"))]
+    pub adc_input: u8,
+}
+
+/// Logical EMG channels. Acquisition frames are in ascending ADC input order,
+/// the order the round-robin converts them in.
+#[cfg(not(feature = "ads129x"))]
+pub const CHANNEL_TABLE: [ChannelConfig; EMG_CHANNELS] = [
+    ChannelConfig {
+        name: "EMG1",
+        frame_index: 1,
+        adc_input: 1,
+    },
+    ChannelConfig {
+        name: "EMG2",
+        frame_index: 0,
+        adc_input: 0,
+    },
+];
+
+/// Logical EMG channels. Acquisition frames are in ADS129x channel order.
+#[cfg(feature = "ads129x")]
+pub const CHANNEL_TABLE: [ChannelConfig; EMG_CHANNELS] = [
+    ChannelConfig {
+        name: "EMG1",
+        frame_index: 0,
+    },
+    ChannelConfig {
+        name: "EMG2",
+        frame_index: 1,
+    },
+];
+
+/// Processed samples kept for consumers (2 s at 500 Hz)
+pub const SAMPLE_HISTORY: usize = 1024;
+
+/// Envelope values of all channels at one sampling instant
+#[derive(Clone, Copy)]
+pub struct EmgSample {
+    /// Frame index since acquisition started
+    pub index: u32,
+    pub timestamp: Instant,
+    pub values: [i32; EMG_CHANNELS],
+}
+
+/// Every processed sample, written by `emg_processing_task` only
+pub static EMG_SAMPLES: SampleRing<EmgSample, SAMPLE_HISTORY> = SampleRing::new();
+
+/// New filter settings, applied to every sensor on the next sample
+pub static FILTER_SETTINGS: Signal<CriticalSectionRawMutex, FilterSettings> = Signal::new();
+/// New envelope follower settings, applied to every sensor on the next sample
+pub static ENVELOPE_SETTINGS: Signal<CriticalSectionRawMutex, EnvelopeSettings> = Signal::new();
+/// New artifact rejection sensitivity, applied to every sensor on the next sample
+pub static ARTIFACT_SENSITIVITY: Signal<CriticalSectionRawMutex, ArtifactSensitivity> =
+    Signal::new();
+
+/// Number of separate artifacts rejected per channel
+pub static ARTIFACT_EPISODES: [AtomicU32; EMG_CHANNELS] =
+    [const { AtomicU32::new(0) }; EMG_CHANNELS];
+/// Number of samples kept out of the envelope per channel
+pub static BLANKED_SAMPLES: [AtomicU32; EMG_CHANNELS] = [const { AtomicU32::new(0) }; EMG_CHANNELS];
 
 #[embassy_executor::task]
-pub async fn emg_reading_task(
-    mut adc: Adc<'static, Async>,
-    mut emg1: EMGSensor<'static>,
-    mut emg2: EMGSensor<'static>,
-) {
-    info!("EMG reading task started!");
-    let mut ticker = Ticker::every(Duration::from_micros(2000));
+pub async fn emg_processing_task(mut sensors: [EMGSensor; EMG_CHANNELS]) {
+    info!("EMG processing task started!");
+    let receiver = SAMPLE_BLOCKS.receiver();
+    let mut samples = unwrap!(EMG_SAMPLES.writer());
+    let frame_period = Duration::from_micros(1_000_000 / SAMPLE_FREQUENCY as u64);
+    let mut expected_sample: Option<u32> = None;
+    let mut processing_cycles = CycleStats::new("EMG processing");
 
     loop {
-        ticker.next().await;
+        let block = receiver.receive().await;
+        watchdog::check_in(Supervised::Processing);
+
+        if let Some(expected) = expected_sample {
+            if block.first_sample != expected {
+                warn!(
+                    "Gap in EMG samples: expected frame {}, got {}",
+                    expected, block.first_sample
+                );
+            }
+        }
+        expected_sample = Some(block.first_sample.wrapping_add(BLOCK_LEN as u32));
+
+        if let Some(settings) = FILTER_SETTINGS.try_take() {
+            info!("Applying new filter settings: {=u8:#x}", settings.to_bits());
+            for sensor in sensors.iter_mut() {
+                sensor.configure(settings);
+            }
+        }
+
+        if let Some(settings) = ENVELOPE_SETTINGS.try_take() {
+            info!(
+                "Applying new envelope settings: kind {}, attack {} ms, release {} ms",
+                settings.kind as u8, settings.attack_ms, settings.release_ms
+            );
+            for sensor in sensors.iter_mut() {
+                sensor.set_envelope(settings);
+            }
+        }
 
-        let emg1_data = emg1.read(&mut adc).await.unwrap();
-        let emg2_data = emg2.read(&mut adc).await.unwrap();
-        // info!("Emg1: {}, Emg2: {}", emg1_data, emg2_data);
+        if let Some(sensitivity) = ARTIFACT_SENSITIVITY.try_take() {
+            info!("Applying new artifact sensitivity: {}", sensitivity as u8);
+            for sensor in sensors.iter_mut() {
+                sensor.set_artifact_sensitivity(sensitivity);
+            }
+        }
 
-        EMG1_VALUE.store(emg1_data, Ordering::Relaxed);
-        EMG2_VALUE.store(emg2_data, Ordering::Relaxed);
+        for (channel, sensor) in sensors.iter_mut().enumerate() {
+            let lead_off = block.lead_off & (1 << CHANNEL_TABLE[channel].frame_index) != 0;
+            sensor.set_lead_off(lead_off);
+        }
+
+        for (offset, frame) in block.samples.iter().enumerate() {
+            let start = cycles::now();
+            let mut sample = EmgSample {
+                index: block.first_sample.wrapping_add(offset as u32),
+                timestamp: block.started_at + frame_period * offset as u32,
+                values: [0; EMG_CHANNELS],
+            };
+            for (channel, sensor) in sensors.iter_mut().enumerate() {
+                let position = CHANNEL_TABLE[channel].frame_index;
+                let failed = block.faults[offset] & (1 << position) != 0;
+                sample.values[channel] = sensor.process(frame[position], failed);
+            }
+            samples.push(sample);
+            processing_cycles.record(cycles::since(start), 1);
+        }
     }
 }
 
-pub struct EMGSensor<'a> {
+pub struct EMGSensor {
+    channel: usize,
     filter: EMGFilters,
-    avg: MovingAvg<250>,
-    pin: AdcChannel<'a>,
+    envelope: Envelope,
+    contact: ContactDetector,
+    artifacts: ArtifactDetector,
+    health: ChannelHealth,
+    last_envelope: i32,
+    window: [f32; FFT_SIZE],
+    window_len: usize,
 }
 
-impl<'a> EMGSensor<'a> {
-    pub fn new(pin: AdcChannel<'a>) -> Self {
+impl EMGSensor {
+    pub fn new(channel: usize) -> Self {
+        let settings = FilterSettings::default();
         let mut filter = EMGFilters::new();
-        filter.init(
-            SampleFrequency::Freq500Hz,
-            NotchFrequency::Freq50Hz,
-            true,
-            true,
-            true,
-        );
+        filter.init(SAMPLE_FREQUENCY, settings);
 
         Self {
+            channel,
             filter,
-            avg: MovingAvg::new(true),
-            pin,
+            envelope: Envelope::new(EnvelopeSettings::default(), SAMPLE_FREQUENCY, 0),
+            contact: ContactDetector::new(
+                SAMPLE_MIN,
+                SAMPLE_MAX,
+                SAMPLE_FREQUENCY,
+                settings.notch_freq,
+            ),
+            artifacts: ArtifactDetector::new(
+                SAMPLE_MIN,
+                SAMPLE_MAX,
+                SAMPLE_FREQUENCY,
+                ArtifactSensitivity::default(),
+            ),
+            health: ChannelHealth::new((SAMPLE_MIN + SAMPLE_MAX) / 2, SAMPLE_FREQUENCY),
+            last_envelope: 0,
+            window: [0.0; FFT_SIZE],
+            window_len: 0,
+        }
+    }
+
+    pub fn configure(&mut self, settings: FilterSettings) {
+        self.filter.configure(settings);
+        self.contact
+            .set_mains(SAMPLE_FREQUENCY, settings.notch_freq);
+    }
+
+    pub fn set_artifact_sensitivity(&mut self, sensitivity: ArtifactSensitivity) {
+        self.artifacts.set_sensitivity(sensitivity);
+    }
+
+    pub fn set_lead_off(&mut self, lead_off: bool) {
+        self.contact.set_lead_off(lead_off);
+    }
+
+    /// Swaps the envelope follower, starting from the current envelope value.
+    pub fn set_envelope(&mut self, settings: EnvelopeSettings) {
+        self.envelope = Envelope::new(settings, SAMPLE_FREQUENCY, self.last_envelope);
+    }
+
+    /// Processes one conversion, `failed` conversions are replaced by the
+    /// last good sample.
+    pub fn process(&mut self, sample: i32, failed: bool) -> i32 {
+        let sample = self.check_health(sample, failed);
+        let filtered_value = self.filter.update(sample);
+        self.check_contact(sample, filtered_value);
+
+        // Blanked samples hold the envelope where it was before the artifact
+        if self.reject_artifact(sample, filtered_value) {
+            return self.last_envelope;
+        }
+
+        self.collect_window(filtered_value);
+        self.last_envelope = self.envelope.update(filtered_value);
+
+        self.last_envelope
+    }
+
+    /// Returns whether the sample is part of an artifact and must be blanked.
+    fn reject_artifact(&mut self, sample: i32, filtered_value: i32) -> bool {
+        let (started, blanked) = self.artifacts.update(sample, filtered_value);
+        if !blanked {
+            return false;
+        }
+
+        let stats = self.artifacts.stats();
+        if let Some(kind) = started {
+            debug!(
+                "Artifact on {}: {} (clipped {}, amplitude {}, slope {}, baseline {})",
+                CHANNEL_TABLE[self.channel].name,
+                kind,
+                stats.clipped,
+                stats.amplitude,
+                stats.slope,
+                stats.baseline
+            );
+            // The spectrum of a window spanning the gap would be meaningless
+            self.window_len = 0;
         }
+
+        ARTIFACT_EPISODES[self.channel].store(stats.episodes, Ordering::Relaxed);
+        BLANKED_SAMPLES[self.channel].store(stats.blanked_samples, Ordering::Relaxed);
+        true
     }
 
-    pub async fn read(&mut self, adc: &mut Adc<'_, Async>) -> Option<i32> {
-        let adc_value = adc.read(&mut self.pin).await.ok()?;
-        let filtered_value = self.filter.update(adc_value as i32);
-        let filtered_value = filtered_value * filtered_value;
-        let avg_value = self.avg.reading(filtered_value);
+    /// Substitutes failed conversions and publishes sensor fault changes.
+    fn check_health(&mut self, sample: i32, failed: bool) -> i32 {
+        let limit = ERROR_RATE_LIMIT.load(Ordering::Relaxed);
+        let (sample, change) = self.health.update(sample, failed, limit);
+        if failed {
+            SAMPLE_ERRORS[self.channel].store(self.health.errors(), Ordering::Relaxed);
+        }
+
+        let event = match change {
+            Some(HealthChange::Fault { error_rate }) => Events::SensorFault {
+                channel: self.channel,
+                error_rate,
+            },
+            Some(HealthChange::Recovered) => Events::SensorRecovered {
+                channel: self.channel,
+            },
+            None => return sample,
+        };
 
-        Some(avg_value)
+        if EVENT_CHANNEL.try_send(event).is_err() {
+            warn!(
+                "Event queue full, sensor fault change of {} not reported",
+                CHANNEL_TABLE[self.channel].name
+            );
+        }
+        sample
+    }
+
+    /// Publishes electrode contact changes.
+    fn check_contact(&mut self, sample: i32, filtered_value: i32) {
+        let event = match self.contact.update(sample, filtered_value) {
+            Some(ContactChange::Lost(fault)) => {
+                CONTACT_STATUS[self.channel].store(fault as u8, Ordering::Relaxed);
+                Events::ContactLost {
+                    channel: self.channel,
+                    fault,
+                }
+            }
+            Some(ContactChange::Restored) => {
+                CONTACT_STATUS[self.channel].store(0, Ordering::Relaxed);
+                Events::ContactRestored {
+                    channel: self.channel,
+                }
+            }
+            None => return,
+        };
+
+        if EVENT_CHANNEL.try_send(event).is_err() {
+            warn!(
+                "Event queue full, contact change of {} not reported",
+                CHANNEL_TABLE[self.channel].name
+            );
+        }
+    }
+
+    /// Hands complete windows of filtered samples to the fatigue monitor.
+    fn collect_window(&mut self, filtered_value: i32) {
+        self.window[self.window_len] = filtered_value as f32;
+        self.window_len += 1;
+
+        if self.window_len == FFT_SIZE {
+            self.window_len = 0;
+            let window = FatigueWindow {
+                channel: self.channel,
+                samples: self.window,
+            };
+            if FATIGUE_WINDOWS.try_send(window).is_err() {
+                debug!(
+                    "Fatigue monitor busy, dropping window of {}",
+                    CHANNEL_TABLE[self.channel].name
+                );
+            }
+        }
     }
 }
 
 pub struct EmgSensorsState {
-    pub emg1_value: i32,
-    pub emg2_value: i32,
+    pub timestamp: Instant,
+    pub values: [i32; EMG_CHANNELS],
 }
 
 impl EmgSensorsState {
+    /// Latest processed sample, all channels from the same instant
     pub async fn gather() -> Self {
-        Self {
-            emg1_value: EMG1_VALUE.load(Ordering::Relaxed),
-            emg2_value: EMG2_VALUE.load(Ordering::Relaxed),
+        match EMG_SAMPLES.latest() {
+            Some(sample) => Self {
+                timestamp: sample.timestamp,
+                values: sample.values,
+            },
+            None => Self {
+                timestamp: Instant::now(),
+                values: [0; EMG_CHANNELS],
+            },
         }
     }
 }
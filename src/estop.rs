@@ -0,0 +1,119 @@
+use core::sync::atomic::Ordering;
+
+use defmt::*;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use portable_atomic::AtomicU8;
+
+use crate::{
+    resources::EStopResources,
+    state::events::{Events, EVENT_CHANNEL},
+};
+
+/// Time the button has to stay pressed, filters contact bounce and spikes
+const DEBOUNCE: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Source {
+    Ble = 0,
+    Button = 1,
+    /// Internal fault detection, e.g. over-temperature during motion
+    Fault = 2,
+    /// The hand controller, which has already stopped or cleared itself
+    Hand = 3,
+}
+
+/// Value of `LATCH` while no emergency stop is latched
+const UNLATCHED: u8 = u8::MAX;
+
+/// Source of the latched emergency stop, `UNLATCHED` once cleared. Motion
+/// commands are dropped while latched.
+static LATCH: AtomicU8 = AtomicU8::new(UNLATCHED);
+
+/// Emergency stops for the command handler, which sends them ahead of any
+/// queued command
+pub static STOP: Signal<CriticalSectionRawMutex, Source> = Signal::new();
+/// Clearing of the emergency stop, forwarded to the hand by the command handler
+pub static CLEAR: Signal<CriticalSectionRawMutex, Source> = Signal::new();
+
+pub fn is_latched() -> bool {
+    LATCH.load(Ordering::Relaxed) != UNLATCHED
+}
+
+/// Stops the hand and latches the emergency stop. Never waits, so it can be
+/// called from any task, including the orchestrator.
+///
+/// A local stop takes over a latch the hand triggered, so that the hand can
+/// no longer clear it.
+pub fn trigger(source: Source) {
+    STOP.signal(source);
+    let previous = if source == Source::Hand {
+        match LATCH.compare_exchange(
+            UNLATCHED,
+            source as u8,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(previous) | Err(previous) => previous,
+        }
+    } else {
+        LATCH.swap(source as u8, Ordering::Relaxed)
+    };
+    if previous != UNLATCHED {
+        return;
+    }
+    warn!("Emergency stop from {}", source);
+    if EVENT_CHANNEL
+        .try_send(Events::EmergencyStop { source })
+        .is_err()
+    {
+        warn!("Event queue full, emergency stop not reported");
+    }
+}
+
+/// Releases the latch, motion commands are accepted again. Each side only
+/// clears its own latches: the hand those it triggered, BLE the local ones. A
+/// stop from the hand is released by the hand, which stays stopped until then.
+pub fn clear(source: Source) {
+    let result = LATCH.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |latch| {
+        let from_hand = latch == Source::Hand as u8;
+        (latch != UNLATCHED && from_hand == (source == Source::Hand)).then_some(UNLATCHED)
+    });
+    match result {
+        Ok(_) => {}
+        Err(UNLATCHED) => return,
+        Err(_) if source == Source::Hand => {
+            warn!("Hand cannot clear a local emergency stop");
+            return;
+        }
+        Err(_) => {
+            warn!("Emergency stop from the hand must be cleared on the hand");
+            return;
+        }
+    }
+    info!("Emergency stop cleared from {}", source);
+    CLEAR.signal(source);
+    if EVENT_CHANNEL
+        .try_send(Events::EmergencyStopCleared)
+        .is_err()
+    {
+        warn!("Event queue full, emergency stop clearing not reported");
+    }
+}
+
+/// Triggers the emergency stop on a press of the normally open button between
+/// the pin and ground.
+#[embassy_executor::task]
+pub async fn button_task(r: EStopResources) {
+    let mut button = Input::new(r.button, Pull::Up);
+
+    loop {
+        button.wait_for_falling_edge().await;
+        Timer::after(DEBOUNCE).await;
+        if button.is_low() {
+            trigger(Source::Button);
+        }
+        button.wait_for_high().await;
+    }
+}
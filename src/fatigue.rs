@@ -0,0 +1,158 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::*;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+use crate::{
+    emg::{EMG_CHANNELS, SAMPLE_FREQUENCY},
+    filters::spectrum::{RealFft, SpectralFeatures, FFT_SIZE},
+    state::{
+        events::{Events, EVENT_CHANNEL},
+        ProgramStage, STAGE_CHANGES,
+    },
+};
+
+/// Windows with less filtered signal power than this are treated as rest
+const ACTIVE_MEAN_SQUARE: f32 = 100.0;
+/// Number of active windows averaged into the session baseline (~10 s at 500 Hz)
+const BASELINE_WINDOWS: u16 = 20;
+/// Smoothing factor of the median frequency trend
+const TREND_ALPHA: f32 = 0.1;
+/// Median frequency drop (percent of baseline) reported as fatigue
+const FATIGUE_ONSET_PERCENT: f32 = 10.0;
+/// Median frequency drop (percent of baseline) below which fatigue is cleared
+const FATIGUE_RECOVERY_PERCENT: f32 = 5.0;
+
+pub struct FatigueWindow {
+    pub channel: usize,
+    pub samples: [f32; FFT_SIZE],
+}
+
+pub static FATIGUE_WINDOWS: Channel<CriticalSectionRawMutex, FatigueWindow, 2> = Channel::new();
+
+/// Latest median frequency per channel, in 0.1 Hz
+pub static MEDIAN_FREQ: [AtomicU16; EMG_CHANNELS] = [const { AtomicU16::new(0) }; EMG_CHANNELS];
+/// Latest mean frequency per channel, in 0.1 Hz
+pub static MEAN_FREQ: [AtomicU16; EMG_CHANNELS] = [const { AtomicU16::new(0) }; EMG_CHANNELS];
+/// Drop of the median frequency trend from the session baseline, in percent
+pub static FATIGUE_INDEX: [AtomicU16; EMG_CHANNELS] = [const { AtomicU16::new(0) }; EMG_CHANNELS];
+
+pub enum FatigueChange {
+    Onset,
+    Recovered,
+}
+
+/// Tracks the median frequency of active windows over a session, which starts
+/// with each calibration.
+pub struct FatigueTracker {
+    baseline: f32,
+    baseline_windows: u16,
+    trend: f32,
+    fatigued: bool,
+}
+
+impl FatigueTracker {
+    pub fn new() -> Self {
+        Self {
+            baseline: 0.0,
+            baseline_windows: 0,
+            trend: 0.0,
+            fatigued: false,
+        }
+    }
+
+    pub fn update(&mut self, features: &SpectralFeatures) -> Option<FatigueChange> {
+        if features.mean_square < ACTIVE_MEAN_SQUARE {
+            return None;
+        }
+
+        if self.baseline_windows < BASELINE_WINDOWS {
+            self.baseline_windows += 1;
+            self.baseline += (features.median_freq - self.baseline) / self.baseline_windows as f32;
+            self.trend = self.baseline;
+            return None;
+        }
+
+        self.trend += TREND_ALPHA * (features.median_freq - self.trend);
+
+        let drop = self.drop_percent();
+        if !self.fatigued && drop >= FATIGUE_ONSET_PERCENT {
+            self.fatigued = true;
+            Some(FatigueChange::Onset)
+        } else if self.fatigued && drop <= FATIGUE_RECOVERY_PERCENT {
+            self.fatigued = false;
+            Some(FatigueChange::Recovered)
+        } else {
+            None
+        }
+    }
+
+    pub fn trend(&self) -> f32 {
+        self.trend
+    }
+
+    pub fn drop_percent(&self) -> f32 {
+        if self.baseline_windows < BASELINE_WINDOWS || self.baseline <= 0.0 {
+            return 0.0;
+        }
+
+        (self.baseline - self.trend) / self.baseline * 100.0
+    }
+}
+
+#[embassy_executor::task]
+pub async fn fatigue_task() {
+    info!("Fatigue monitoring task started!");
+    let mut fft = RealFft::new();
+    let mut trackers: [FatigueTracker; EMG_CHANNELS] =
+        core::array::from_fn(|_| FatigueTracker::new());
+    let mut stages = unwrap!(STAGE_CHANGES.subscriber());
+    let receiver = FATIGUE_WINDOWS.receiver();
+
+    loop {
+        let window = receiver.receive().await;
+
+        // Electrodes may have moved and the muscles rested, the baseline is
+        // learned again after every calibration
+        let mut calibrated = false;
+        while let Some(stage) = stages.try_next_message_pure() {
+            calibrated |= stage == ProgramStage::Calibration;
+        }
+        if calibrated {
+            debug!("Calibration entered, fatigue baselines reset");
+            trackers = core::array::from_fn(|_| FatigueTracker::new());
+            for index in FATIGUE_INDEX.iter() {
+                index.store(0, Ordering::Relaxed);
+            }
+        }
+        let channel = window.channel;
+        let features = fft.analyze(&window.samples, SAMPLE_FREQUENCY);
+
+        MEDIAN_FREQ[channel].store((features.median_freq * 10.0) as u16, Ordering::Relaxed);
+        MEAN_FREQ[channel].store((features.mean_freq * 10.0) as u16, Ordering::Relaxed);
+
+        let tracker = &mut trackers[channel];
+        let change = tracker.update(&features);
+        FATIGUE_INDEX[channel].store(tracker.drop_percent().max(0.0) as u16, Ordering::Relaxed);
+
+        match change {
+            Some(FatigueChange::Onset) => {
+                EVENT_CHANNEL
+                    .send(Events::FatigueDetected {
+                        channel,
+                        median_freq: tracker.trend(),
+                    })
+                    .await;
+            }
+            Some(FatigueChange::Recovered) => {
+                EVENT_CHANNEL
+                    .send(Events::FatigueRecovered {
+                        channel,
+                        median_freq: tracker.trend(),
+                    })
+                    .await;
+            }
+            None => {}
+        }
+    }
+}
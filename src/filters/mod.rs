@@ -1,2 +0,0 @@
-pub mod EMG;
-pub mod mean;
@@ -0,0 +1,145 @@
+use defmt::*;
+use embassy_time::{Duration, Ticker};
+
+use crate::{
+    emg::{EMG_CHANNELS, EMG_SAMPLES, SAMPLE_FREQUENCY},
+    state::{
+        calibration::{RestNoise, REST_NOISE},
+        events::{Events, EVENT_CHANNEL},
+        machine::UserRequest,
+        ProgramStage, PROGRAM_STATE, STAGE_CHANGES,
+    },
+};
+
+/// Time every channel has to stay active for the lock gesture
+const HOLD_SAMPLES: u32 = 2 * SAMPLE_FREQUENCY as u32;
+/// Envelope above the rest mean, in rest standard deviations, that counts as active
+const ONSET_STD_DEVS: f32 = 8.0;
+/// Envelope above the rest mean, in rest standard deviations, that counts as relaxed
+const RELEASE_STD_DEVS: f32 = 3.0;
+/// Interval at which new samples are checked
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+
+/// Envelope levels of one channel, derived from its rest noise
+#[derive(Clone, Copy)]
+pub struct GestureThresholds {
+    onset: i32,
+    release: i32,
+}
+
+impl GestureThresholds {
+    pub fn from_rest(noise: &RestNoise) -> Self {
+        let mean = noise.mean as f32;
+        // Never below twice the largest rest value, a very quiet channel
+        // would otherwise trigger on any twitch
+        let onset = (mean + ONSET_STD_DEVS * noise.std_dev).max(2.0 * noise.max as f32);
+        let release = mean + RELEASE_STD_DEVS * noise.std_dev;
+        Self {
+            onset: onset as i32,
+            release: (release as i32).min(onset as i32),
+        }
+    }
+}
+
+/// Detects a sustained co-contraction of every channel, which no normal
+/// grip produces.
+pub struct CoContraction {
+    thresholds: [GestureThresholds; EMG_CHANNELS],
+    // Index of the first sample of the current co-contraction
+    active_since: Option<u32>,
+    // Cleared after a gesture until every channel relaxed, so that holding on
+    // does not toggle again
+    armed: bool,
+}
+
+impl CoContraction {
+    pub fn new(thresholds: [GestureThresholds; EMG_CHANNELS]) -> Self {
+        Self {
+            thresholds,
+            active_since: None,
+            armed: false,
+        }
+    }
+
+    /// Returns true once per gesture, when the co-contraction was held long enough
+    pub fn update(&mut self, index: u32, values: &[i32; EMG_CHANNELS]) -> bool {
+        let levels = self.thresholds.iter().zip(values.iter());
+
+        if !self.armed {
+            self.armed = levels.clone().all(|(t, &value)| value < t.release);
+            return false;
+        }
+
+        if !levels.clone().all(|(t, &value)| value >= t.onset) {
+            self.active_since = None;
+            return false;
+        }
+
+        let start = *self.active_since.get_or_insert(index);
+        if index.wrapping_sub(start) < HOLD_SAMPLES {
+            return false;
+        }
+
+        self.active_since = None;
+        self.armed = false;
+        true
+    }
+}
+
+/// Toggles between Operation and Paused on a co-contraction gesture.
+#[embassy_executor::task]
+pub async fn gesture_task() {
+    info!("Gesture task started!");
+    let mut stages = unwrap!(STAGE_CHANGES.subscriber());
+    let mut samples = EMG_SAMPLES.reader();
+    let mut ticker = Ticker::every(POLL_PERIOD);
+    let mut stage = *PROGRAM_STATE.lock().await;
+    let mut detector: Option<CoContraction> = None;
+
+    loop {
+        ticker.next().await;
+
+        let mut changed = false;
+        while let Some(next) = stages.try_next_message_pure() {
+            stage = next;
+            changed = true;
+        }
+        if changed {
+            // Thresholds follow the latest calibration, and the gesture that
+            // caused the change has to be released first
+            detector = match stage {
+                ProgramStage::Operation | ProgramStage::Paused => {
+                    let rest = *REST_NOISE.lock().await;
+                    Some(CoContraction::new(core::array::from_fn(|channel| {
+                        GestureThresholds::from_rest(&rest[channel])
+                    })))
+                }
+                _ => None,
+            };
+        }
+
+        let mut gesture = false;
+        loop {
+            match samples.read() {
+                Ok(Some(sample)) => {
+                    if let Some(detector) = detector.as_mut() {
+                        gesture |= detector.update(sample.index, &sample.values);
+                    }
+                }
+                Ok(None) => break,
+                Err(overrun) => warn!("Gesture detection lost {} samples", overrun.lost),
+            }
+        }
+
+        if !gesture {
+            continue;
+        }
+
+        let request = match stage {
+            ProgramStage::Paused => UserRequest::Resume,
+            _ => UserRequest::Pause,
+        };
+        info!("Co-contraction gesture, requesting {}", request);
+        EVENT_CHANNEL.send(Events::UserRequest { request }).await;
+    }
+}
@@ -0,0 +1,10 @@
+use core::sync::atomic::{AtomicU16, AtomicU32};
+
+pub use picow_logic::health::{ChannelHealth, HealthChange, DEFAULT_ERROR_RATE_LIMIT};
+
+use crate::emg::EMG_CHANNELS;
+
+/// Share of failed conversions per window, in per mille, above which a channel is faulted
+pub static ERROR_RATE_LIMIT: AtomicU16 = AtomicU16::new(DEFAULT_ERROR_RATE_LIMIT);
+/// Failed conversions per channel since boot
+pub static SAMPLE_ERRORS: [AtomicU32; EMG_CHANNELS] = [const { AtomicU32::new(0) }; EMG_CHANNELS];
@@ -0,0 +1,106 @@
+pub use picow_logic::limits;
+
+use defmt::*;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::commands::{CommandType, Packet};
+use limits::{encode_positions, parse_positions, JointLimits, JointRange, JOINTS};
+
+/// Interval at which the hand is asked for its joint positions
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+/// Age after which a measured position is no longer trusted
+const STALE_AFTER: Duration = Duration::from_millis(500);
+/// Longest time the velocity limit is applied over, the first target after a
+/// pause may not jump arbitrarily far
+const MAX_ELAPSED: Duration = Duration::from_secs(1);
+
+/// What is known about the hand's joints
+pub struct JointModel {
+    /// Latest `ResponsePosition`, `None` until the hand first answers
+    pub measured: Option<[JointRange; JOINTS]>,
+    measured_at: Instant,
+    // Latest `SetPosition` sent, the reference while measurements are stale
+    commanded: Option<[JointRange; JOINTS]>,
+    commanded_at: Instant,
+    pub limits: JointLimits,
+}
+
+impl JointModel {
+    const fn new() -> Self {
+        Self {
+            measured: None,
+            measured_at: Instant::from_ticks(0),
+            commanded: None,
+            commanded_at: Instant::from_ticks(0),
+            limits: JointLimits::new(),
+        }
+    }
+
+    fn reference(&self, now: Instant) -> Option<&[JointRange; JOINTS]> {
+        match &self.measured {
+            Some(measured) if now - self.measured_at < STALE_AFTER => Some(measured),
+            _ => self.commanded.as_ref(),
+        }
+    }
+}
+
+pub static JOINT_MODEL: Mutex<CriticalSectionRawMutex, JointModel> = Mutex::new(JointModel::new());
+
+/// Stores the positions of a `ResponsePosition` payload
+pub async fn update(payload: &[u8]) {
+    match parse_positions(payload) {
+        Some(positions) => {
+            let mut model = JOINT_MODEL.lock().await;
+            model.measured = Some(positions);
+            model.measured_at = Instant::now();
+        }
+        None => warn!("Malformed position response"),
+    }
+}
+
+/// Ranges of the latest `SetPosition` sent
+pub async fn commanded() -> Option<[JointRange; JOINTS]> {
+    JOINT_MODEL.lock().await.commanded
+}
+
+/// Applies the soft limits and the velocity limit to the ranges of a
+/// `SetPosition` packet. Returns `None` for a malformed one, which is not sent
+/// at all.
+pub async fn limit(packet: Packet) -> Option<Packet> {
+    let Some(targets) = parse_positions(&packet.payload[..packet.length as usize]) else {
+        warn!("Malformed SetPosition dropped");
+        return None;
+    };
+
+    let mut model = JOINT_MODEL.lock().await;
+    let now = Instant::now();
+    let elapsed = match model.commanded {
+        Some(_) => (now - model.commanded_at).min(MAX_ELAPSED),
+        None => MAX_ELAPSED,
+    };
+    let limited = model
+        .limits
+        .apply(&targets, model.reference(now), elapsed.as_millis() as u32);
+    if limited != targets {
+        debug!("SetPosition limited from {} to {}", targets, limited);
+    }
+    model.commanded = Some(limited);
+    model.commanded_at = now;
+
+    Packet::with_payload(CommandType::SetPosition, &encode_positions(&limited))
+}
+
+/// Keeps the joint model up to date by polling the hand.
+#[embassy_executor::task]
+pub async fn joint_polling_task() {
+    info!("Joint polling started!");
+    let mut ticker = Ticker::every(POLL_PERIOD);
+
+    loop {
+        ticker.next().await;
+        if let Some(packet) = Packet::with_payload(CommandType::RequestPosition, &[]) {
+            packet.send().await;
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use defmt::{info, unwrap};
+use embassy_futures::select::{select3, Either3};
+use embassy_time::{Duration, Timer};
+
+use crate::state::{
+    machine::Confirmation, wait_stage_change, ProgramStage, CONFIRMATION, PROGRAM_STATE,
+    STAGE_CHANGES,
+};
+
+/// GPIO of the wireless chip driving the Pico W's LED
+const LED_GPIO: u8 = 0;
+
+/// On and off time of the LED in each stage, `None` for a steady LED
+fn blink_pattern(stage: ProgramStage) -> Option<(Duration, Duration)> {
+    let ms = Duration::from_millis;
+    match stage {
+        ProgramStage::Boot | ProgramStage::Idle => Some((ms(100), ms(1900))),
+        ProgramStage::Calibration => Some((ms(250), ms(250))),
+        ProgramStage::Operation | ProgramStage::Shutdown => None,
+        ProgramStage::Paused => Some((ms(1000), ms(1000))),
+        ProgramStage::Error => Some((ms(100), ms(100))),
+    }
+}
+
+/// Flashes of a lock or unlock confirmation, as on and off time and count
+fn confirmation_pattern(confirmation: Confirmation) -> (Duration, u8) {
+    match confirmation {
+        Confirmation::Locked => (Duration::from_millis(80), 3),
+        Confirmation::Unlocked => (Duration::from_millis(300), 2),
+    }
+}
+
+async fn confirm(control: &mut cyw43::Control<'static>, confirmation: Confirmation) {
+    let (period, flashes) = confirmation_pattern(confirmation);
+    control.gpio_set(LED_GPIO, false).await;
+    Timer::after(period).await;
+    for _ in 0..flashes {
+        control.gpio_set(LED_GPIO, true).await;
+        Timer::after(period).await;
+        control.gpio_set(LED_GPIO, false).await;
+        Timer::after(period).await;
+    }
+}
+
+/// Shows the program stage on the on-board LED, interrupted by confirmations.
+#[embassy_executor::task]
+pub async fn status_led_task(mut control: cyw43::Control<'static>) {
+    info!("Status LED task started!");
+    let mut stages = unwrap!(STAGE_CHANGES.subscriber());
+    let mut stage = *PROGRAM_STATE.lock().await;
+
+    loop {
+        let pattern = blink_pattern(stage);
+        let steady_on = stage == ProgramStage::Operation;
+        let shown = async {
+            match pattern {
+                Some((on, off)) => loop {
+                    control.gpio_set(LED_GPIO, true).await;
+                    Timer::after(on).await;
+                    control.gpio_set(LED_GPIO, false).await;
+                    Timer::after(off).await;
+                },
+                None => {
+                    control.gpio_set(LED_GPIO, steady_on).await;
+                    core::future::pending::<()>().await
+                }
+            }
+        };
+
+        let event = select3(wait_stage_change(&mut stages), CONFIRMATION.wait(), shown).await;
+        match event {
+            Either3::First(next) => stage = next,
+            Either3::Second(confirmation) => confirm(&mut control, confirmation).await,
+            Either3::Third(()) => {}
+        }
+    }
+}
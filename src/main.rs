@@ -1,22 +1,44 @@
 #![no_std]
 #![no_main]
-use {defmt_rtt as _, panic_probe as _};
+use defmt_rtt as _;
 
 mod adc;
 mod bluetooth;
 mod commands;
+mod contact;
+mod crash;
+mod cycles;
+mod device;
 mod emg;
-mod filters;
+mod estop;
+mod fatigue;
+mod gesture;
+mod health;
+mod joints;
+mod led;
+mod monitor;
+mod protection;
 mod resources;
 mod serial;
 mod state;
+mod watchdog;
 
+use core::ptr::addr_of_mut;
+
+#[cfg(not(feature = "ads129x"))]
+use adc::adc_acquisition_task;
 use adc::init_adc;
 use defmt::*;
-use embassy_executor::Spawner;
-use embassy_rp::{adc::Channel, gpio::Pull};
+use embassy_executor::{Executor, Spawner};
+use embassy_rp::flash::Flash;
+use embassy_rp::multicore::{spawn_core1, Stack};
+#[cfg(feature = "ads129x")]
+use picow_logic::ads129x;
+use picow_logic::{filters, ring};
+use static_cell::StaticCell;
 
-use emg::{emg_reading_task, EMGSensor};
+use emg::{emg_processing_task, EMGSensor, EMG_CHANNELS};
+use fatigue::fatigue_task;
 use resources::*;
 use state::{
     calibration::calibration_task,
@@ -25,6 +47,9 @@ use state::{
     orchestrator,
 };
 
+static mut CORE1_STACK: Stack<16384> = Stack::new();
+static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     info!("Starting up...");
@@ -32,19 +57,80 @@ async fn main(spawner: Spawner) {
 
     let r = split_resources!(p);
 
+    // Before core 1 runs, as flash access would otherwise have to pause it
+    let mut flash = Flash::new_blocking(r.flash.flash);
+    device::read_serial(&mut flash);
+    // A panic record is moved to flash
+    crash::init(flash).await;
+
     let uart = serial::init_buffered_uart(r.uart);
     let (tx, rx) = uart.split();
 
-    let adc = init_adc(r.adc.adc);
-
     info!("Initializing EMG filters...");
-    let emg1 = EMGSensor::new(Channel::new_pin(p.PIN_27, Pull::None));
-    let emg2 = EMGSensor::new(Channel::new_pin(p.PIN_26, Pull::None));
+    let sensors: [EMGSensor; EMG_CHANNELS] = core::array::from_fn(EMGSensor::new);
     info!("EMG filters initialized!");
 
-    info!("Spawning EMG reading task...");
-    unwrap!(spawner.spawn(emg_reading_task(adc, emg1, emg2)));
-    info!("EMG reading task spawned!");
+    // Acquisition and signal processing run on core 1 so that their timing
+    // does not depend on the radio and host tasks left on this core. Samples,
+    // settings and events cross over through the critical-section based
+    // channels, signals and the sample ring.
+    info!("Starting core 1...");
+    spawn_core1(
+        p.CORE1,
+        unsafe { &mut *addr_of_mut!(CORE1_STACK) },
+        move || {
+            let executor1 = EXECUTOR1.init(Executor::new());
+            executor1.run(|spawner| {
+                cycles::init();
+
+                info!("Spawning EMG processing task...");
+                unwrap!(spawner.spawn(emg_processing_task(sensors)));
+                info!("EMG processing task spawned!");
+
+                #[cfg(not(feature = "ads129x"))]
+                {
+                    info!("Spawning ADC acquisition task...");
+                    // Created here so that the ADC interrupt is handled by this core
+                    let adc = init_adc(r.adc.adc);
+                    unwrap!(spawner.spawn(adc_acquisition_task(
+                        adc,
+                        r.emg_inputs,
+                        r.adc.dma_data,
+                        r.adc.dma_control
+                    )));
+                    info!("ADC acquisition task spawned!");
+                }
+
+                #[cfg(feature = "ads129x")]
+                {
+                    info!("Spawning ADS129x acquisition task...");
+                    unwrap!(spawner.spawn(adc::ads129x_acquisition_task(r.ads)));
+                    info!("ADS129x acquisition task spawned!");
+                }
+
+                info!("Starting fatigue monitoring task...");
+                unwrap!(spawner.spawn(fatigue_task()));
+                info!("Fatigue monitoring task spawned!");
+            })
+        },
+    );
+    cycles::init();
+
+    // The supply monitor still needs the converter powered up
+    #[cfg(feature = "ads129x")]
+    init_adc(r.adc.adc);
+
+    info!("Starting supply monitor...");
+    unwrap!(spawner.spawn(monitor::monitor_task()));
+    info!("Supply monitor spawned!");
+
+    info!("Starting watchdog...");
+    unwrap!(spawner.spawn(watchdog::watchdog_task(r.watchdog)));
+    info!("Watchdog spawned!");
+
+    info!("Starting emergency stop button...");
+    unwrap!(spawner.spawn(estop::button_task(r.estop)));
+    info!("Emergency stop button spawned!");
 
     info!("Starting calibration task...");
     unwrap!(spawner.spawn(calibration_task()));
@@ -54,6 +140,22 @@ async fn main(spawner: Spawner) {
     unwrap!(spawner.spawn(operation_task()));
     info!("Operation task spawned!");
 
+    info!("Starting grip protection...");
+    unwrap!(spawner.spawn(protection::protection_task()));
+    info!("Grip protection spawned!");
+
+    info!("Starting joint polling...");
+    unwrap!(spawner.spawn(joints::joint_polling_task()));
+    info!("Joint polling spawned!");
+
+    info!("Starting device info exchange...");
+    unwrap!(spawner.spawn(device::device_info_task()));
+    info!("Device info exchange spawned!");
+
+    info!("Starting gesture task...");
+    unwrap!(spawner.spawn(gesture::gesture_task()));
+    info!("Gesture task spawned!");
+
     info!("Starting orchestrator...");
     unwrap!(spawner.spawn(orchestrator()));
     info!("Orchestrator task spawned!");
@@ -0,0 +1,223 @@
+use core::sync::atomic::{AtomicI16, AtomicU16, AtomicU8, Ordering};
+
+use defmt::*;
+use embassy_rp::pac;
+use embassy_time::{Duration, Ticker};
+
+use crate::{
+    adc::{AdcLoan, ADC_LEASE},
+    commands::{CommandType, Packet},
+    state::events::{Events, EVENT_CHANNEL},
+};
+
+/// Interval between readings
+const MEASUREMENT_PERIOD: Duration = Duration::from_secs(5);
+/// Readings between periodic status reports over UART (30 s)
+const REPORT_READINGS: u32 = 6;
+/// Consecutive readings without VSYS after which the monitor complains
+const MISSED_READINGS_WARNING: u8 = 6;
+
+/// ADC input of VSYS / 3, whose pin doubles as the wireless chip's SPI clock
+const VSYS_INPUT: u8 = 3;
+/// ADC input of the on-chip temperature sensor
+const TEMPERATURE_INPUT: u8 = 4;
+const VSYS_PIN: usize = 29;
+/// Chip select of the wireless chip, the VSYS divider is only connected while it is high
+const WIRELESS_CS_PIN: usize = 25;
+/// GPIO function that disconnects the pin from every peripheral
+const FUNCSEL_NULL: u8 = 0x1f;
+
+/// VSYS below which the battery is reported low, in mV
+pub const DEFAULT_LOW_BATTERY: u16 = 3400;
+/// Die temperature above which the controller is reported too hot, in 0.1 °C
+pub const DEFAULT_OVER_TEMPERATURE: i16 = 700;
+const BATTERY_HYSTERESIS: u16 = 100;
+const TEMPERATURE_HYSTERESIS: i16 = 50;
+/// VSYS of an empty and of a full single Li-ion cell, in mV
+const BATTERY_EMPTY: u16 = 3300;
+const BATTERY_FULL: u16 = 4200;
+
+/// VSYS in mV below which the battery is low
+pub static LOW_BATTERY: AtomicU16 = AtomicU16::new(DEFAULT_LOW_BATTERY);
+/// Die temperature in 0.1 °C above which the controller is too hot
+pub static OVER_TEMPERATURE: AtomicI16 = AtomicI16::new(DEFAULT_OVER_TEMPERATURE);
+
+/// Latest VSYS in mV, 0 before the first reading
+pub static VSYS: AtomicU16 = AtomicU16::new(0);
+/// Latest die temperature in 0.1 °C
+pub static TEMPERATURE: AtomicI16 = AtomicI16::new(0);
+/// Latest battery charge estimate in percent
+pub static BATTERY_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// Charge estimate in percent, linear in VSYS between an empty and a full cell
+pub fn battery_level(millivolts: u16) -> u8 {
+    let clamped = millivolts.clamp(BATTERY_EMPTY, BATTERY_FULL);
+    ((clamped - BATTERY_EMPTY) as u32 * 100 / (BATTERY_FULL - BATTERY_EMPTY) as u32) as u8
+}
+
+/// VSYS in mV from a conversion of the 1:3 divider against the 3.3 V reference
+fn vsys_millivolts(raw: u16) -> u16 {
+    (raw as u32 * 3 * 3300 / 4096) as u16
+}
+
+/// Die temperature in 0.1 °C, from the sensor's 0.706 V at 27 °C and -1.721 mV/°C
+fn temperature_decicelsius(raw: u16) -> i16 {
+    let volts = raw as f32 * 3.3 / 4096.0;
+    ((27.0 - (volts - 0.706) / 0.001721) * 10.0) as i16
+}
+
+/// Alarm with separate raise and clear conditions.
+struct Alarm {
+    active: bool,
+}
+
+impl Alarm {
+    const fn new() -> Self {
+        Self { active: false }
+    }
+
+    /// Returns the new state when it changed
+    fn update(&mut self, raise: bool, clear: bool) -> Option<bool> {
+        if !self.active && raise {
+            self.active = true;
+            return Some(true);
+        }
+        if self.active && clear {
+            self.active = false;
+            return Some(false);
+        }
+        None
+    }
+}
+
+/// Converts VSYS, `None` while the wireless chip is selected.
+///
+/// The pin is the clock of the wireless chip's PIO SPI. It is only borrowed
+/// while the chip is deselected, which makes the divider valid, and without
+/// yielding, so the cyw43 runner on this core cannot start a transfer before
+/// the pin is handed back.
+fn convert_vsys(loan: &mut AdcLoan) -> Option<u16> {
+    // Only this core's interrupts are masked, core 1 keeps processing EMG
+    cortex_m::interrupt::free(|_| {
+        if pac::SIO.gpio_out(0).value().read() & (1 << WIRELESS_CS_PIN) == 0 {
+            return None;
+        }
+
+        let ctrl = pac::IO_BANK0.gpio(VSYS_PIN).ctrl();
+        let pad = pac::PADS_BANK0.gpio(VSYS_PIN);
+        let saved_ctrl = ctrl.read();
+        let saved_pad = pad.read();
+
+        ctrl.modify(|w| w.set_funcsel(FUNCSEL_NULL));
+        pad.modify(|w| {
+            w.set_ie(false);
+            w.set_od(true);
+            w.set_pue(false);
+            w.set_pde(false);
+        });
+
+        let raw = loan.convert(VSYS_INPUT);
+
+        pad.write_value(saved_pad);
+        ctrl.write_value(saved_ctrl);
+        raw
+    })
+}
+
+/// Reports supply and temperature to the hand controller.
+///
+/// Payload: VSYS in mV (u16), die temperature in 0.1 °C (i16), battery level
+/// in percent, bit 0 low battery and bit 1 over-temperature, little endian.
+async fn report_status(millivolts: u16, decicelsius: i16, alarms: u8) {
+    let mut payload = [0; 6];
+    payload[..2].copy_from_slice(&millivolts.to_le_bytes());
+    payload[2..4].copy_from_slice(&decicelsius.to_le_bytes());
+    payload[4] = battery_level(millivolts);
+    payload[5] = alarms;
+
+    if let Some(packet) = Packet::with_payload(CommandType::ReportStatus, &payload) {
+        packet.send().await;
+    }
+}
+
+/// Watches VSYS and the die temperature against `LOW_BATTERY` and `OVER_TEMPERATURE`.
+///
+/// Must run on the core that runs the wireless chip's driver, see `convert_vsys`.
+#[embassy_executor::task]
+pub async fn monitor_task() {
+    info!("Supply monitor task started!");
+    let mut ticker = Ticker::every(MEASUREMENT_PERIOD);
+    let mut low_battery = Alarm::new();
+    let mut over_temperature = Alarm::new();
+    let mut missed_readings: u8 = 0;
+    let mut readings: u32 = 0;
+
+    loop {
+        ticker.next().await;
+
+        let (vsys, temperature) = {
+            let mut loan = ADC_LEASE.borrow().await;
+            (convert_vsys(&mut loan), loan.convert(TEMPERATURE_INPUT))
+        };
+
+        let mut changed = false;
+
+        match vsys {
+            Some(raw) => {
+                missed_readings = 0;
+                let millivolts = vsys_millivolts(raw);
+                VSYS.store(millivolts, Ordering::Relaxed);
+                BATTERY_LEVEL.store(battery_level(millivolts), Ordering::Relaxed);
+
+                let limit = LOW_BATTERY.load(Ordering::Relaxed);
+                let clear = millivolts >= limit.saturating_add(BATTERY_HYSTERESIS);
+                if let Some(low) = low_battery.update(millivolts < limit, clear) {
+                    changed = true;
+                    let event = if low {
+                        Events::LowBattery { millivolts }
+                    } else {
+                        Events::BatteryRecovered { millivolts }
+                    };
+                    EVENT_CHANNEL.send(event).await;
+                }
+            }
+            None => {
+                missed_readings = missed_readings.saturating_add(1);
+                if missed_readings == MISSED_READINGS_WARNING {
+                    warn!("VSYS not measured for {} readings", missed_readings);
+                }
+            }
+        }
+
+        match temperature {
+            Some(raw) => {
+                let decicelsius = temperature_decicelsius(raw);
+                TEMPERATURE.store(decicelsius, Ordering::Relaxed);
+
+                let limit = OVER_TEMPERATURE.load(Ordering::Relaxed);
+                let clear = decicelsius <= limit.saturating_sub(TEMPERATURE_HYSTERESIS);
+                if let Some(hot) = over_temperature.update(decicelsius > limit, clear) {
+                    changed = true;
+                    let event = if hot {
+                        Events::OverTemperature { decicelsius }
+                    } else {
+                        Events::TemperatureRecovered { decicelsius }
+                    };
+                    EVENT_CHANNEL.send(event).await;
+                }
+            }
+            None => warn!("Temperature conversion failed"),
+        }
+
+        readings = readings.wrapping_add(1);
+        if changed || readings % REPORT_READINGS == 0 {
+            let alarms = low_battery.active as u8 | (over_temperature.active as u8) << 1;
+            report_status(
+                VSYS.load(Ordering::Relaxed),
+                TEMPERATURE.load(Ordering::Relaxed),
+                alarms,
+            )
+            .await;
+        }
+    }
+}
@@ -0,0 +1,174 @@
+pub use picow_logic::detector;
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::*;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Ticker, Timer};
+use portable_atomic::AtomicU8;
+
+use crate::{
+    commands::{CommandType, Packet},
+    joints,
+    state::{
+        events::{Events, EVENT_CHANNEL},
+        stop_motion,
+    },
+};
+use detector::{Goal, GripFault, GripMonitor, Progress, SensorReadings, PRESSURE_SENSORS};
+
+/// Interval at which sensors are requested while the hand moves
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+/// Longest wait for the hand to answer a sensor request
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(40);
+/// Time the grip is opened after closing with too much force
+const BACK_OFF: Duration = Duration::from_millis(150);
+/// `StartMotion` payload that opens the hand
+const OPEN: u8 = 1;
+
+/// Pressure limit, in raw sensor counts, of each pressure sensor.
+///
+/// Limits are kept per sensor rather than per grip: `StartMotion` only opens
+/// or closes the whole hand and the protocol has no notion of grip types, so
+/// the sensors are what tells one contact from another. Every grip the hand
+/// makes presses through them and is bounded by their limits.
+pub const DEFAULT_FORCE_LIMIT: u16 = 3000;
+/// Range force limits set over BLE are clamped to. Lower limits trip on a
+/// light touch, higher ones let the grip crush what it holds.
+pub const MIN_FORCE_LIMIT: u16 = 200;
+pub const MAX_FORCE_LIMIT: u16 = 4000;
+pub static FORCE_LIMITS: [AtomicU16; PRESSURE_SENSORS] =
+    [const { AtomicU16::new(DEFAULT_FORCE_LIMIT) }; PRESSURE_SENSORS];
+
+/// Latest `ResponseSensors` from the hand
+pub static SENSOR_READINGS: Signal<CriticalSectionRawMutex, SensorReadings> = Signal::new();
+
+/// Motion the hand was last told to make
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+enum Motion {
+    Stopped = 0,
+    Closing = 1,
+    Opening = 2,
+    /// Moving to a `SetPosition` target
+    Positioning = 3,
+    /// A close stalled and was stopped, the grip is held until the hand
+    /// opens or moves elsewhere
+    Holding = 4,
+}
+
+static MOTION: AtomicU8 = AtomicU8::new(Motion::Stopped as u8);
+
+fn motion() -> Motion {
+    match MOTION.load(Ordering::Relaxed) {
+        1 => Motion::Closing,
+        2 => Motion::Opening,
+        3 => Motion::Positioning,
+        4 => Motion::Holding,
+        _ => Motion::Stopped,
+    }
+}
+
+/// Follows the motion started or ended by a command sent to the hand
+pub fn track(command: CommandType, payload: &[u8]) {
+    let motion = match command {
+        CommandType::StartMotion if payload.first() == Some(&OPEN) => Motion::Opening,
+        CommandType::StartMotion => Motion::Closing,
+        CommandType::SetPosition => Motion::Positioning,
+        // A held grip stays watched, stopping does not release it
+        CommandType::StopMotion if motion() == Motion::Holding => return,
+        CommandType::StopMotion | CommandType::EmergencyStop => Motion::Stopped,
+        _ => return,
+    };
+    MOTION.store(motion as u8, Ordering::Relaxed);
+}
+
+/// Relieves the grip by opening it for a moment
+async fn back_off() {
+    if let Some(packet) = Packet::with_payload(CommandType::StartMotion, &[OPEN]) {
+        packet.send().await;
+    }
+    Timer::after(BACK_OFF).await;
+    stop_motion().await;
+}
+
+/// Polls the hand's sensors while it moves or holds a grip and stops it on
+/// over-force or a stall. A grip pressing with too much force is backed off as
+/// well. A stalled close is stopped and held, its pressure still watched.
+#[embassy_executor::task]
+pub async fn protection_task() {
+    info!("Grip protection started!");
+    let mut monitor = GripMonitor::new();
+    let mut watched = None;
+    let mut ticker = Ticker::every(POLL_PERIOD);
+
+    loop {
+        ticker.next().await;
+
+        let motion = motion();
+        let goal = match motion {
+            Motion::Stopped => {
+                watched = None;
+                continue;
+            }
+            Motion::Closing | Motion::Holding => Goal::Close,
+            Motion::Opening => Goal::Open,
+            Motion::Positioning => match joints::commanded().await {
+                Some(targets) => Goal::Position(targets),
+                None => continue,
+            },
+        };
+        // A new goal is watched from scratch
+        if watched != Some(goal) {
+            monitor = GripMonitor::new();
+            watched = Some(goal);
+        }
+
+        SENSOR_READINGS.reset();
+        if let Some(packet) = Packet::with_payload(CommandType::RequestSensors, &[]) {
+            packet.send().await;
+        }
+        let Ok(readings) = with_timeout(RESPONSE_TIMEOUT, SENSOR_READINGS.wait()).await else {
+            debug!("No sensor response from the hand");
+            continue;
+        };
+
+        let limits = core::array::from_fn(|sensor| FORCE_LIMITS[sensor].load(Ordering::Relaxed));
+        let fault = match monitor.update(&readings, &limits, &goal) {
+            Ok(Progress::Moving | Progress::Holding) => continue,
+            Ok(Progress::Complete) => {
+                debug!("{} complete", motion);
+                // Unless another motion was commanded meanwhile
+                let _ = MOTION.compare_exchange(
+                    motion as u8,
+                    Motion::Stopped as u8,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                continue;
+            }
+            Err(fault) => fault,
+        };
+
+        warn!("{} while {}, stopping", fault, motion);
+        // Set before the stop is sent, which would otherwise end the watch
+        if fault == GripFault::Stall && motion == Motion::Closing {
+            let _ = MOTION.compare_exchange(
+                motion as u8,
+                Motion::Holding as u8,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        stop_motion().await;
+        let event = match fault {
+            GripFault::OverForce { sensor, pressure } => {
+                if let Motion::Closing | Motion::Holding = motion {
+                    back_off().await;
+                }
+                Events::OverForce { sensor, pressure }
+            }
+            GripFault::Stall => Events::Stalled,
+        };
+        EVENT_CHANNEL.send(event).await;
+    }
+}
@@ -17,7 +17,35 @@ assign_resources! {
         dma_0: DMA_CH1,
         dma_1: DMA_CH2,
     }
+    // Converting into a ring, see `adc::InternalAdc`
     adc: AdcResources {
-        adc: ADC
+        adc: ADC,
+        dma_data: DMA_CH3,
+        dma_control: DMA_CH6,
+    }
+    // GPIO 26 to 28, ADC inputs 0 to 2, see `emg::CHANNEL_TABLE`
+    emg_inputs: EmgInputResources {
+        input_0: PIN_26,
+        input_1: PIN_27,
+        input_2: PIN_28,
+    }
+    watchdog: WatchdogResources {
+        watchdog: WATCHDOG,
+    }
+    flash: FlashResources {
+        flash: FLASH,
+    }
+    estop: EStopResources {
+        button: PIN_15,
+    }
+    ads: AdsResources {
+        spi: SPI1,
+        clk: PIN_10,
+        mosi: PIN_11,
+        miso: PIN_12,
+        cs: PIN_13,
+        drdy: PIN_14,
+        dma_tx: DMA_CH4,
+        dma_rx: DMA_CH5,
     }
 }
@@ -1,10 +1,25 @@
 use core::fmt::Display;
 
-use defmt::info;
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{select, Either};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
-use embassy_time::{Instant, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 
-use super::events::{Events, EVENT_CHANNEL};
+use super::{
+    events::{Events, EVENT_CHANNEL},
+    wait_stage_change, ProgramStage, StageSubscriber, STAGE_CHANGES,
+};
+use crate::{
+    emg::{CHANNEL_TABLE, EMG_CHANNELS, EMG_SAMPLES, SAMPLE_FREQUENCY},
+    filters::mean::MovingAvg,
+};
+
+/// Rest period during which the noise floor is measured
+const REST_DURATION_MS: u64 = 3000;
+const REST_SAMPLE_PERIOD_MS: u64 = 10;
+const REST_SAMPLES: usize = (REST_DURATION_MS / REST_SAMPLE_PERIOD_MS) as usize;
+/// Every n-th processed sample is used for the rest statistics
+const REST_DECIMATION: u32 = SAMPLE_FREQUENCY as u32 * REST_SAMPLE_PERIOD_MS as u32 / 1000;
 
 #[derive(Clone, Copy)]
 pub enum CalibrationStage {
@@ -28,30 +43,116 @@ type CalibrationStateMutex = Mutex<CriticalSectionRawMutex, CalibrationStage>;
 pub static CALIBRATION_STATE: CalibrationStateMutex =
     Mutex::new(CalibrationStage::WaitForZero(None));
 
-pub struct CalibrationCommand;
+/// Envelope statistics of a relaxed muscle
+#[derive(Clone, Copy)]
+pub struct RestNoise {
+    pub mean: i32,
+    pub std_dev: f32,
+    pub max: i32,
+}
+
+impl RestNoise {
+    const fn new() -> Self {
+        Self {
+            mean: 0,
+            std_dev: 0.0,
+            max: 0,
+        }
+    }
+}
+
+pub static REST_NOISE: Mutex<CriticalSectionRawMutex, [RestNoise; EMG_CHANNELS]> =
+    Mutex::new([RestNoise::new(); EMG_CHANNELS]);
+
+/// Starts calibration run `run`, see `machine::CalibrationRuns`
+pub struct CalibrationCommand {
+    pub run: u32,
+}
 pub static START_CALIBRATION: Signal<CriticalSectionRawMutex, CalibrationCommand> = Signal::new();
 
-async fn calibration() {
+async fn calibration(run: u32) {
     info!("Starting calibration");
 
     let now = Instant::now();
     *CALIBRATION_STATE.lock().await = CalibrationStage::WaitForZero(Some(now));
 
-    Timer::after_secs(3).await;
+    let rest_noise = measure_rest_noise().await;
+    for (config, noise) in CHANNEL_TABLE.iter().zip(rest_noise.iter()) {
+        info!(
+            "Rest noise on {}: mean {}, std dev {}, max {}",
+            config.name, noise.mean, noise.std_dev, noise.max
+        );
+    }
+    *REST_NOISE.lock().await = rest_noise;
 
     *CALIBRATION_STATE.lock().await = CalibrationStage::PeakCalibration(0, 0);
 
     Timer::after_secs(5).await;
 
     info!("Calibration finished");
-    EVENT_CHANNEL.send(Events::CalibrationFinished).await;
+    EVENT_CHANNEL
+        .send(Events::CalibrationFinished { run })
+        .await;
+}
+
+/// Waits until a stage other than Calibration is entered
+async fn left_calibration(stages: &mut StageSubscriber) -> ProgramStage {
+    loop {
+        let stage = wait_stage_change(stages).await;
+        if stage != ProgramStage::Calibration {
+            return stage;
+        }
+    }
+}
+
+async fn measure_rest_noise() -> [RestNoise; EMG_CHANNELS] {
+    let mut stats: [MovingAvg<i32, REST_SAMPLES>; EMG_CHANNELS] =
+        core::array::from_fn(|_| MovingAvg::new(true));
+    let mut samples = EMG_SAMPLES.reader();
+    let mut ticker = Ticker::every(Duration::from_millis(REST_SAMPLE_PERIOD_MS));
+    let mut collected = 0;
+
+    while collected < REST_SAMPLES {
+        ticker.next().await;
+        loop {
+            let sample = match samples.read() {
+                Ok(Some(sample)) => sample,
+                Ok(None) => break,
+                Err(overrun) => {
+                    warn!("Rest noise measurement lost {} samples", overrun.lost);
+                    continue;
+                }
+            };
+            if sample.index % REST_DECIMATION != 0 || collected == REST_SAMPLES {
+                continue;
+            }
+
+            for (channel_stats, &value) in stats.iter_mut().zip(sample.values.iter()) {
+                channel_stats.reading(value);
+            }
+            collected += 1;
+        }
+    }
+
+    core::array::from_fn(|channel| RestNoise {
+        mean: stats[channel].get_avg(),
+        std_dev: stats[channel].get_std_dev(),
+        max: stats[channel].get_max().unwrap_or(0),
+    })
 }
 
 #[embassy_executor::task]
 pub async fn calibration_task() {
+    let mut stages = unwrap!(STAGE_CHANGES.subscriber());
     loop {
         info!("Waiting for calibration start signal");
-        START_CALIBRATION.wait().await;
-        calibration().await;
+        let CalibrationCommand { run } = START_CALIBRATION.wait().await;
+        // Only changes after the one into Calibration end the run. One that
+        // is missed here is caught by the orchestrator from the run number.
+        while stages.try_next_message_pure().is_some() {}
+        if let Either::Second(stage) = select(calibration(run), left_calibration(&mut stages)).await
+        {
+            info!("Calibration abandoned, {} entered", stage);
+        }
     }
 }
@@ -1,37 +1,278 @@
-use defmt::info;
-use embassy_futures::select::{select, Either};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::{debug, info, warn};
+use embassy_futures::select::{select4, Either4};
 use embassy_rp::uart::BufferedUartTx;
 use embassy_rp::{peripherals::UART0, uart::BufferedUartRx};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 
-use crate::commands::Packet;
+use super::events::{Events, EVENT_CHANNEL};
+use super::machine::UserRequest;
+use crate::{
+    commands::{device_info::DeviceInfo, CommandType, Packet},
+    crash::CRASH_LOG,
+    device::{self, HAND_RESPONSES},
+    estop::{self, Source},
+    joints,
+    protection::{self, detector::SensorReadings, SENSOR_READINGS},
+    watchdog::{self, Supervised},
+};
+
+/// Longest wait for a packet before the link and the watchdog are checked
+const IDLE_CHECK_IN: Duration = Duration::from_millis(50);
+/// Heartbeats sent per timeout, so that a lost one does not drop the link
+const HEARTBEATS_PER_TIMEOUT: u32 = 4;
+/// Interval at which `StopMotion` is repeated while the link is lost
+const STOP_REPEAT: Duration = Duration::from_millis(200);
+/// Shortest heartbeat timeout accepted over BLE, in ms
+pub const MIN_HEARTBEAT_TIMEOUT: u16 = 200;
+pub const DEFAULT_HEARTBEAT_TIMEOUT: u16 = 1000;
+
+/// Time in ms without any packet from the hand after which the link is lost
+pub static HEARTBEAT_TIMEOUT: AtomicU16 = AtomicU16::new(DEFAULT_HEARTBEAT_TIMEOUT);
+/// Crash record bytes per `CrashReport` packet, after its 3 byte header
+const CRASH_CHUNK: usize = 29;
 
 pub static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, Packet, 10> = Channel::new();
 pub static RESPONSE_CHANNEL: Channel<CriticalSectionRawMutex, Packet, 10> = Channel::new();
 
+/// Forwards commands to the hand and supervises the link.
+///
+/// The link is lost when a write fails or nothing was received from the hand
+/// within `HEARTBEAT_TIMEOUT`, and restored by the next packet from it. While
+/// it is lost `StopMotion` is repeated and motion commands are dropped, so that
+/// none of them is carried out late. An emergency stop would stay latched in
+/// the hand once the link is back.
 pub struct CommandSender {
     uart: BufferedUartTx<'static, UART0>,
+    link_lost: bool,
+    last_received: Instant,
+    next_heartbeat: Instant,
+    next_stop: Instant,
 }
 
 impl CommandSender {
     pub fn new(uart: BufferedUartTx<'static, UART0>) -> Self {
-        Self { uart }
+        let now = Instant::now();
+        Self {
+            uart,
+            link_lost: false,
+            // The hand gets one timeout to answer the first heartbeat
+            last_received: now,
+            next_heartbeat: now,
+            next_stop: now,
+        }
     }
 
     async fn send_request(&mut self, packet: Packet) {
-        info!("Sending request: {}", packet);
+        if self.link_lost && packet.command.moves() {
+            warn!("Link to the hand lost, {} dropped", packet.command);
+            return;
+        }
+        if estop::is_latched() && packet.command.moves() {
+            warn!("Emergency stop latched, {} dropped", packet.command);
+            return;
+        }
+        let packet = match packet.command {
+            CommandType::SetPosition => match joints::limit(packet).await {
+                Some(packet) => packet,
+                None => return,
+            },
+            _ => packet,
+        };
+
+        // Polls are sent several times a second
+        if let CommandType::RequestPosition | CommandType::RequestSensors = packet.command {
+            debug!("Sending request: {}", packet);
+        } else {
+            info!("Sending request: {}", packet);
+        }
+        self.write(packet).await;
+    }
+
+    async fn write(&mut self, packet: Packet) {
         let serialized = packet.serialize();
-        info!("Serialized: {}", serialized);
-        self.uart.write_all(&serialized).await.unwrap();
+        debug!("Serialized: {}", serialized);
+
+        match self.uart.write_all(&serialized).await {
+            Ok(()) => protection::track(packet.command, &packet.payload[..packet.length as usize]),
+            Err(e) => {
+                warn!("Failed to send request: {}", e);
+                self.set_link_lost(true);
+            }
+        }
+    }
+
+    /// Drops every queued command and stops the hand right away
+    async fn emergency_stop(&mut self, source: Source) {
+        let mut flushed = 0;
+        while COMMAND_CHANNEL.try_receive().is_ok() {
+            flushed += 1;
+        }
+        if flushed > 0 {
+            warn!("Emergency stop, {} queued commands dropped", flushed);
+        }
+
+        // The hand stopped itself when it is the source
+        if source != Source::Hand {
+            if let Some(packet) = Packet::with_payload(CommandType::EmergencyStop, &[]) {
+                self.write(packet).await;
+            }
+        }
+    }
+
+    /// Tells the hand that the emergency stop was cleared here
+    async fn clear_emergency_stop(&mut self) {
+        match estop::CLEAR.try_take() {
+            Some(Source::Hand) | None => {}
+            Some(_) => {
+                if let Some(packet) = Packet::with_payload(CommandType::ClearEmergencyStop, &[]) {
+                    self.write(packet).await;
+                }
+            }
+        }
+    }
+
+    /// Reports changes of the link to the orchestrator
+    fn set_link_lost(&mut self, lost: bool) {
+        if lost == self.link_lost {
+            return;
+        }
+        self.link_lost = lost;
+        let event = if lost {
+            Events::CommsLost
+        } else {
+            Events::CommsRestored
+        };
+        // The orchestrator may itself be waiting for room in the command queue
+        if EVENT_CHANNEL.try_send(event).is_err() {
+            warn!("Event queue full, link change not reported");
+        }
+    }
+
+    /// Sends the heartbeat when due, detects its timeout and repeats the
+    /// emergency stop while the link is lost
+    async fn supervise_link(&mut self) {
+        let now = Instant::now();
+        let timeout = Duration::from_millis(HEARTBEAT_TIMEOUT.load(Ordering::Relaxed) as u64);
+
+        if !self.link_lost && now.saturating_duration_since(self.last_received) > timeout {
+            warn!(
+                "Nothing received from the hand for {} ms",
+                timeout.as_millis()
+            );
+            self.set_link_lost(true);
+        }
+
+        if now >= self.next_heartbeat {
+            self.next_heartbeat = now + timeout / HEARTBEATS_PER_TIMEOUT;
+            if let Some(packet) = Packet::with_payload(CommandType::Heartbeat, &[]) {
+                self.write(packet).await;
+            }
+        }
+
+        if self.link_lost && now >= self.next_stop {
+            self.next_stop = now + STOP_REPEAT;
+            if let Some(packet) = Packet::with_payload(CommandType::StopMotion, &[]) {
+                self.write(packet).await;
+            }
+        }
     }
 
     async fn handle_response(&mut self, packet: Packet) {
+        // Any packet shows that the hand is there
+        self.last_received = Instant::now();
+        if self.link_lost {
+            info!("Hand answers again");
+            self.set_link_lost(false);
+        }
+
+        match packet.command {
+            CommandType::HeartbeatAck => return,
+            // Polled several times a second, too often to log
+            CommandType::ResponsePosition => {
+                joints::update(&packet.payload[..packet.length as usize]).await;
+                return;
+            }
+            CommandType::ResponseSensors => {
+                match SensorReadings::parse(&packet.payload[..packet.length as usize]) {
+                    Some(readings) => SENSOR_READINGS.signal(readings),
+                    None => warn!("Malformed sensor response"),
+                }
+                return;
+            }
+            _ => {}
+        }
         info!("Handling response: {:?}", packet);
         match packet.command {
+            CommandType::LockRequest if packet.length == 1 => {
+                let request = if packet.payload[0] != 0 {
+                    UserRequest::Pause
+                } else {
+                    UserRequest::Resume
+                };
+                if EVENT_CHANNEL
+                    .try_send(Events::UserRequest { request })
+                    .is_err()
+                {
+                    warn!("Event queue full, lock request dropped");
+                }
+            }
+            CommandType::GetDeviceInfo => {
+                let info = device::own_info().to_bytes();
+                if let Some(packet) = Packet::with_payload(CommandType::ResponseDeviceInfo, &info) {
+                    self.send_request(packet).await;
+                }
+            }
+            CommandType::ResponseDeviceInfo => {
+                match DeviceInfo::from_bytes(&packet.payload[..packet.length as usize]) {
+                    Some(info) => HAND_RESPONSES.signal(info),
+                    None => warn!("Malformed device info"),
+                }
+            }
+            CommandType::EmergencyStop => estop::trigger(Source::Hand),
+            CommandType::ClearEmergencyStop => estop::clear(Source::Hand),
+            CommandType::RequestCrashLog if packet.length == 1 => {
+                self.send_crash_record(packet.payload[0]).await;
+            }
+            CommandType::ClearCrashLog => {
+                if let Some(log) = CRASH_LOG.lock().await.as_mut() {
+                    log.clear();
+                    info!("Crash log cleared");
+                }
+            }
             _ => {}
         }
     }
+
+    /// Sends the stored crash record `index` in `CrashReport` chunks, or a
+    /// single header-only packet if there is no such record
+    async fn send_crash_record(&mut self, index: u8) {
+        let (count, record) = match CRASH_LOG.lock().await.as_mut() {
+            Some(log) => (log.count() as u8, log.raw(index as usize)),
+            None => (0, None),
+        };
+
+        let Some(record) = record else {
+            if let Some(packet) = Packet::with_payload(CommandType::CrashReport, &[index, count]) {
+                self.send_request(packet).await;
+            }
+            return;
+        };
+
+        for (chunk_index, chunk) in record.chunks(CRASH_CHUNK).enumerate() {
+            let mut payload = [0u8; 3 + CRASH_CHUNK];
+            payload[..3].copy_from_slice(&[index, count, (chunk_index * CRASH_CHUNK) as u8]);
+            payload[3..3 + chunk.len()].copy_from_slice(chunk);
+            if let Some(packet) =
+                Packet::with_payload(CommandType::CrashReport, &payload[..3 + chunk.len()])
+            {
+                self.send_request(packet).await;
+            }
+        }
+    }
 }
 
 #[embassy_executor::task]
@@ -42,15 +283,28 @@ pub async fn command_handler_task(uart: BufferedUartTx<'static, UART0>) {
     let response_receiver = RESPONSE_CHANNEL.receiver();
 
     loop {
-        match select(command_receiver.receive(), response_receiver.receive()).await {
-            Either::First(packet) => {
-                info!("Received command packet");
+        watchdog::check_in(Supervised::CommandHandler);
+        handler.supervise_link().await;
+        handler.clear_emergency_stop().await;
+        // Polled in order, an emergency stop goes ahead of any queued command
+        match select4(
+            estop::STOP.wait(),
+            command_receiver.receive(),
+            response_receiver.receive(),
+            Timer::after(IDLE_CHECK_IN),
+        )
+        .await
+        {
+            Either4::First(source) => handler.emergency_stop(source).await,
+            Either4::Second(packet) => {
+                debug!("Received command packet");
                 handler.send_request(packet).await;
             }
-            Either::Second(packet) => {
-                info!("Received response packet");
+            Either4::Third(packet) => {
+                debug!("Received response packet");
                 handler.handle_response(packet).await;
             }
+            Either4::Fourth(()) => {}
         }
     }
 }
@@ -1,7 +1,34 @@
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 
+use super::machine::UserRequest;
+use crate::{contact::ContactFault, estop};
+
 pub static EVENT_CHANNEL: Channel<CriticalSectionRawMutex, Events, 10> = Channel::new();
 
 pub enum Events {
-    CalibrationFinished,
+    // Carries the run number of `CalibrationCommand`
+    CalibrationFinished { run: u32 },
+    FatigueDetected { channel: usize, median_freq: f32 },
+    FatigueRecovered { channel: usize, median_freq: f32 },
+    ContactLost { channel: usize, fault: ContactFault },
+    ContactRestored { channel: usize },
+    // Error rate in per mille of conversions, see `health::ERROR_RATE_LIMIT`
+    SensorFault { channel: usize, error_rate: u16 },
+    SensorRecovered { channel: usize },
+    // A UART write to the hand failed or its heartbeat timed out, or it answers again
+    CommsLost,
+    CommsRestored,
+    UserRequest { request: UserRequest },
+    LowBattery { millivolts: u16 },
+    BatteryRecovered { millivolts: u16 },
+    // Die temperature in 0.1 °C
+    OverTemperature { decicelsius: i16 },
+    TemperatureRecovered { decicelsius: i16 },
+    // Raw pressure of the sensor beyond its limit, see `protection::FORCE_LIMITS`
+    OverForce { sensor: usize, pressure: u16 },
+    Stalled,
+    EmergencyStop { source: estop::Source },
+    // The hand controller's protocol major version matches ours, or no longer does
+    HandIdentified { compatible: bool },
+    EmergencyStopCleared,
 }
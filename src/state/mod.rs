@@ -3,55 +3,253 @@ pub mod command_handler;
 pub mod events;
 pub mod operation;
 
+pub use picow_logic::machine;
 
-use defmt::{debug, info};
+use defmt::{debug, info, warn};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
+use embassy_sync::signal::Signal;
 
+use crate::{
+    commands::{CommandType, Packet},
+    emg::CHANNEL_TABLE,
+    estop,
+};
 use calibration::{CalibrationCommand, START_CALIBRATION};
 use events::Events;
-use operation::OperationCommand;
-use operation::START_OPERATION;
+pub use machine::ProgramStage;
+use machine::{
+    entry_actions, exit_actions, next_stage, Action, CalibrationRuns, Confirmation, Guards, Trigger,
+};
 
+pub type ProgramStateMutex = Mutex<CriticalSectionRawMutex, ProgramStage>;
+pub static PROGRAM_STATE: ProgramStateMutex = Mutex::new(ProgramStage::Boot);
+
+/// Queued stage changes per subscriber
+const STAGE_QUEUE: usize = 4;
+/// Tasks following stage changes: operation, BLE, the status LED, gestures,
+/// fatigue and calibration
+const STAGE_SUBSCRIBERS: usize = 6;
+
+type StageChanges =
+    PubSubChannel<CriticalSectionRawMutex, ProgramStage, STAGE_QUEUE, STAGE_SUBSCRIBERS, 0>;
+pub type StageSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, ProgramStage, STAGE_QUEUE, STAGE_SUBSCRIBERS, 0>;
 
-#[derive(Copy, Clone)]
-pub enum ProgramStage {
-    Calibration,
-    Operation,
-    Error,
+/// Every stage the orchestrator enters, in order
+pub static STAGE_CHANGES: StageChanges = PubSubChannel::new();
+
+/// Waits for the next stage change. A subscriber that fell behind skips the
+/// oldest changes, the latest one is always delivered.
+pub async fn wait_stage_change(subscriber: &mut StageSubscriber) -> ProgramStage {
+    loop {
+        match subscriber.next_message().await {
+            WaitResult::Message(stage) => return stage,
+            WaitResult::Lagged(missed) => debug!("{} stage changes missed", missed),
+        }
+    }
 }
 
-impl ProgramStage {
-    pub async fn transition(&mut self, new_state: ProgramStage) {
-        let mut state = PROGRAM_STATE.lock().await;
-        *state = new_state;
+/// Lock and unlock feedback, shown by the status LED. An audible or haptic
+/// output would wait on it in the same way.
+pub static CONFIRMATION: Signal<CriticalSectionRawMutex, Confirmation> = Signal::new();
+
+pub async fn stop_motion() {
+    if let Some(packet) = Packet::with_payload(CommandType::StopMotion, &[]) {
+        packet.send().await;
     }
 }
 
-pub type ProgramStateMutex = Mutex<CriticalSectionRawMutex, ProgramStage>;
-pub static PROGRAM_STATE: ProgramStateMutex = Mutex::new(ProgramStage::Calibration);
+async fn perform(action: Action, runs: &mut CalibrationRuns) {
+    match action {
+        Action::StartCalibration => {
+            START_CALIBRATION.signal(CalibrationCommand { run: runs.start() })
+        }
+        Action::StopMotion => stop_motion().await,
+        Action::Confirm(confirmation) => CONFIRMATION.signal(confirmation),
+    }
+}
+
+/// Moves to the stage `trigger` leads to, running the exit actions of the old
+/// stage and the entry actions of the new one
+async fn apply(trigger: Trigger, guards: &Guards, runs: &mut CalibrationRuns) {
+    // The lock is released before any action waits, e.g. for room in the
+    // command queue, so that readers of the stage are never held up by it
+    let (previous, next) = {
+        let mut state = PROGRAM_STATE.lock().await;
+        let Some(next) = next_stage(*state, trigger, guards) else {
+            debug!("{} ignored in {} state", trigger, *state);
+            return;
+        };
+        info!("Transitioning from {} to {} on {}", *state, next, trigger);
+        let previous = *state;
+        *state = next;
+        STAGE_CHANGES.immediate_publisher().publish_immediate(next);
+        (previous, next)
+    };
+
+    for &action in exit_actions(previous) {
+        perform(action, runs).await;
+    }
+    for &action in entry_actions(next) {
+        perform(action, runs).await;
+    }
+}
 
 #[embassy_executor::task]
 pub async fn orchestrator() {
     info!("Starting orchestrator");
     let event_receiver = events::EVENT_CHANNEL.receiver();
-    START_CALIBRATION.signal(CalibrationCommand);
+    let mut guards = Guards::new();
+    let mut runs = CalibrationRuns::new();
+    // Bit `n` is set while channel `n` reports a sensor fault
+    let mut faulty_sensors: u8 = 0;
+
+    apply(Trigger::BootComplete, &guards, &mut runs).await;
 
     loop {
         let event = event_receiver.receive().await;
         debug!("Received event");
 
-        {
-            let mut state = PROGRAM_STATE.lock().await;
-
-            match event {
-                // TODO: Add calibrated data to event
-                Events::CalibrationFinished => {
-                    info!("Calibration finished, transitioning to Operation state");
-                    *state = ProgramStage::Operation;
-                    START_OPERATION.signal(OperationCommand {});
+        let trigger = match event {
+            // TODO: Add calibrated data to event
+            Events::CalibrationFinished { run } => {
+                let trigger = runs.finished(run);
+                if trigger.is_some() {
+                    info!("Calibration finished");
+                } else {
+                    info!("Calibration run {} finished after being abandoned", run);
                 }
+                trigger
+            }
+            Events::FatigueDetected {
+                channel,
+                median_freq,
+            } => {
+                info!(
+                    "Muscle fatigue on {}, median frequency {} Hz",
+                    CHANNEL_TABLE[channel].name, median_freq
+                );
+                None
             }
+            Events::FatigueRecovered {
+                channel,
+                median_freq,
+            } => {
+                info!(
+                    "Muscle fatigue recovered on {}, median frequency {} Hz",
+                    CHANNEL_TABLE[channel].name, median_freq
+                );
+                None
+            }
+            Events::ContactLost { channel, fault } => {
+                warn!(
+                    "Electrode contact lost on {}: {}",
+                    CHANNEL_TABLE[channel].name, fault
+                );
+                // Operation holds motion until contact recovers, stop
+                // whatever is already running
+                if let ProgramStage::Operation = *PROGRAM_STATE.lock().await {
+                    stop_motion().await;
+                }
+                None
+            }
+            Events::ContactRestored { channel } => {
+                info!(
+                    "Electrode contact restored on {}",
+                    CHANNEL_TABLE[channel].name
+                );
+                None
+            }
+            Events::SensorFault {
+                channel,
+                error_rate,
+            } => {
+                warn!(
+                    "Sensor fault on {}, {} per mille of conversions failed",
+                    CHANNEL_TABLE[channel].name, error_rate
+                );
+                faulty_sensors |= 1 << channel;
+                guards.sensors_ok = false;
+                Some(Trigger::SensorFault)
+            }
+            Events::SensorRecovered { channel } => {
+                info!("Sensor recovered on {}", CHANNEL_TABLE[channel].name);
+                faulty_sensors &= !(1 << channel);
+                guards.sensors_ok = faulty_sensors == 0;
+                guards.sensors_ok.then_some(Trigger::Recovered)
+            }
+            Events::CommsLost => {
+                warn!("Link to the hand lost");
+                guards.comms_ok = false;
+                Some(Trigger::CommsLost)
+            }
+            Events::CommsRestored => {
+                info!("Link to the hand restored");
+                guards.comms_ok = true;
+                Some(Trigger::Recovered)
+            }
+            Events::UserRequest { request } => {
+                info!("User requested {}", request);
+                Some(Trigger::User(request))
+            }
+            Events::LowBattery { millivolts } => {
+                warn!("Battery low, VSYS at {} mV", millivolts);
+                guards.battery_ok = false;
+                Some(Trigger::LowBattery)
+            }
+            Events::BatteryRecovered { millivolts } => {
+                info!("Battery recovered, VSYS at {} mV", millivolts);
+                guards.battery_ok = true;
+                Some(Trigger::Recovered)
+            }
+            Events::OverTemperature { decicelsius } => {
+                warn!("Controller overheating at {} C", decicelsius as f32 / 10.0);
+                if let ProgramStage::Operation = *PROGRAM_STATE.lock().await {
+                    estop::trigger(estop::Source::Fault);
+                }
+                None
+            }
+            Events::TemperatureRecovered { decicelsius } => {
+                info!(
+                    "Controller temperature back to {} C",
+                    decicelsius as f32 / 10.0
+                );
+                None
+            }
+            Events::OverForce { sensor, pressure } => {
+                warn!("Grip stopped, pressure sensor {} at {}", sensor, pressure);
+                None
+            }
+            Events::Stalled => {
+                warn!("Grip stopped, the fingers stalled");
+                None
+            }
+            Events::EmergencyStop { source } => {
+                warn!("Emergency stop latched by {}", source);
+                guards.released = false;
+                Some(Trigger::EmergencyStop)
+            }
+            Events::EmergencyStopCleared => {
+                info!("Emergency stop cleared");
+                guards.released = true;
+                Some(Trigger::Recovered)
+            }
+            Events::HandIdentified { compatible } => {
+                guards.compatible = compatible;
+                if compatible {
+                    info!("Hand controller is compatible");
+                    Some(Trigger::Recovered)
+                } else {
+                    warn!("Hand controller is incompatible");
+                    Some(Trigger::Incompatible)
+                }
+            }
+        };
+
+        if let Some(trigger) = trigger {
+            apply(trigger, &guards, &mut runs).await;
         }
     }
 }
@@ -1,25 +1,68 @@
-use defmt::info;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use defmt::{debug, info, unwrap};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
 
 use crate::{
     commands::{CommandType, Packet},
-    state::command_handler::COMMAND_CHANNEL,
+    contact,
+    emg::EmgSensorsState,
+    state::{
+        command_handler::COMMAND_CHANNEL, wait_stage_change, ProgramStage, StageSubscriber,
+        STAGE_CHANGES,
+    },
 };
 
-pub struct OperationCommand;
-pub static START_OPERATION: Signal<CriticalSectionRawMutex, OperationCommand> = Signal::new();
+/// Waits for `duration`, or returns the new stage as soon as Operation is left
+async fn wait_in_operation(
+    stages: &mut StageSubscriber,
+    duration: Duration,
+) -> Option<ProgramStage> {
+    match select(wait_stage_change(stages), Timer::after(duration)).await {
+        Either::First(ProgramStage::Operation) | Either::Second(()) => None,
+        Either::First(stage) => Some(stage),
+    }
+}
 
 #[embassy_executor::task]
 pub async fn operation_task() {
+    let mut stages = unwrap!(STAGE_CHANGES.subscriber());
+
     loop {
-        info!("Waiting for operation start signal");
-        START_OPERATION.wait().await;
-        info!("Operation signal received");
+        info!("Waiting for Operation stage");
+        while wait_stage_change(&mut stages).await != ProgramStage::Operation {}
+        info!("Operation stage entered");
 
+        let mut frozen = false;
         loop {
+            // Motion is held while any electrode has lost contact, the
+            // signal would only be noise
+            if !contact::all_in_contact() {
+                if !frozen {
+                    info!("Electrode contact lost, motion frozen");
+                    frozen = true;
+                }
+                if let Some(stage) =
+                    wait_in_operation(&mut stages, Duration::from_millis(100)).await
+                {
+                    info!("{} stage entered, leaving operation", stage);
+                    break;
+                }
+                continue;
+            }
+            if frozen {
+                info!("Electrode contact restored, motion resumed");
+                frozen = false;
+            }
+
+            let sensors = EmgSensorsState::gather().await;
+            debug!("EMG at {}: {}", sensors.timestamp, sensors.values);
+
             // Packet::new(CommandType::RequestSensors).send().await;
 
-            embassy_time::Timer::after(embassy_time::Duration::from_secs(1)).await;
+            if let Some(stage) = wait_in_operation(&mut stages, Duration::from_secs(1)).await {
+                info!("{} stage entered, leaving operation", stage);
+                break;
+            }
         }
     }
 }
@@ -0,0 +1,168 @@
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use defmt::*;
+use embassy_rp::watchdog::{ResetReason, Watchdog};
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::{
+    commands::{CommandType, Packet},
+    crash::CRASHED,
+    resources::WatchdogResources,
+};
+
+/// Hardware timeout, only reached when the supervisor stops feeding. Covers a
+/// feeding interval plus a blocking flash sector erase, up to 400 ms.
+const HARDWARE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Interval at which check-ins are verified
+const SUPERVISION_PERIOD: Duration = Duration::from_millis(100);
+/// Scratch register holding the task that missed its deadline
+const CULPRIT_SCRATCH: usize = 0;
+/// Marks the scratch value as written by the supervisor
+const CULPRIT_MAGIC: u32 = 0x5EED_0000;
+/// Reported in place of a task when the reset was not caused by one
+pub const NO_CULPRIT: u8 = 0xFF;
+
+/// Tasks whose liveness is supervised, each checks in well within its deadline
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Supervised {
+    Acquisition = 0,
+    Processing = 1,
+    Bluetooth = 2,
+    CommandHandler = 3,
+}
+
+const SUPERVISED: [Supervised; 4] = [
+    Supervised::Acquisition,
+    Supervised::Processing,
+    Supervised::Bluetooth,
+    Supervised::CommandHandler,
+];
+
+impl Supervised {
+    fn deadline(self) -> Duration {
+        match self {
+            Self::Acquisition | Self::Processing => Duration::from_millis(500),
+            Self::Bluetooth | Self::CommandHandler => Duration::from_millis(1000),
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        SUPERVISED.iter().copied().find(|&task| task as u8 == id)
+    }
+}
+
+/// Time of each task's last check-in in ms since boot plus one, 0 until the
+/// task first checks in and is supervised from then on
+static CHECK_INS: [AtomicU32; SUPERVISED.len()] = [const { AtomicU32::new(0) }; SUPERVISED.len()];
+
+/// Why the previous run ended
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum ResetCause {
+    /// Power-on, reset pin or debugger
+    PowerOn = 0,
+    /// Software requested reset
+    Forced = 1,
+    /// The watchdog expired
+    Watchdog = 2,
+    /// A panic, its record is kept in the crash log
+    Panic = 3,
+}
+
+/// Cause of the last reset, see `ResetCause`
+pub static RESET_CAUSE: AtomicU8 = AtomicU8::new(ResetCause::PowerOn as u8);
+/// Task that missed its deadline before the last reset, or `NO_CULPRIT`
+pub static RESET_CULPRIT: AtomicU8 = AtomicU8::new(NO_CULPRIT);
+
+/// Tells the supervisor that `task` is still making progress
+pub fn check_in(task: Supervised) {
+    let now = Instant::now().as_millis() as u32;
+    CHECK_INS[task as usize].store(now.wrapping_add(1), Ordering::Relaxed);
+}
+
+/// Restarts the deadline of every supervised task after the system was
+/// stalled on purpose, e.g. by a flash erase with core 1 paused
+pub fn excuse_stall() {
+    let now = Instant::now().as_millis() as u32;
+    // Tasks that never checked in stay unsupervised
+    for check_in in CHECK_INS.iter() {
+        if check_in.load(Ordering::Relaxed) != 0 {
+            check_in.store(now.wrapping_add(1), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reads and clears the reset cause and culprit left by the previous run
+fn read_last_reset(watchdog: &mut Watchdog) -> (ResetCause, Option<Supervised>) {
+    let cause = match watchdog.reset_reason() {
+        // The panic handler resets through the core, not the watchdog
+        None if CRASHED.load(Ordering::Relaxed) => ResetCause::Panic,
+        None => ResetCause::PowerOn,
+        Some(ResetReason::Forced) => ResetCause::Forced,
+        Some(ResetReason::TimedOut) => ResetCause::Watchdog,
+    };
+
+    let scratch = watchdog.get_scratch(CULPRIT_SCRATCH);
+    watchdog.set_scratch(CULPRIT_SCRATCH, 0);
+    let culprit = match (cause, scratch & 0xFFFF_FF00) {
+        (ResetCause::Watchdog, CULPRIT_MAGIC) => Supervised::from_id(scratch as u8),
+        _ => None,
+    };
+
+    (cause, culprit)
+}
+
+/// First supervised task that missed its deadline
+fn overdue_task() -> Option<Supervised> {
+    SUPERVISED.iter().copied().find(|&task| {
+        let last = CHECK_INS[task as usize].load(Ordering::Relaxed);
+        // Taken after the check-in so that one from the other core is never in the future
+        let now = Instant::now().as_millis() as u32;
+        last != 0 && now.wrapping_sub(last - 1) > task.deadline().as_millis() as u32
+    })
+}
+
+/// Reports the last reset to the hand controller.
+///
+/// Payload: reset cause (see `ResetCause`) and the task that missed its
+/// deadline, `NO_CULPRIT` if none.
+async fn report_reset(cause: ResetCause, culprit: u8) {
+    if let Some(packet) = Packet::with_payload(CommandType::ResetReport, &[cause as u8, culprit]) {
+        packet.send().await;
+    }
+}
+
+/// Feeds the hardware watchdog while every supervised task checks in in time.
+///
+/// A task that misses its deadline is recorded in a scratch register, which
+/// survives the watchdog reset, and the watchdog is left to expire.
+#[embassy_executor::task]
+pub async fn watchdog_task(r: WatchdogResources) {
+    let mut watchdog = Watchdog::new(r.watchdog);
+
+    let (cause, culprit) = read_last_reset(&mut watchdog);
+    match culprit {
+        Some(task) => warn!("Reset by the watchdog, {} missed its deadline", task),
+        None => info!("Last reset: {}", cause),
+    }
+    let culprit = culprit.map_or(NO_CULPRIT, |task| task as u8);
+    RESET_CAUSE.store(cause as u8, Ordering::Relaxed);
+    RESET_CULPRIT.store(culprit, Ordering::Relaxed);
+
+    report_reset(cause, culprit).await;
+
+    watchdog.pause_on_debug(true);
+    watchdog.start(HARDWARE_TIMEOUT);
+
+    let mut ticker = Ticker::every(SUPERVISION_PERIOD);
+    loop {
+        ticker.next().await;
+
+        if let Some(task) = overdue_task() {
+            error!("{} missed its deadline, resetting", task);
+            watchdog.set_scratch(CULPRIT_SCRATCH, CULPRIT_MAGIC | task as u32);
+            core::future::pending::<()>().await;
+        }
+
+        watchdog.feed();
+    }
+}